@@ -0,0 +1,44 @@
+use anyhow::bail;
+use libp2p::core::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+use std::net::IpAddr;
+
+/// Parses the address of a relay server, which has to end in `/p2p/<peer id>` and must not
+/// itself be a circuit.
+pub fn parse_relay(address: &str) -> anyhow::Result<Multiaddr> {
+    let address: Multiaddr = address.parse()?;
+    if address.iter().any(|p| p == Protocol::P2pCircuit) {
+        bail!("relay address {address} must not contain /p2p-circuit");
+    }
+    if PeerId::try_from_multiaddr(&address).is_none() {
+        bail!("relay address {address} must end in /p2p/<peer id>");
+    }
+    Ok(address)
+}
+
+/// Parses the address of a peer, which has to end in `/p2p/<peer id>`.
+pub fn parse_peer(address: &str) -> anyhow::Result<Multiaddr> {
+    let address: Multiaddr = address.parse()?;
+    if PeerId::try_from_multiaddr(&address).is_none() {
+        bail!("peer address {address} must end in /p2p/<peer id>");
+    }
+    Ok(address)
+}
+
+/// The address of `relay` to listen on, or of `peer` behind `relay` when given.
+pub fn circuit(relay: &Multiaddr, peer: Option<PeerId>) -> Multiaddr {
+    let address = relay.clone().with(Protocol::P2pCircuit);
+    match peer {
+        Some(peer) => address.with(Protocol::P2p(peer.into())),
+        None => address,
+    }
+}
+
+/// The IP address `address` starts with, `None` for DNS names and other transports.
+pub fn ip(address: &Multiaddr) -> Option<IpAddr> {
+    match address.iter().next()? {
+        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    }
+}
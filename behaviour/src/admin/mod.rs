@@ -0,0 +1,80 @@
+//! Read-only HTTP queries against a running node, for operators.
+//!
+//! - `GET /relay`: the [`RelayStatus`](crate::node::RelayStatus) of the relay server as JSON
+
+use crate::node::NodeHandle;
+use anyhow::Context;
+use clap::Args;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+const RELAY_PATH: &str = "/relay";
+
+/// Admin endpoint options of the binaries.
+#[derive(Debug, Clone, Args)]
+pub struct AdminOpt {
+    /// Answer admin queries such as `http://<address>/relay`, e.g. `127.0.0.1:9091`. Nothing
+    /// guards the endpoint, so keep it on a private address
+    #[clap(long)]
+    pub admin_addr: Option<SocketAddr>,
+}
+
+impl AdminOpt {
+    /// Starts answering queries about `node`, a no-op without `--admin-addr`.
+    pub fn start(&self, node: &NodeHandle) -> anyhow::Result<()> {
+        match self.admin_addr {
+            Some(address) => serve(address, node.clone()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Serves admin queries about `node` on `address` from a background task.
+pub fn serve(address: SocketAddr, node: NodeHandle) -> anyhow::Result<()> {
+    let server = Server::try_bind(&address)
+        .with_context(|| format!("binding admin endpoint to {address}"))?
+        .serve(make_service_fn(move |_| {
+            let node = node.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let node = node.clone();
+                    async move { Ok::<_, Infallible>(respond(&request, &node).await) }
+                }))
+            }
+        }));
+    tracing::info!("serving admin queries on http://{address}");
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            tracing::warn!("admin endpoint failed: {e}");
+        }
+    });
+    Ok(())
+}
+
+async fn respond(request: &Request<Body>, node: &NodeHandle) -> Response<Body> {
+    if request.method() != Method::GET || request.uri().path() != RELAY_PATH {
+        return status(StatusCode::NOT_FOUND);
+    }
+    let body = match node.relay_status().await {
+        Ok(relay_status) => serde_json::to_vec(&relay_status).map_err(anyhow::Error::from),
+        Err(e) => Err(e),
+    };
+    match body {
+        Ok(body) => Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .expect("static response parts are valid"),
+        Err(e) => {
+            tracing::warn!("answering admin query failed: {e}");
+            status(StatusCode::SERVICE_UNAVAILABLE)
+        }
+    }
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
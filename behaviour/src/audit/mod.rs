@@ -0,0 +1,348 @@
+//! Append-only audit log of what a relay server did for whom.
+//!
+//! Every reservation accepted, denied or revoked and every circuit opened, denied or closed is written
+//! as one JSON [`AuditRecord`] per line. Once the file reaches `--audit-max-bytes` it is
+//! renamed to `<file>.1`, older files shift to `<file>.2` and so on, and the oldest beyond
+//! `--audit-max-files` is deleted. [`scan`] goes through all of them, oldest first.
+//!
+//! A crash can still tear the last line of a file. Opening the log starts a new line after a
+//! torn one, and [`scan`] skips and reports lines that do not parse.
+
+use anyhow::Context;
+use clap::Args;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    ReservationAccepted,
+    ReservationDenied,
+    /// A reservation ended as its holder no longer passed the ACL.
+    ReservationRevoked,
+    CircuitOpened,
+    CircuitDenied,
+    CircuitClosed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// RFC 3339 in UTC with milliseconds.
+    pub time: String,
+    pub event: AuditEvent,
+    /// The peer reserving, or the source of a circuit.
+    pub src: PeerId,
+    /// IP address `src` connected to the relay from, unless it came through another relay.
+    pub src_ip: Option<IpAddr>,
+    pub dst: Option<PeerId>,
+    /// Approximate bytes relayed by a closed circuit: all stop protocol traffic with `dst`
+    /// while it was open, so it includes other circuits to `dst` open at the same time.
+    pub bytes: Option<u64>,
+    pub duration_ms: Option<u64>,
+    /// Why a request was denied, a reservation revoked or a circuit closed.
+    pub reason: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(event: AuditEvent, src: PeerId, src_ip: Option<IpAddr>) -> Self {
+        Self {
+            time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            event,
+            src,
+            src_ip,
+            dst: None,
+            bytes: None,
+            duration_ms: None,
+            reason: None,
+        }
+    }
+
+    pub fn time(&self) -> anyhow::Result<SystemTime> {
+        humantime::parse_rfc3339_weak(&self.time)
+            .with_context(|| format!("invalid audit time {}", self.time))
+    }
+
+    /// Whether `peer` was the source or the destination.
+    pub fn involves(&self, peer: &PeerId) -> bool {
+        self.src == *peer || self.dst.as_ref() == Some(peer)
+    }
+}
+
+/// Audit options of the relay server.
+#[derive(Debug, Clone, Args)]
+pub struct AuditOpt {
+    /// Append an audit record of every reservation and circuit to this file
+    #[clap(long)]
+    pub audit_log: Option<PathBuf>,
+
+    /// Size in bytes at which the audit log is rotated
+    #[clap(long, default_value_t = 64 * 1024 * 1024)]
+    pub audit_max_bytes: u64,
+
+    /// Rotated audit logs to keep besides the current one
+    #[clap(long, default_value_t = 10)]
+    pub audit_max_files: usize,
+}
+
+impl AuditOpt {
+    /// Opens the audit log, `None` without `--audit-log`.
+    pub fn open(&self) -> anyhow::Result<Option<AuditLog>> {
+        match &self.audit_log {
+            Some(path) => {
+                AuditLog::open(path.clone(), self.audit_max_bytes, self.audit_max_files).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl AuditLog {
+    pub fn open(path: PathBuf, max_bytes: u64, max_files: usize) -> anyhow::Result<Self> {
+        let mut file = append(&path)?;
+        let mut size = file.metadata()?.len();
+        if size > 0 && !ends_with_newline(&path)? {
+            file.write_all(b"\n")?;
+            size += 1;
+        }
+        Ok(Self {
+            path,
+            max_bytes,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    /// Appends `record` as one line, rotating the file first when it is full.
+    pub fn write(&mut self, record: &AuditRecord) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        // A crash can still tear this line; the next open starts a new one after it and
+        // `scan` skips it.
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        self.file.sync_all()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            remove_if_exists(&rotated(&self.path, self.max_files))?;
+            for n in (1..self.max_files).rev() {
+                rename_if_exists(&rotated(&self.path, n), &rotated(&self.path, n + 1))?;
+            }
+            fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+        self.file = append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// A line of an audit log that [`scan`] skipped because it is not a record.
+#[derive(Debug)]
+pub struct Skipped {
+    pub file: PathBuf,
+    /// 1-based line number.
+    pub line: usize,
+    pub error: serde_json::Error,
+}
+
+/// Hands the records of the audit log at `path` and its rotated files to `f`, oldest first,
+/// stopping at the first error. Lines that are not records, like one torn by a crash, are
+/// skipped and returned.
+pub fn scan(
+    path: &Path,
+    mut f: impl FnMut(AuditRecord) -> anyhow::Result<()>,
+) -> anyhow::Result<Vec<Skipped>> {
+    let mut files: Vec<PathBuf> = (1..)
+        .map(|n| rotated(path, n))
+        .take_while(|file| file.exists())
+        .collect();
+    files.reverse();
+    files.push(path.to_path_buf());
+    let mut skipped = Vec::new();
+    for file in files {
+        let mut reader = match File::open(&file) {
+            Ok(file) => BufReader::new(file),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("opening {}", file.display())),
+        };
+        let mut line = Vec::new();
+        let mut number = 0;
+        loop {
+            line.clear();
+            if reader
+                .read_until(b'\n', &mut line)
+                .with_context(|| format!("reading {}", file.display()))?
+                == 0
+            {
+                break;
+            }
+            number += 1;
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+            match serde_json::from_slice(&line) {
+                Ok(record) => f(record)?,
+                Err(error) => skipped.push(Skipped {
+                    file: file.clone(),
+                    line: number,
+                    error,
+                }),
+            }
+        }
+    }
+    Ok(skipped)
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+fn append(path: &Path) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("opening audit log {}", path.display()))
+}
+
+fn ends_with_newline(path: &Path) -> anyhow::Result<bool> {
+    let mut file = File::open(path)?;
+    let mut last = [0];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] == b'\n')
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_log(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("audit-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("audit.log")
+    }
+
+    fn record(n: u64) -> AuditRecord {
+        let mut record = AuditRecord::new(AuditEvent::CircuitClosed, PeerId::random(), None);
+        record.bytes = Some(n);
+        record
+    }
+
+    fn line_len() -> u64 {
+        serde_json::to_vec(&record(0)).unwrap().len() as u64 + 1
+    }
+
+    fn scanned(path: &Path) -> (Vec<u64>, Vec<Skipped>) {
+        let mut bytes = Vec::new();
+        let skipped = scan(path, |record| {
+            bytes.push(record.bytes.unwrap());
+            Ok(())
+        })
+        .unwrap();
+        (bytes, skipped)
+    }
+
+    #[test]
+    fn rotates_and_scans_oldest_first() {
+        let path = temp_log("rotate");
+        // Two records per file.
+        let mut log = AuditLog::open(path.clone(), 2 * line_len(), 2).unwrap();
+        for n in 0..7 {
+            log.write(&record(n)).unwrap();
+        }
+        assert!(rotated(&path, 1).exists());
+        assert!(rotated(&path, 2).exists());
+        assert!(!rotated(&path, 3).exists());
+        // Records 0 and 1 went with the oldest file.
+        let (bytes, skipped) = scanned(&path);
+        assert_eq!(bytes, vec![2, 3, 4, 5, 6]);
+        assert!(skipped.is_empty());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn keeps_no_rotated_files_without_max_files() {
+        let path = temp_log("no-files");
+        let mut log = AuditLog::open(path.clone(), line_len(), 0).unwrap();
+        for n in 0..3 {
+            log.write(&record(n)).unwrap();
+        }
+        assert!(!rotated(&path, 1).exists());
+        assert_eq!(scanned(&path).0, vec![2]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn a_record_larger_than_max_bytes_still_gets_written() {
+        let path = temp_log("large");
+        let mut log = AuditLog::open(path.clone(), 1, 1).unwrap();
+        log.write(&record(0)).unwrap();
+        log.write(&record(1)).unwrap();
+        assert_eq!(scanned(&path).0, vec![0, 1]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn skips_a_torn_line() {
+        let path = temp_log("torn");
+        let mut log = AuditLog::open(path.clone(), u64::MAX, 1).unwrap();
+        log.write(&record(0)).unwrap();
+        drop(log);
+        let line = serde_json::to_vec(&record(1)).unwrap();
+        append(&path)
+            .unwrap()
+            .write_all(&line[..line.len() / 2])
+            .unwrap();
+
+        // Reopening starts a new line, so the next record is not glued to the torn one.
+        let mut log = AuditLog::open(path.clone(), u64::MAX, 1).unwrap();
+        log.write(&record(2)).unwrap();
+        let (bytes, skipped) = scanned(&path);
+        assert_eq!(bytes, vec![0, 2]);
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].file, path);
+        assert_eq!(skipped[0].line, 2);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
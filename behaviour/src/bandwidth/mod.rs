@@ -0,0 +1,467 @@
+//! Byte counters per peer and negotiated protocol.
+//!
+//! The muxer of every connection is wrapped so each substream counts what passes through it.
+//! Substreams do not know which protocol they carry, so the multistream-select negotiation at
+//! their start is read along: the protocol is the first one named by both sides.
+//!
+//! Totals of a peer are kept while it has a connection open. The exported metrics add up all
+//! peers, per protocol and direction, so their number of series stays bounded.
+
+use futures::io::{AsyncRead, AsyncWrite, IoSlice, IoSliceMut};
+use futures::ready;
+use libp2p::core::muxing::{
+    StreamMuxer, StreamMuxerBox, StreamMuxerEvent, StreamMuxerExt, SubstreamBox,
+};
+use libp2p::PeerId;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::registry::{Registry, Unit};
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// Protocol of substreams whose negotiation could not be followed.
+pub const UNKNOWN_PROTOCOL: &str = "unknown";
+
+const MULTISTREAM_PROTOCOL: &str = "/multistream/1.0.0";
+/// Negotiations are a few short messages, anything longer is not one we can follow.
+const MAX_NEGOTIATION_BYTES: usize = 1024;
+
+/// Bytes exchanged with one peer over one protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Traffic {
+    pub peer: PeerId,
+    pub protocol: String,
+    pub inbound: u64,
+    pub outbound: u64,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct Labels {
+    protocol: String,
+    direction: &'static str,
+}
+
+#[derive(Debug)]
+struct Counters {
+    inbound: AtomicU64,
+    outbound: AtomicU64,
+    inbound_metric: Counter,
+    outbound_metric: Counter,
+}
+
+impl Counters {
+    fn add(&self, inbound: u64, outbound: u64) {
+        if inbound > 0 {
+            self.inbound.fetch_add(inbound, Ordering::Relaxed);
+            self.inbound_metric.inc_by(inbound);
+        }
+        if outbound > 0 {
+            self.outbound.fetch_add(outbound, Ordering::Relaxed);
+            self.outbound_metric.inc_by(outbound);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Peers {
+    counters: HashMap<(PeerId, String), Arc<Counters>>,
+    /// Open connections per peer, its counters go when the last one closes.
+    connections: HashMap<PeerId, usize>,
+}
+
+/// Shared byte counters of every connection of a node. Clones count into the same totals.
+#[derive(Debug, Clone, Default)]
+pub struct Bandwidth {
+    peers: Arc<Mutex<Peers>>,
+    metrics: Family<Labels, Counter>,
+}
+
+impl Bandwidth {
+    /// Exports the counters as `p2p_bandwidth_bytes_total{protocol, direction}`.
+    pub fn register(&self, registry: &mut Registry) {
+        registry.sub_registry_with_prefix("p2p").register_with_unit(
+            "bandwidth",
+            "Bytes exchanged by peer, negotiated protocol and direction",
+            Unit::Bytes,
+            self.metrics.clone(),
+        );
+    }
+
+    /// Current totals of the connected peers, sorted by peer and protocol.
+    pub fn snapshot(&self) -> Vec<Traffic> {
+        let peers = self.peers.lock().expect("bandwidth lock");
+        let mut traffic: Vec<Traffic> = peers
+            .counters
+            .iter()
+            .map(|((peer, protocol), counters)| Traffic {
+                peer: *peer,
+                protocol: protocol.clone(),
+                inbound: counters.inbound.load(Ordering::Relaxed),
+                outbound: counters.outbound.load(Ordering::Relaxed),
+            })
+            .collect();
+        traffic.sort_by(|a, b| (a.peer, &a.protocol).cmp(&(b.peer, &b.protocol)));
+        traffic
+    }
+
+    /// Inbound and outbound bytes exchanged with `peer` over `protocol`.
+    pub fn traffic(&self, peer: &PeerId, protocol: &str) -> (u64, u64) {
+        let peers = self.peers.lock().expect("bandwidth lock");
+        match peers.counters.get(&(*peer, protocol.to_string())) {
+            Some(counters) => (
+                counters.inbound.load(Ordering::Relaxed),
+                counters.outbound.load(Ordering::Relaxed),
+            ),
+            None => (0, 0),
+        }
+    }
+
+    /// Wraps the muxer of a connection to `peer` so its substreams are counted.
+    pub fn wrap(&self, peer: PeerId, muxer: StreamMuxerBox) -> StreamMuxerBox {
+        let mut peers = self.peers.lock().expect("bandwidth lock");
+        *peers.connections.entry(peer).or_default() += 1;
+        StreamMuxerBox::new(CountingMuxer {
+            inner: muxer,
+            peer,
+            bandwidth: self.clone(),
+        })
+    }
+
+    fn counters(&self, peer: PeerId, protocol: &str) -> Arc<Counters> {
+        let mut peers = self.peers.lock().expect("bandwidth lock");
+        let new_counters = || {
+            let labels = |direction| Labels {
+                protocol: protocol.to_string(),
+                direction,
+            };
+            // One at a time, `get_or_create` holds a read lock on the family until its guard
+            // is dropped and creating the second series needs the write lock.
+            let inbound_metric = self.metrics.get_or_create(&labels("inbound")).clone();
+            let outbound_metric = self.metrics.get_or_create(&labels("outbound")).clone();
+            Arc::new(Counters {
+                inbound: AtomicU64::new(0),
+                outbound: AtomicU64::new(0),
+                inbound_metric,
+                outbound_metric,
+            })
+        };
+        if !peers.connections.contains_key(&peer) {
+            // A substream outliving its connection still counts towards the metrics.
+            return new_counters();
+        }
+        peers
+            .counters
+            .entry((peer, protocol.to_string()))
+            .or_insert_with(new_counters)
+            .clone()
+    }
+
+    fn on_connection_closed(&self, peer: PeerId) {
+        let mut peers = self.peers.lock().expect("bandwidth lock");
+        let Some(connections) = peers.connections.get_mut(&peer) else {
+            return;
+        };
+        *connections -= 1;
+        if *connections == 0 {
+            peers.connections.remove(&peer);
+            peers.counters.retain(|(counted, _), _| *counted != peer);
+        }
+    }
+}
+
+struct CountingMuxer {
+    inner: StreamMuxerBox,
+    peer: PeerId,
+    bandwidth: Bandwidth,
+}
+
+impl Drop for CountingMuxer {
+    fn drop(&mut self) {
+        self.bandwidth.on_connection_closed(self.peer);
+    }
+}
+
+impl CountingMuxer {
+    fn substream(&self, inner: SubstreamBox) -> CountingSubstream {
+        CountingSubstream {
+            inner,
+            peer: self.peer,
+            bandwidth: self.bandwidth.clone(),
+            state: State::Negotiating(Negotiation::default()),
+        }
+    }
+}
+
+impl StreamMuxer for CountingMuxer {
+    type Substream = CountingSubstream;
+    type Error = io::Error;
+
+    fn poll_inbound(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let inner = ready!(self.inner.poll_inbound_unpin(cx))?;
+        Poll::Ready(Ok(self.substream(inner)))
+    }
+
+    fn poll_outbound(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let inner = ready!(self.inner.poll_outbound_unpin(cx))?;
+        Poll::Ready(Ok(self.substream(inner)))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_close_unpin(cx)
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        self.inner.poll_unpin(cx)
+    }
+}
+
+/// The start of a substream, kept until its protocol is known.
+#[derive(Debug, Default)]
+struct Negotiation {
+    read: Vec<u8>,
+    written: Vec<u8>,
+    inbound: u64,
+    outbound: u64,
+}
+
+impl Negotiation {
+    /// The negotiated protocol, [`UNKNOWN_PROTOCOL`] once there is too much to follow.
+    fn protocol(&self) -> Option<String> {
+        let read = multistream_messages(&self.read);
+        let agreed = multistream_messages(&self.written)
+            .into_iter()
+            .find(|name| name != MULTISTREAM_PROTOCOL && name != "na" && read.contains(name));
+        match agreed {
+            Some(protocol) => Some(protocol),
+            None if self.read.len().max(self.written.len()) >= MAX_NEGOTIATION_BYTES => {
+                Some(UNKNOWN_PROTOCOL.to_string())
+            }
+            None => None,
+        }
+    }
+}
+
+enum State {
+    Negotiating(Negotiation),
+    Counting(Arc<Counters>),
+}
+
+struct CountingSubstream {
+    inner: SubstreamBox,
+    peer: PeerId,
+    bandwidth: Bandwidth,
+    state: State,
+}
+
+impl CountingSubstream {
+    fn record(&mut self, inbound: &[u8], outbound: &[u8]) {
+        let negotiation = match &mut self.state {
+            State::Counting(counters) => {
+                return counters.add(inbound.len() as u64, outbound.len() as u64);
+            }
+            State::Negotiating(negotiation) => negotiation,
+        };
+        negotiation.inbound += inbound.len() as u64;
+        negotiation.outbound += outbound.len() as u64;
+        let room = |buffer: &Vec<u8>| MAX_NEGOTIATION_BYTES.saturating_sub(buffer.len());
+        let read = &inbound[..inbound.len().min(room(&negotiation.read))];
+        negotiation.read.extend_from_slice(read);
+        let written = &outbound[..outbound.len().min(room(&negotiation.written))];
+        negotiation.written.extend_from_slice(written);
+        if let Some(protocol) = negotiation.protocol() {
+            let counters = self.bandwidth.counters(self.peer, &protocol);
+            counters.add(negotiation.inbound, negotiation.outbound);
+            self.state = State::Counting(counters);
+        }
+    }
+}
+
+impl Drop for CountingSubstream {
+    fn drop(&mut self) {
+        // Substreams closed during negotiation still used the connection.
+        if let State::Negotiating(negotiation) = &self.state {
+            if negotiation.inbound > 0 || negotiation.outbound > 0 {
+                let counters = self.bandwidth.counters(self.peer, UNKNOWN_PROTOCOL);
+                counters.add(negotiation.inbound, negotiation.outbound);
+            }
+        }
+    }
+}
+
+impl AsyncRead for CountingSubstream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let read = ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.record(&buf[..read], &[]);
+        Poll::Ready(Ok(read))
+    }
+
+    fn poll_read_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let read = ready!(Pin::new(&mut self.inner).poll_read_vectored(cx, bufs))?;
+        let mut remaining = read;
+        for buf in bufs.iter() {
+            let len = remaining.min(buf.len());
+            self.record(&buf[..len], &[]);
+            remaining -= len;
+        }
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl AsyncWrite for CountingSubstream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.record(&[], &buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write_vectored(cx, bufs))?;
+        let mut remaining = written;
+        for buf in bufs {
+            let len = remaining.min(buf.len());
+            self.record(&[], &buf[..len]);
+            remaining -= len;
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// The names in a run of multistream-select messages, each an unsigned varint length followed
+/// by a newline terminated name. Stops at the first incomplete or malformed message.
+fn multistream_messages(mut data: &[u8]) -> Vec<String> {
+    let mut names = Vec::new();
+    while let Some((len, rest)) = read_uvarint(data) {
+        let Some(message) = rest.get(..len) else {
+            break;
+        };
+        let Some(name) = message.strip_suffix(b"\n") else {
+            break;
+        };
+        let Ok(name) = std::str::from_utf8(name) else {
+            break;
+        };
+        names.push(name.to_string());
+        data = &rest[len..];
+    }
+    names
+}
+
+fn read_uvarint(data: &[u8]) -> Option<(usize, &[u8])> {
+    let mut value = 0usize;
+    for (i, byte) in data.iter().enumerate().take(4) {
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, &data[i + 1..]));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(names: &[&str]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for name in names {
+            data.push(name.len() as u8 + 1);
+            data.extend_from_slice(name.as_bytes());
+            data.push(b'\n');
+        }
+        data
+    }
+
+    fn negotiation(written: &[&str], read: &[&str]) -> Negotiation {
+        Negotiation {
+            read: messages(read),
+            written: messages(written),
+            ..Negotiation::default()
+        }
+    }
+
+    #[test]
+    fn reads_multistream_messages_up_to_the_first_incomplete_one() {
+        let mut data = messages(&[MULTISTREAM_PROTOCOL, "/chat/0.2.0"]);
+        assert_eq!(
+            multistream_messages(&data),
+            vec![MULTISTREAM_PROTOCOL, "/chat/0.2.0"]
+        );
+        data.extend_from_slice(&messages(&["/ipfs/ping/1.0.0"])[..5]);
+        assert_eq!(multistream_messages(&data).len(), 2);
+        assert!(multistream_messages(&[0x80, 0x80, 0x80, 0x80, 0x01]).is_empty());
+    }
+
+    #[test]
+    fn the_protocol_is_the_first_one_named_by_both_sides() {
+        let rejected = negotiation(
+            &[MULTISTREAM_PROTOCOL, "/chat/0.2.0", "/chat/0.1.0"],
+            &[MULTISTREAM_PROTOCOL, "na", "/chat/0.1.0"],
+        );
+        assert_eq!(rejected.protocol().as_deref(), Some("/chat/0.1.0"));
+        let pending = negotiation(
+            &[MULTISTREAM_PROTOCOL, "/chat/0.2.0"],
+            &[MULTISTREAM_PROTOCOL],
+        );
+        assert_eq!(pending.protocol(), None);
+    }
+
+    #[test]
+    fn long_negotiations_are_unknown() {
+        let mut negotiation = negotiation(&[MULTISTREAM_PROTOCOL], &[]);
+        negotiation.read = vec![0xff; MAX_NEGOTIATION_BYTES];
+        assert_eq!(negotiation.protocol().as_deref(), Some(UNKNOWN_PROTOCOL));
+    }
+
+    #[test]
+    fn counters_go_with_the_last_connection() {
+        let bandwidth = Bandwidth::default();
+        let peer = PeerId::random();
+        bandwidth.peers.lock().unwrap().connections.insert(peer, 2);
+        bandwidth.counters(peer, "/chat/0.2.0").add(3, 4);
+        bandwidth.on_connection_closed(peer);
+        assert_eq!(bandwidth.traffic(&peer, "/chat/0.2.0"), (3, 4));
+        bandwidth.on_connection_closed(peer);
+        assert!(bandwidth.snapshot().is_empty());
+        // Late substreams only count towards the metrics.
+        bandwidth.counters(peer, "/chat/0.2.0").add(1, 1);
+        assert!(bandwidth.snapshot().is_empty());
+    }
+}
@@ -1,188 +1,380 @@
-// use libp2p::{kad, PeerId, ping, relay, request_response, Swarm, Transport, tcp, noise};
-// use libp2p::swarm::{keep_alive, NetworkBehaviour, SwarmBuilder};
-// use libp2p::{autonat, dcutr, identify};
-// use libp2p::core::muxing::StreamMuxerBox;
-// use libp2p::core::transport::{Boxed, MemoryTransport, OrTransport};
-// use libp2p::core::upgrade;
-// use libp2p::identity::{Keypair, PublicKey};
-// use libp2p::plaintext::PlainText2Config;
-// use libp2p::swarm::behaviour::toggle::Toggle;
-// use codec::chat::{ChatCodec, ChatRequest, ChatResponse};
-// use futures::io::{AsyncRead, AsyncWrite};
-// use futures::future::Either;
-// use void;
-// use crate::behaviour_trait::autonat::Autonat;
-// use crate::behaviour_trait::chat::Chat;
-// use crate::behaviour_trait::dcutr::Dcutr;
-// use crate::behaviour_trait::identify::Identify;
-// use crate::behaviour_trait::relay_server::RelayServer;
-//
-//
-//
-// #[derive(NetworkBehaviour)]
-// #[behaviour(out_event = "Event")]
-// pub struct Behaviour {
-//     pub ping: ping::Behaviour,
-//     pub identify: identify::Behaviour,
-//     pub keep_alive: keep_alive::Behaviour,
-//     // pub auto_nat: autonat::Behaviour,
-//     pub dcutr: dcutr::Behaviour,
-//     pub chat: request_response::Behaviour<ChatCodec>,
-//     pub relay_server: Toggle<relay::Behaviour>,
-//     pub relay_client: Toggle<relay::client::Behaviour>,
-// }
-//
-//
-// impl Identify for Behaviour{}
-//
-// impl Autonat for Behaviour {}
-//
-// impl Dcutr for Behaviour {}
-//
-// impl RelayServer for Behaviour {}
-//
-// impl Chat for Behaviour{}
-//
-// impl Behaviour {
-//     pub async fn new_relay_server(keypair: &Keypair) -> anyhow::Result<Swarm<Self>> {
-//         let local_public_key = keypair.public();
-//         let peer_id = local_public_key.to_peer_id();
-//         let tcp_transport = tcp::tokio::Transport::default();
-//         let transport = Self::upgrade_transport(tcp_transport, local_public_key.clone());
-//         let behaviour = Self{
-//             ping: ping::Behaviour::default(),
-//             identify: Self::identify(Self::identify_config("/identify/0.1.0".to_string(), local_public_key, "relay".to_string(), Some(64 * 1024)).await).await,
-//             keep_alive: Default::default(),
-//             // auto_nat: Self::autonat(peer_id, Self::autonat_config(false, 0, 5, 5).await).await,
-//             dcutr: Self::dcutr(peer_id).await,
-//             chat: Self::chat(&vec![]).await?.unwrap(),
-//             relay_server: Some(Self::replay_server(peer_id, Default::default()).await).into(),
-//             relay_client: Toggle::from(None),
-//         };
-//         Ok(SwarmBuilder::with_tokio_executor(transport, behaviour, peer_id).build())
-//     }
-//
-//     pub async fn new_relay_client(keypair: &Keypair) -> anyhow::Result<Swarm<Behaviour>> {
-//         let local_public_key = keypair.public();
-//         let peer_id = local_public_key.to_peer_id();
-//
-//         let (relay_transport, relay_client) = relay::client::new(peer_id);
-//         let data = OrTransport::new(relay_transport, tcp::tokio::Transport::default()).boxed();
-//         let transport = Self::upgrade_transport(
-//             data,
-//             keypair.clone(),
-//         );
-//
-//         let b = Self{
-//             ping: ping::Behaviour::default(),
-//             identify: Self::identify(Self::identify_config("/identify/0.1.0".to_string(), local_public_key, "relay".to_string(), Some(64 * 1024)).await).await,
-//             keep_alive: Default::default(),
-//             // auto_nat: Self::autonat(peer_id, Self::autonat_config(true, 0, 5, 5).await).await,
-//             dcutr: Self::dcutr(peer_id).await,
-//             chat: Self::chat(&vec!["/ip4/127.0.0.1/tcp/9999".parse()?]).await?.unwrap(),
-//             relay_server: Toggle::from(None),
-//             relay_client: Some(relay_client).into(),
-//         };
-//
-//         Ok(SwarmBuilder::with_tokio_executor(
-//             transport,
-//             b,
-//             peer_id,
-//         )
-//             .build())
-//     }
-//
-//     fn upgrade_transport(
-//         transport: tcp::tokio::Transport,
-//         local_key: Keypair,
-//     ) -> Boxed<(PeerId, StreamMuxerBox)>
-//         where
-//             StreamSink: AsyncRead + AsyncWrite + Send + Unpin + 'static,
-//     {
-//         transport
-//             .upgrade(upgrade::Version::V1)
-//             .authenticate(noise::NoiseAuthenticated::xx(&local_key))
-//             .multiplex(libp2p::yamux::YamuxConfig::default())
-//             .boxed()
-//     }
-// }
-//
-// #[derive(Debug)]
-// pub enum Event {
-//     Ping(ping::Event),
-//     Identify(identify::Event),
-//     Kademlia(kad::KademliaEvent),
-//     // Mdns(mdns::Event),
-//     Chat(request_response::Event<ChatRequest, ChatResponse>),
-//     Autonat(autonat::Event),
-//     Relay(relay::Event),
-//     RelayClient(relay::client::Event),
-//     Dcutr(dcutr::Event),
-//     // Gossipsub(gossipsub::Event),
-// }
-//
-// impl From<ping::Event> for Event {
-//     fn from(event: ping::Event) -> Self {
-//         Event::Ping(event)
-//     }
-// }
-//
-// impl From<identify::Event> for Event {
-//     fn from(event: identify::Event) -> Self {
-//         Event::Identify(event)
-//     }
-// }
-//
-// impl From<kad::KademliaEvent> for Event {
-//     fn from(event: kad::KademliaEvent) -> Self {
-//         Event::Kademlia(event)
-//     }
-// }
-//
-// // impl From<mdns::Event> for Event {
-// //     fn from(event: mdns::Event) -> Self {
-// //         Event::Mdns(event)
-// //     }
-// // }
-//
-// // impl From<gossipsub::Event> for Event {
-// //     fn from(event: gossipsub::Event) -> Self {
-// //         Event::Gossipsub(event)
-// //     }
-// // }
-//
-// // impl From<autonat::Event> for Event {
-// //     fn from(event: autonat::Event) -> Self {
-// //         Event::Autonat(event)
-// //     }
-// // }
-//
-// impl From<relay::Event> for Event {
-//     fn from(event: relay::Event) -> Self {
-//         Event::Relay(event)
-//     }
-// }
-//
-// impl From<relay::client::Event> for Event {
-//     fn from(event: relay::client::Event) -> Self {
-//         Event::RelayClient(event)
-//     }
-// }
-//
-// impl From<dcutr::Event> for Event {
-//     fn from(event: dcutr::Event) -> Self {
-//         Event::Dcutr(event)
-//     }
-// }
-//
-// impl From<request_response::Event<ChatRequest, ChatResponse>> for Event {
-//     fn from(event: request_response::Event<ChatRequest, ChatResponse>) -> Self {
-//         Event::Chat(event)
-//     }
-// }
-//
-// impl From<void::Void> for Event {
-//     fn from(e: void::Void) -> Self {
-//         void::unreachable(e)
-//     }
-// }
+use crate::bandwidth::Bandwidth;
+use crate::behaviour_trait::autonat::Autonat;
+use crate::behaviour_trait::chat::Chat;
+use crate::behaviour_trait::dcutr::Dcutr;
+use crate::behaviour_trait::gossipsub::Gossipsub;
+use crate::behaviour_trait::identify::Identify;
+use crate::behaviour_trait::kad::Kad;
+use crate::behaviour_trait::keep_alive::KeepAlive;
+use crate::behaviour_trait::mdns::Mdns;
+use crate::behaviour_trait::ping::Ping;
+use crate::behaviour_trait::relay_client::RelayClient;
+use crate::behaviour_trait::relay_server::RelayServer;
+use crate::behaviour_trait::serde_protocol::SerdeRequestResponse;
+use crate::migrate;
+use crate::relay_config::{LimiterState, RelayConfig};
+use codec::chat::{ChatCodec, ChatReply, ChatRequest, DEFAULT_MAX_MESSAGE_SIZE};
+use codec::voucher::{VoucherCodec, VoucherRequest, VoucherResponse, VOUCHER_PROTOCOL};
+use futures::io::{AsyncRead, AsyncWrite};
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::{Boxed, OrTransport};
+use libp2p::core::upgrade;
+use libp2p::gossipsub::ValidationMode;
+use libp2p::identity::Keypair;
+use libp2p::kad::store::MemoryStore;
+use libp2p::swarm::behaviour::toggle::Toggle;
+use libp2p::swarm::{keep_alive, NetworkBehaviour, SwarmBuilder};
+use libp2p::{autonat, dcutr, gossipsub, identify, kad, mdns, ping, relay, request_response};
+use libp2p::{noise, tcp, yamux, Multiaddr, PeerId, Swarm, Transport};
+use std::time::Duration;
+
+/// Selects which sub-protocols of [`Behaviour`] are enabled.
+///
+/// Every sub-protocol is wrapped in a [`Toggle`], so a disabled one costs nothing at runtime.
+#[derive(Debug, Clone)]
+pub struct BehaviourConfig {
+    pub ping: bool,
+    pub identify: bool,
+    pub keep_alive: bool,
+    pub kad: bool,
+    pub gossipsub: bool,
+    pub mdns: bool,
+    pub autonat: bool,
+    pub dcutr: bool,
+    pub chat: bool,
+    pub relay_server: bool,
+    pub relay_client: bool,
+    /// `/p2p/<peer id>` terminated addresses added to kad and chat on start.
+    pub bootstrap_peers: Vec<Multiaddr>,
+    pub protocol_version: String,
+    pub agent_version: String,
+    /// How long an outbound chat request waits for its response.
+    pub chat_request_timeout: Duration,
+    pub chat_max_request_size: usize,
+    pub chat_max_response_size: usize,
+    /// Counters every connection records its traffic into.
+    pub bandwidth: Bandwidth,
+    /// Limits of the relay server.
+    pub relay: RelayConfig,
+    /// State the relay server's limiters share with the node.
+    pub relay_limiters: LimiterState,
+}
+
+impl Default for BehaviourConfig {
+    fn default() -> Self {
+        Self {
+            ping: true,
+            identify: true,
+            keep_alive: true,
+            kad: true,
+            gossipsub: false,
+            mdns: false,
+            autonat: false,
+            dcutr: false,
+            chat: true,
+            relay_server: false,
+            relay_client: false,
+            bootstrap_peers: vec![],
+            protocol_version: "/identify/0.1.0".to_string(),
+            agent_version: "relay".to_string(),
+            chat_request_timeout: Duration::from_secs(10),
+            chat_max_request_size: DEFAULT_MAX_MESSAGE_SIZE,
+            chat_max_response_size: DEFAULT_MAX_MESSAGE_SIZE,
+            bandwidth: Bandwidth::default(),
+            relay: RelayConfig::default(),
+            relay_limiters: LimiterState::default(),
+        }
+    }
+}
+
+impl BehaviourConfig {
+    /// The protocols served by `relay_server`.
+    pub fn relay_server() -> Self {
+        Self {
+            relay_server: true,
+            ..Default::default()
+        }
+    }
+
+    /// The protocols used by `relay_client`, which upgrades relayed connections to direct ones
+    /// with DCUtR.
+    pub fn relay_client() -> Self {
+        Self {
+            relay_client: true,
+            dcutr: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn chat_codec(&self) -> ChatCodec {
+        ChatCodec::new(self.chat_max_request_size, self.chat_max_response_size)
+    }
+}
+
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "Event")]
+pub struct Behaviour {
+    pub ping: Toggle<ping::Behaviour>,
+    pub identify: Toggle<identify::Behaviour>,
+    pub keep_alive: Toggle<keep_alive::Behaviour>,
+    pub kad: Toggle<kad::Kademlia<MemoryStore>>,
+    pub gossipsub: Toggle<gossipsub::Behaviour>,
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
+    pub autonat: Toggle<autonat::Behaviour>,
+    pub dcutr: Toggle<dcutr::Behaviour>,
+    /// Moves traffic to the direct connections DCUtR sets up, enabled along with it.
+    pub migrate: Toggle<migrate::Behaviour>,
+    pub chat: Toggle<request_response::Behaviour<ChatCodec>>,
+    pub relay_server: Toggle<relay::Behaviour>,
+    pub relay_client: Toggle<relay::client::Behaviour>,
+    /// `/relay-voucher/0.1.0`, on clients and on servers whose ACL requires vouchers.
+    pub voucher: Toggle<request_response::Behaviour<VoucherCodec>>,
+}
+
+impl Ping for Behaviour {}
+
+impl Identify for Behaviour {}
+
+impl KeepAlive for Behaviour {}
+
+impl Kad for Behaviour {}
+
+impl Gossipsub for Behaviour {}
+
+impl Mdns for Behaviour {}
+
+impl Autonat for Behaviour {}
+
+impl Dcutr for Behaviour {}
+
+impl Chat for Behaviour {}
+
+impl RelayServer for Behaviour {}
+
+impl RelayClient for Behaviour {}
+
+impl SerdeRequestResponse for Behaviour {}
+
+impl Behaviour {
+    /// Builds the transport and the behaviour described by `config` and wraps them in a swarm
+    /// driven by the tokio executor.
+    pub async fn new(keypair: &Keypair, config: BehaviourConfig) -> anyhow::Result<Swarm<Self>> {
+        // Hole punching dials out from the port we listen on.
+        let tcp_config = tcp::Config::new().port_reuse(config.dcutr);
+        Self::with_transport(keypair, config, tcp::tokio::Transport::new(tcp_config)).await
+    }
+
+    /// Like [`new`](Self::new), over `transport` instead of TCP.
+    pub async fn with_transport<T>(
+        keypair: &Keypair,
+        config: BehaviourConfig,
+        transport: T,
+    ) -> anyhow::Result<Swarm<Self>>
+    where
+        T: Transport + Send + Unpin + 'static,
+        T::Output: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+        T::Dial: Send + 'static,
+        T::ListenerUpgrade: Send + 'static,
+        T::Error: Send + Sync + 'static,
+    {
+        let local_public_key = keypair.public();
+        let peer_id = local_public_key.to_peer_id();
+
+        let (transport, relay_client) = if config.relay_client {
+            let (relay_transport, relay_client) = relay::client::new(peer_id);
+            let transport = OrTransport::new(relay_transport, transport);
+            (
+                Self::upgrade_transport(transport, keypair, config.bandwidth.clone())?,
+                Some(Self::relay_client(Some(relay_client)).await),
+            )
+        } else {
+            let transport = Self::upgrade_transport(transport, keypair, config.bandwidth.clone())?;
+            (transport, None)
+        };
+
+        let mut behaviour = Self {
+            ping: None.into(),
+            identify: None.into(),
+            keep_alive: None.into(),
+            kad: None.into(),
+            gossipsub: None.into(),
+            mdns: None.into(),
+            autonat: None.into(),
+            dcutr: None.into(),
+            migrate: None.into(),
+            chat: None.into(),
+            relay_server: None.into(),
+            relay_client: relay_client.into(),
+            voucher: None.into(),
+        };
+        if config.ping {
+            behaviour.ping = Some(Self::ping().await).into();
+        }
+        if config.identify {
+            let identify_config = Self::identify_config(
+                config.protocol_version.clone(),
+                local_public_key,
+                config.agent_version.clone(),
+                None,
+            )
+            .await;
+            behaviour.identify = Some(Self::identify(identify_config).await).into();
+        }
+        if config.keep_alive {
+            behaviour.keep_alive = Some(Self::keep_alive().await).into();
+        }
+        if config.kad {
+            let kad = Self::kad(peer_id, None, None, 60, None, &config.bootstrap_peers).await;
+            behaviour.kad = Some(kad).into();
+        }
+        if config.gossipsub {
+            let privacy = Self::message_authenticity_signed(keypair.clone()).await;
+            let gossipsub_config = Self::gossipsub_config(ValidationMode::Strict).await;
+            behaviour.gossipsub = Some(Self::gossipsub(privacy, gossipsub_config).await?).into();
+        }
+        if config.mdns {
+            behaviour.mdns = Some(Self::mdns_default(peer_id).await?).into();
+        }
+        if config.autonat {
+            let autonat_config = Self::autonat_config(!config.relay_server, 15, 15 * 60, 90).await;
+            behaviour.autonat = Some(Self::autonat(peer_id, autonat_config).await).into();
+        }
+        if config.dcutr {
+            behaviour.dcutr = Some(Self::dcutr(peer_id).await).into();
+            behaviour.migrate = Some(migrate::Behaviour::default()).into();
+        }
+        if config.chat {
+            behaviour.chat = Self::chat(
+                &config.bootstrap_peers,
+                config.chat_codec(),
+                config.chat_request_timeout,
+            )
+            .await?
+            .into();
+        }
+        if config.relay_server {
+            let relay_config =
+                Self::replay_server_config(&config.relay, &config.relay_limiters).await;
+            behaviour.relay_server = Some(Self::replay_server(peer_id, relay_config).await).into();
+        }
+        if config.relay_client || (config.relay_server && config.relay.acl.requires_vouchers()) {
+            let voucher = Self::serde_request_response(
+                VOUCHER_PROTOCOL,
+                VoucherCodec::default(),
+                config.chat_request_timeout,
+            )
+            .await;
+            behaviour.voucher = Some(voucher).into();
+        }
+
+        Ok(SwarmBuilder::with_tokio_executor(transport, behaviour, peer_id).build())
+    }
+
+    fn upgrade_transport<T>(
+        transport: T,
+        keypair: &Keypair,
+        bandwidth: Bandwidth,
+    ) -> anyhow::Result<Boxed<(PeerId, StreamMuxerBox)>>
+    where
+        T: Transport + Send + Unpin + 'static,
+        T::Output: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+        T::Dial: Send + 'static,
+        T::ListenerUpgrade: Send + 'static,
+        T::Error: Send + Sync + 'static,
+    {
+        Ok(transport
+            .upgrade(upgrade::Version::V1)
+            .authenticate(noise::Config::new(keypair)?)
+            .multiplex(yamux::Config::default())
+            .timeout(Duration::from_secs(20))
+            .map(move |(peer, muxer), _| (peer, bandwidth.wrap(peer, StreamMuxerBox::new(muxer))))
+            .boxed())
+    }
+}
+
+#[derive(Debug)]
+pub enum Event {
+    Ping(ping::Event),
+    Identify(identify::Event),
+    Kademlia(kad::KademliaEvent),
+    Gossipsub(gossipsub::Event),
+    Mdns(mdns::Event),
+    Autonat(autonat::Event),
+    Dcutr(dcutr::Event),
+    Chat(request_response::Event<ChatRequest, ChatReply>),
+    Relay(relay::Event),
+    RelayClient(relay::client::Event),
+    Voucher(request_response::Event<VoucherRequest, VoucherResponse>),
+}
+
+impl From<ping::Event> for Event {
+    fn from(event: ping::Event) -> Self {
+        Event::Ping(event)
+    }
+}
+
+impl From<identify::Event> for Event {
+    fn from(event: identify::Event) -> Self {
+        Event::Identify(event)
+    }
+}
+
+impl From<kad::KademliaEvent> for Event {
+    fn from(event: kad::KademliaEvent) -> Self {
+        Event::Kademlia(event)
+    }
+}
+
+impl From<gossipsub::Event> for Event {
+    fn from(event: gossipsub::Event) -> Self {
+        Event::Gossipsub(event)
+    }
+}
+
+impl From<mdns::Event> for Event {
+    fn from(event: mdns::Event) -> Self {
+        Event::Mdns(event)
+    }
+}
+
+impl From<autonat::Event> for Event {
+    fn from(event: autonat::Event) -> Self {
+        Event::Autonat(event)
+    }
+}
+
+impl From<dcutr::Event> for Event {
+    fn from(event: dcutr::Event) -> Self {
+        Event::Dcutr(event)
+    }
+}
+
+impl From<request_response::Event<ChatRequest, ChatReply>> for Event {
+    fn from(event: request_response::Event<ChatRequest, ChatReply>) -> Self {
+        Event::Chat(event)
+    }
+}
+
+impl From<relay::Event> for Event {
+    fn from(event: relay::Event) -> Self {
+        Event::Relay(event)
+    }
+}
+
+impl From<relay::client::Event> for Event {
+    fn from(event: relay::client::Event) -> Self {
+        Event::RelayClient(event)
+    }
+}
+
+impl From<request_response::Event<VoucherRequest, VoucherResponse>> for Event {
+    fn from(event: request_response::Event<VoucherRequest, VoucherResponse>) -> Self {
+        Event::Voucher(event)
+    }
+}
+
+impl From<void::Void> for Event {
+    fn from(e: void::Void) -> Self {
+        void::unreachable(e)
+    }
+}
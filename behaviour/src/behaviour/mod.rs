@@ -1 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod behaviour;
+
+pub use behaviour::{Behaviour, BehaviourConfig, Event};
@@ -4,6 +4,6 @@ use libp2p::swarm::keep_alive;
 #[async_trait]
 pub trait KeepAlive {
     async fn keep_alive() -> keep_alive::Behaviour {
-        keep_alive::Behaviour::default()
+        keep_alive::Behaviour
     }
 }
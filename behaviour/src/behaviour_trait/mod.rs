@@ -20,6 +20,7 @@ pub mod mdns;
 pub mod ping;
 pub mod relay_client;
 pub mod relay_server;
+pub mod serde_protocol;
 
 pub trait AllTrait:
     Ping + KeepAlive + Identify + Mdns + Autonat + Dcutr + Kad + Gossipsub + RelayClient + RelayServer
@@ -1,3 +1,4 @@
+use crate::relay_config::{LimiterState, RelayConfig};
 use async_trait::async_trait;
 use libp2p::relay::Config;
 use libp2p::PeerId;
@@ -8,7 +9,7 @@ pub trait RelayServer {
         libp2p::relay::Behaviour::new(peer_id, config)
     }
 
-    async fn replay_server_config() -> Config {
-        Config::default()
+    async fn replay_server_config(config: &RelayConfig, state: &LimiterState) -> Config {
+        config.to_libp2p(state)
     }
 }
@@ -0,0 +1,24 @@
+use async_trait::async_trait;
+use codec::serde_codec::{SerdeCodec, SerdeProtocol};
+use libp2p::request_response::{Behaviour, Codec, Config, ProtocolSupport};
+use std::iter;
+use std::time::Duration;
+
+#[async_trait]
+pub trait SerdeRequestResponse {
+    async fn serde_request_response<Req, Resp, F>(
+        protocol: SerdeProtocol,
+        codec: SerdeCodec<Req, Resp, F>,
+        request_timeout: Duration,
+    ) -> Behaviour<SerdeCodec<Req, Resp, F>>
+    where
+        Req: Send + 'static,
+        Resp: Send + 'static,
+        F: Send + 'static,
+        SerdeCodec<Req, Resp, F>: Codec<Protocol = SerdeProtocol>,
+    {
+        let mut cfg = Config::default();
+        cfg.set_request_timeout(request_timeout);
+        Behaviour::new(codec, iter::once((protocol, ProtocolSupport::Full)), cfg)
+    }
+}
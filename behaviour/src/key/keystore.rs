@@ -0,0 +1,218 @@
+//! Passphrase protected key files.
+//!
+//! A keystore is a JSON document holding the protobuf encoded keypair, encrypted with
+//! XChaCha20-Poly1305 under a key derived from the passphrase with scrypt.
+
+use super::KeyFormat;
+use anyhow::{anyhow, bail, Context};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use clap::Args;
+use libp2p::identity::Keypair;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+const VERSION: u32 = 1;
+const KDF: &str = "scrypt";
+const CIPHER: &str = "xchacha20poly1305";
+/// Upper bounds on the scrypt parameters accepted from a file, 2^20 with `r = 8` needs 1 GiB of
+/// memory and `p` multiplies the time spent.
+const MAX_LOG_N: u8 = 20;
+const MAX_R: u32 = 8;
+const MAX_P: u32 = 4;
+
+/// Environment variable checked for the passphrase when no file is given.
+pub const PASSPHRASE_ENV: &str = "P2P_KEY_PASSPHRASE";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Keystore {
+    version: u32,
+    kdf: String,
+    kdf_params: ScryptParams,
+    cipher: String,
+    /// Hex encoded.
+    nonce: String,
+    /// Hex encoded.
+    ciphertext: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ScryptParams {
+    log_n: u8,
+    r: u32,
+    p: u32,
+    /// Hex encoded.
+    salt: String,
+}
+
+impl ScryptParams {
+    fn derive_key(&self, passphrase: &str) -> anyhow::Result<[u8; 32]> {
+        if self.log_n > MAX_LOG_N {
+            bail!(
+                "scrypt cost 2^{} exceeds the maximum of 2^{MAX_LOG_N}",
+                self.log_n
+            );
+        }
+        if self.r > MAX_R || self.p > MAX_P {
+            bail!(
+                "scrypt parameters r = {}, p = {} exceed the maximum of r = {MAX_R}, p = {MAX_P}",
+                self.r,
+                self.p
+            );
+        }
+        let params = scrypt::Params::new(self.log_n, self.r, self.p, 32)
+            .map_err(|e| anyhow!("invalid scrypt parameters: {e}"))?;
+        let salt = hex::decode(&self.salt).context("invalid salt")?;
+        let mut key = [0u8; 32];
+        scrypt::scrypt(passphrase.as_bytes(), &salt, &params, &mut key)
+            .map_err(|e| anyhow!("scrypt failed: {e}"))?;
+        Ok(key)
+    }
+}
+
+/// Whether `data` looks like a keystore rather than a plain key encoding.
+pub fn is_keystore(data: &[u8]) -> bool {
+    data.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{')
+}
+
+pub fn encrypt(keypair: &Keypair, passphrase: &str) -> anyhow::Result<Vec<u8>> {
+    encrypt_with_cost(keypair, passphrase, scrypt::Params::RECOMMENDED_LOG_N)
+}
+
+fn encrypt_with_cost(keypair: &Keypair, passphrase: &str, log_n: u8) -> anyhow::Result<Vec<u8>> {
+    let kdf_params = ScryptParams {
+        log_n,
+        r: scrypt::Params::RECOMMENDED_R,
+        p: scrypt::Params::RECOMMENDED_P,
+        salt: hex::encode(rand::random::<[u8; 32]>()),
+    };
+    let key = kdf_params.derive_key(passphrase)?;
+    let nonce: [u8; 24] = rand::random();
+    let ciphertext = XChaCha20Poly1305::new(&key.into())
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            super::encode(keypair, KeyFormat::Protobuf)?.as_slice(),
+        )
+        .map_err(|_| anyhow!("encrypting key failed"))?;
+    let keystore = Keystore {
+        version: VERSION,
+        kdf: KDF.to_string(),
+        kdf_params,
+        cipher: CIPHER.to_string(),
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    };
+    Ok(serde_json::to_vec_pretty(&keystore)?)
+}
+
+pub fn decrypt(data: &[u8], passphrase: &str) -> anyhow::Result<Keypair> {
+    let keystore: Keystore = serde_json::from_slice(data).context("invalid keystore")?;
+    if keystore.version != VERSION || keystore.kdf != KDF || keystore.cipher != CIPHER {
+        bail!(
+            "unsupported keystore version {} ({}, {})",
+            keystore.version,
+            keystore.kdf,
+            keystore.cipher
+        );
+    }
+    let key = keystore.kdf_params.derive_key(passphrase)?;
+    let nonce = hex::decode(&keystore.nonce).context("invalid nonce")?;
+    if nonce.len() != 24 {
+        bail!("invalid nonce length {}", nonce.len());
+    }
+    let ciphertext = hex::decode(&keystore.ciphertext).context("invalid ciphertext")?;
+    let plaintext = XChaCha20Poly1305::new(&key.into())
+        .decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| anyhow!("wrong passphrase or corrupted keystore"))?;
+    super::decode(&plaintext, KeyFormat::Protobuf)
+}
+
+/// Where the keystore passphrase comes from.
+///
+/// A passphrase file wins over the environment variable, and without either the passphrase is
+/// prompted for on the terminal.
+#[derive(Debug, Clone, Args)]
+pub struct PassphraseOpt {
+    /// File holding the key file passphrase, a trailing newline is ignored
+    #[clap(long)]
+    pub passphrase_file: Option<PathBuf>,
+
+    /// Environment variable holding the key file passphrase
+    #[clap(long, default_value = PASSPHRASE_ENV)]
+    pub passphrase_env: String,
+}
+
+impl PassphraseOpt {
+    /// Reads the passphrase, asking twice when prompting for a new one.
+    pub fn read(&self, confirm: bool) -> anyhow::Result<String> {
+        if let Some(path) = &self.passphrase_file {
+            let passphrase = fs::read_to_string(path)
+                .with_context(|| format!("reading passphrase file {}", path.display()))?;
+            return Ok(passphrase.trim_end_matches(['\r', '\n']).to_string());
+        }
+        if let Ok(passphrase) = std::env::var(&self.passphrase_env) {
+            return Ok(passphrase);
+        }
+        let passphrase = rpassword::prompt_password("Key passphrase: ")?;
+        if confirm && passphrase != rpassword::prompt_password("Repeat passphrase: ")? {
+            bail!("passphrases do not match");
+        }
+        Ok(passphrase)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap enough for unoptimized test builds.
+    const TEST_LOG_N: u8 = 4;
+
+    fn keystore(keypair: &Keypair) -> Keystore {
+        let data = encrypt_with_cost(keypair, "secret", TEST_LOG_N).unwrap();
+        assert!(is_keystore(&data));
+        serde_json::from_slice(&data).unwrap()
+    }
+
+    #[test]
+    fn decrypts_with_the_passphrase() {
+        let keypair = Keypair::generate_ed25519();
+        let data = serde_json::to_vec(&keystore(&keypair)).unwrap();
+        let decrypted = decrypt(&data, "secret").unwrap();
+        assert_eq!(decrypted.public(), keypair.public());
+    }
+
+    #[test]
+    fn wrong_passphrase_fails() {
+        let data = serde_json::to_vec(&keystore(&Keypair::generate_ed25519())).unwrap();
+        assert!(decrypt(&data, "not the secret").is_err());
+    }
+
+    #[test]
+    fn tampered_ciphertext_fails() {
+        let mut keystore = keystore(&Keypair::generate_ed25519());
+        let mut ciphertext = hex::decode(&keystore.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        keystore.ciphertext = hex::encode(ciphertext);
+        let data = serde_json::to_vec(&keystore).unwrap();
+        assert!(decrypt(&data, "secret").is_err());
+    }
+
+    #[test]
+    fn excessive_scrypt_parameters_are_rejected() {
+        for (log_n, r, p) in [
+            (MAX_LOG_N + 1, 8, 1),
+            (TEST_LOG_N, MAX_R + 1, 1),
+            (TEST_LOG_N, 8, MAX_P + 1),
+        ] {
+            let mut keystore = keystore(&Keypair::generate_ed25519());
+            keystore.kdf_params.log_n = log_n;
+            keystore.kdf_params.r = r;
+            keystore.kdf_params.p = p;
+            let data = serde_json::to_vec(&keystore).unwrap();
+            let error = decrypt(&data, "secret").unwrap_err();
+            assert!(error.to_string().contains("exceed"), "{error}");
+        }
+    }
+}
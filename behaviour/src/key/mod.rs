@@ -0,0 +1,326 @@
+pub mod keystore;
+
+use anyhow::{anyhow, bail, Context};
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{Args, ValueEnum};
+use keystore::PassphraseOpt;
+use libp2p::identity::{self, Keypair};
+use pkcs8::der::asn1::OctetStringRef;
+use pkcs8::der::pem::LineEnding;
+use pkcs8::der::{Decode, Encode, EncodePem};
+use pkcs8::{AlgorithmIdentifierRef, ObjectIdentifier, PrivateKeyInfo, SecretDocument};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// RFC 8410 object identifier of ed25519 keys.
+const ED25519_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+
+/// Protobuf header of a secp256k1 private key: type `Secp256k1`, followed by 32 data bytes.
+const SECP256K1_PROTOBUF_PREFIX: [u8; 4] = [0x08, 0x02, 0x12, 0x20];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum KeyType {
+    Ed25519,
+    Secp256k1,
+}
+
+/// On-disk encodings of a keypair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum KeyFormat {
+    /// libp2p protobuf encoding.
+    Protobuf,
+    /// PKCS#8 DER, ed25519 only.
+    Der,
+    /// PKCS#8 PEM, ed25519 only.
+    Pem,
+    /// The bare secret key bytes, which do not record the key type. Never detected, a raw key
+    /// is only read when named together with its type.
+    Raw,
+}
+
+impl KeyFormat {
+    /// Guesses the format of an encoded key from its first bytes.
+    pub fn detect(data: &[u8]) -> KeyFormat {
+        if data.starts_with(b"-----BEGIN") {
+            KeyFormat::Pem
+        } else if data.first() == Some(&0x30) {
+            // Every DER document starts with a SEQUENCE tag.
+            KeyFormat::Der
+        } else {
+            KeyFormat::Protobuf
+        }
+    }
+
+    /// Parses the formats [`detect`](Self::detect) recognizes, for key files that are read back
+    /// without naming their format.
+    pub fn detectable_parser() -> impl TypedValueParser<Value = KeyFormat> {
+        PossibleValuesParser::new(["protobuf", "der", "pem"])
+            .map(|format| KeyFormat::from_str(&format, false).expect("listed formats parse"))
+    }
+}
+
+pub fn generate(key_type: KeyType) -> Keypair {
+    match key_type {
+        KeyType::Ed25519 => Keypair::generate_ed25519(),
+        KeyType::Secp256k1 => Keypair::generate_secp256k1(),
+    }
+}
+
+/// The type of `keypair`, `None` for key types this module cannot store.
+pub fn key_type(keypair: &Keypair) -> Option<KeyType> {
+    let public = keypair.public();
+    if public.clone().try_into_ed25519().is_ok() {
+        Some(KeyType::Ed25519)
+    } else if public.try_into_secp256k1().is_ok() {
+        Some(KeyType::Secp256k1)
+    } else {
+        None
+    }
+}
+
+pub fn encode(keypair: &Keypair, format: KeyFormat) -> anyhow::Result<Vec<u8>> {
+    match format {
+        KeyFormat::Protobuf => match keypair.clone().try_into_secp256k1() {
+            // libp2p-identity only encodes ed25519 keys itself.
+            Ok(keypair) => {
+                Ok([&SECP256K1_PROTOBUF_PREFIX[..], &keypair.secret().to_bytes()].concat())
+            }
+            Err(_) => Ok(keypair.to_protobuf_encoding()?),
+        },
+        KeyFormat::Der | KeyFormat::Pem => {
+            let secret = keypair
+                .clone()
+                .try_into_ed25519()
+                .map_err(|_| anyhow!("PKCS#8 is only supported for ed25519 keys"))?
+                .secret();
+            // The PKCS#8 private key of ed25519 is the seed wrapped in an OCTET STRING.
+            let private_key = OctetStringRef::new(secret.as_ref())?.to_der()?;
+            let algorithm = AlgorithmIdentifierRef {
+                oid: ED25519_OID,
+                parameters: None,
+            };
+            let info = PrivateKeyInfo::new(algorithm, &private_key);
+            Ok(match format {
+                KeyFormat::Pem => info.to_pem(LineEnding::LF)?.into_bytes(),
+                _ => info.to_der()?,
+            })
+        }
+        KeyFormat::Raw => match key_type(keypair) {
+            Some(KeyType::Ed25519) => {
+                let keypair = keypair.clone().try_into_ed25519()?;
+                Ok(keypair.secret().as_ref().to_vec())
+            }
+            Some(KeyType::Secp256k1) => {
+                let keypair = keypair.clone().try_into_secp256k1()?;
+                Ok(keypair.secret().to_bytes().to_vec())
+            }
+            None => bail!("unsupported key type"),
+        },
+    }
+}
+
+/// Decodes a key in `format`. Raw keys are taken to be ed25519, see [`decode_raw`] for others.
+pub fn decode(data: &[u8], format: KeyFormat) -> anyhow::Result<Keypair> {
+    match format {
+        KeyFormat::Protobuf => match data.strip_prefix(&SECP256K1_PROTOBUF_PREFIX[..]) {
+            Some(secret) => decode_raw(secret, KeyType::Secp256k1),
+            None => Ok(Keypair::from_protobuf_encoding(data)?),
+        },
+        KeyFormat::Der => decode_pkcs8(data),
+        KeyFormat::Pem => {
+            let pem = std::str::from_utf8(data).context("PEM key is not valid UTF-8")?;
+            let (_, document) = SecretDocument::from_pem(pem)?;
+            decode_pkcs8(document.as_bytes())
+        }
+        KeyFormat::Raw => decode_raw(data, KeyType::Ed25519),
+    }
+}
+
+pub fn decode_raw(data: &[u8], key_type: KeyType) -> anyhow::Result<Keypair> {
+    Ok(match key_type {
+        KeyType::Ed25519 => Keypair::ed25519_from_bytes(data.to_vec())?,
+        KeyType::Secp256k1 => {
+            let secret = identity::secp256k1::SecretKey::try_from_bytes(data.to_vec())?;
+            identity::secp256k1::Keypair::from(secret).into()
+        }
+    })
+}
+
+fn decode_pkcs8(der: &[u8]) -> anyhow::Result<Keypair> {
+    let info = PrivateKeyInfo::try_from(der)?;
+    if info.algorithm.oid != ED25519_OID {
+        bail!("unsupported PKCS#8 key algorithm {}", info.algorithm.oid);
+    }
+    let seed = OctetStringRef::from_der(info.private_key)?;
+    Ok(Keypair::ed25519_from_bytes(seed.as_bytes().to_vec())?)
+}
+
+/// Writes `keypair` to `path`, readable by the owner only.
+pub fn save(path: &Path, keypair: &Keypair, format: KeyFormat) -> anyhow::Result<()> {
+    write_private(path, &encode(keypair, format)?)
+}
+
+/// Writes `keypair` to `path` as a keystore encrypted with `passphrase`.
+pub fn save_encrypted(path: &Path, keypair: &Keypair, passphrase: &str) -> anyhow::Result<()> {
+    write_private(path, &keystore::encrypt(keypair, passphrase)?)
+}
+
+fn write_private(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("creating key file {}", path.display()))?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+/// Loads the key at `path`, asking `passphrase` for the passphrase if it is a keystore.
+pub fn load(path: &Path, passphrase: &PassphraseOpt) -> anyhow::Result<Keypair> {
+    let data = fs::read(path).with_context(|| format!("reading key file {}", path.display()))?;
+    if keystore::is_keystore(&data) {
+        return keystore::decrypt(&data, &passphrase.read(false)?)
+            .with_context(|| format!("unlocking key file {}", path.display()));
+    }
+    decode(&data, KeyFormat::detect(&data))
+        .with_context(|| format!("decoding key file {}", path.display()))
+}
+
+/// Derives a keypair from a single byte. Only 256 such keys exist and anyone can recompute
+/// them, so this is for local development only.
+pub fn insecure_dev_seed(seed: u8) -> Keypair {
+    let mut bytes = [0u8; 32];
+    bytes[0] = seed;
+
+    identity::Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
+}
+
+/// Identity options shared by the binaries.
+#[derive(Debug, Clone, Args)]
+pub struct KeyOpt {
+    /// Identity key file, generated on first start when missing
+    #[clap(long, required_unless_present = "insecure_dev_seed")]
+    pub key_file: Option<PathBuf>,
+
+    /// Type of the key generated for a missing key file
+    #[clap(long, value_enum, default_value_t = KeyType::Ed25519)]
+    pub key_type: KeyType,
+
+    /// Encoding of the key generated for a missing key file
+    #[clap(long, default_value = "protobuf", value_parser = KeyFormat::detectable_parser())]
+    pub key_format: KeyFormat,
+
+    /// Encrypt the key generated for a missing key file with a passphrase
+    #[clap(long, conflicts_with = "key_format")]
+    pub encrypt: bool,
+
+    #[clap(flatten)]
+    pub passphrase: PassphraseOpt,
+
+    /// Derive the identity from a single byte instead of a key file. Anyone can recompute
+    /// these keys, use for local development only
+    #[clap(long, conflicts_with = "key_file")]
+    pub insecure_dev_seed: Option<u8>,
+}
+
+impl KeyOpt {
+    /// Loads the configured identity, generating and saving a new key on first use.
+    pub fn keypair(&self) -> anyhow::Result<Keypair> {
+        match (&self.key_file, self.insecure_dev_seed) {
+            (Some(path), _) if path.exists() => load(path, &self.passphrase),
+            (Some(path), _) => {
+                let keypair = generate(self.key_type);
+                if self.encrypt {
+                    save_encrypted(path, &keypair, &self.passphrase.read(true)?)?;
+                } else {
+                    save(path, &keypair, self.key_format)?;
+                }
+                tracing::info!(
+                    "generated new {:?} key in {}",
+                    self.key_type,
+                    path.display()
+                );
+                Ok(keypair)
+            }
+            (None, Some(seed)) => {
+                tracing::warn!("using insecure development identity derived from seed {seed}");
+                Ok(insecure_dev_seed(seed))
+            }
+            (None, None) => bail!("either --key-file or --insecure-dev-seed is required"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_TYPES: [KeyType; 2] = [KeyType::Ed25519, KeyType::Secp256k1];
+
+    fn round_trip(key_type: KeyType, format: KeyFormat) -> anyhow::Result<()> {
+        let keypair = generate(key_type);
+        let data = encode(&keypair, format)?;
+        let decoded = match format {
+            KeyFormat::Raw => decode_raw(&data, key_type)?,
+            _ => {
+                assert_eq!(KeyFormat::detect(&data), format);
+                decode(&data, format)?
+            }
+        };
+        assert_eq!(decoded.public(), keypair.public());
+        assert_eq!(super::key_type(&decoded), Some(key_type));
+        Ok(())
+    }
+
+    #[test]
+    fn every_key_type_round_trips() {
+        for key_type in KEY_TYPES {
+            round_trip(key_type, KeyFormat::Protobuf).unwrap();
+            round_trip(key_type, KeyFormat::Raw).unwrap();
+        }
+    }
+
+    #[test]
+    fn pkcs8_round_trips_ed25519_only() {
+        for format in [KeyFormat::Der, KeyFormat::Pem] {
+            round_trip(KeyType::Ed25519, format).unwrap();
+            assert!(round_trip(KeyType::Secp256k1, format).is_err());
+        }
+    }
+
+    #[test]
+    fn raw_keys_read_as_another_format_are_rejected() {
+        // A bare secret may start with the markers of the formats that are detected, it is then
+        // taken for that format and fails to decode rather than loading some other key.
+        for (marker, format) in [
+            (&b"-----BEGIN"[..], KeyFormat::Pem),
+            (&[0x30][..], KeyFormat::Der),
+            (&[0x08, 0x01][..], KeyFormat::Protobuf),
+        ] {
+            let mut secret = [7u8; 32];
+            secret[..marker.len()].copy_from_slice(marker);
+            assert_eq!(KeyFormat::detect(&secret), format);
+            assert!(decode(&secret, format).is_err(), "{format:?}");
+        }
+    }
+
+    #[test]
+    fn detectable_formats_exclude_raw() {
+        let parser = KeyFormat::detectable_parser();
+        let command = clap::Command::new("test");
+        for format in KeyFormat::value_variants() {
+            let name = format.to_possible_value().unwrap().get_name().to_string();
+            let parsed = parser.parse_ref(&command, None, name.as_ref());
+            match format {
+                KeyFormat::Raw => assert!(parsed.is_err()),
+                _ => assert_eq!(parsed.unwrap(), *format),
+            }
+        }
+    }
+}
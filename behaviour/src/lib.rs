@@ -1,3 +1,15 @@
+pub mod address;
+pub mod admin;
+pub mod audit;
+pub mod bandwidth;
 pub mod behaviour;
 pub mod behaviour_trait;
+pub mod key;
+pub mod logging;
+pub mod metrics;
+pub mod migrate;
+pub mod node;
+pub mod output;
+pub mod relay_acl;
+pub mod relay_config;
 pub mod select_next;
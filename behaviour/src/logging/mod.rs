@@ -0,0 +1,72 @@
+use anyhow::Context;
+use clap::{Args, ValueEnum};
+use std::fs::OpenOptions;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+
+/// Filter used when neither `--log-filter` nor `RUST_LOG` is set.
+const DEFAULT_FILTER: &str = "info";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, including the fields of every enclosing span.
+    Json,
+}
+
+/// Logging options shared by the binaries.
+#[derive(Debug, Clone, Args)]
+pub struct LogOpt {
+    /// Level filter in `RUST_LOG` syntax, e.g. `info,behaviour=debug`. Falls back to `RUST_LOG`,
+    /// then to `info`
+    #[clap(long)]
+    pub log_filter: Option<String>,
+
+    #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Append logs to this file instead of writing them to stderr
+    #[clap(long)]
+    pub log_file: Option<PathBuf>,
+}
+
+impl LogOpt {
+    /// Installs the global `tracing` subscriber. Records of crates using `log`, such as libp2p,
+    /// are forwarded to it as well.
+    pub fn init(&self) -> anyhow::Result<()> {
+        let filter = match &self.log_filter {
+            Some(filter) => EnvFilter::try_new(filter)
+                .with_context(|| format!("invalid log filter {filter}"))?,
+            None => {
+                EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER))
+            }
+        };
+        let writer = match &self.log_file {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("opening log file {}", path.display()))?;
+                BoxMakeWriter::new(Mutex::new(file))
+            }
+            None => BoxMakeWriter::new(io::stderr),
+        };
+        let builder = tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_writer(writer)
+            .with_ansi(self.log_file.is_none());
+        match self.log_format {
+            LogFormat::Text => builder.try_init(),
+            LogFormat::Json => builder
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+                .try_init(),
+        }
+        .map_err(anyhow::Error::msg)
+    }
+}
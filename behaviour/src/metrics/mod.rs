@@ -0,0 +1,98 @@
+use crate::bandwidth::Bandwidth;
+use crate::node::RelayMetrics;
+use anyhow::Context;
+use clap::Args;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use libp2p::metrics::Metrics;
+use prometheus_client::encoding::text::encode;
+use prometheus_client::registry::Registry;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+const METRICS_PATH: &str = "/metrics";
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Metrics options shared by the binaries.
+#[derive(Debug, Clone, Args)]
+pub struct MetricsOpt {
+    /// Serve OpenMetrics text at `http://<address>/metrics`, e.g. `127.0.0.1:9090`
+    #[clap(long)]
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+/// Everything a [`Node`](crate::node::Node) records.
+pub struct NodeMetrics {
+    pub libp2p: Metrics,
+    pub relay: RelayMetrics,
+}
+
+impl NodeMetrics {
+    pub fn new(registry: &mut Registry) -> Self {
+        Self {
+            libp2p: Metrics::new(registry),
+            relay: RelayMetrics::new(registry),
+        }
+    }
+}
+
+impl MetricsOpt {
+    /// Registers the node metrics and `bandwidth` and starts serving them, `None` without
+    /// `--metrics-addr`.
+    pub fn start(&self, bandwidth: &Bandwidth) -> anyhow::Result<Option<NodeMetrics>> {
+        let Some(address) = self.metrics_addr else {
+            return Ok(None);
+        };
+        let mut registry = Registry::default();
+        let metrics = NodeMetrics::new(&mut registry);
+        bandwidth.register(&mut registry);
+        serve(address, registry)?;
+        Ok(Some(metrics))
+    }
+}
+
+/// Serves `registry` on `address` from a background task. Binding happens right away, so a
+/// taken port is reported here rather than from the task.
+pub fn serve(address: SocketAddr, registry: Registry) -> anyhow::Result<()> {
+    let registry = Arc::new(registry);
+    let server = Server::try_bind(&address)
+        .with_context(|| format!("binding metrics endpoint to {address}"))?
+        .serve(make_service_fn(move |_| {
+            let registry = registry.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let registry = registry.clone();
+                    async move { Ok::<_, Infallible>(respond(&request, &registry)) }
+                }))
+            }
+        }));
+    tracing::info!("serving metrics on http://{address}{METRICS_PATH}");
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            tracing::warn!("metrics endpoint failed: {e}");
+        }
+    });
+    Ok(())
+}
+
+fn respond(request: &Request<Body>, registry: &Registry) -> Response<Body> {
+    if request.method() != Method::GET || request.uri().path() != METRICS_PATH {
+        return status(StatusCode::NOT_FOUND);
+    }
+    let mut body = String::new();
+    if let Err(e) = encode(&mut body, registry) {
+        tracing::warn!("encoding metrics failed: {e}");
+        return status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Response::builder()
+        .header(header::CONTENT_TYPE, OPENMETRICS_CONTENT_TYPE)
+        .body(Body::from(body))
+        .expect("static response parts are valid")
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,259 @@
+//! Moves the traffic of a peer off its relayed connections once DCUtR upgraded it to a direct
+//! one.
+//!
+//! Protocols such as chat spread their requests over every connection to a peer, so the
+//! relayed connections are closed once a direct one exists. Closing waits until the requests
+//! under way with the peer are done, as those on a relayed connection would fail with it, and
+//! so does a peer with requests going on all the time. Should the direct connection go away
+//! again, the peer is reached through its circuit addresses as before.
+
+use libp2p::core::{ConnectedPoint, Endpoint};
+use libp2p::swarm::behaviour::{ConnectionClosed, ConnectionEstablished};
+use libp2p::swarm::{
+    dummy, CloseConnection, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour,
+    PollParameters, THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+};
+use libp2p::{Multiaddr, PeerId};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::task::{Context, Poll, Waker};
+use void::Void;
+
+#[derive(Debug, Default)]
+pub struct Behaviour {
+    relayed: HashMap<PeerId, HashSet<ConnectionId>>,
+    direct: HashMap<PeerId, HashSet<ConnectionId>>,
+    /// Peers whose relayed connections were closed, or are about to be, in favour of a
+    /// direct one.
+    migrated: HashSet<PeerId>,
+    /// Migrated peers whose relayed connections wait for their requests to be done.
+    draining: HashSet<PeerId>,
+    /// Requests under way with each peer.
+    in_flight: HashMap<PeerId, usize>,
+    to_close: VecDeque<(PeerId, ConnectionId)>,
+    waker: Option<Waker>,
+}
+
+impl Behaviour {
+    /// Closes the relayed connections to `peer` if there is a direct one, as soon as no
+    /// request is under way with it, returning whether traffic moves to the direct connection.
+    pub fn migrate(&mut self, peer: &PeerId) -> bool {
+        if !self.direct.contains_key(peer) {
+            return false;
+        }
+        self.migrated.insert(*peer);
+        self.draining.insert(*peer);
+        self.close_drained(peer);
+        true
+    }
+
+    /// A request to or from `peer` went out or came in.
+    pub fn on_request_started(&mut self, peer: &PeerId) {
+        *self.in_flight.entry(*peer).or_default() += 1;
+    }
+
+    /// A request to or from `peer` got its response or failed.
+    pub fn on_request_finished(&mut self, peer: &PeerId) {
+        if let Some(count) = self.in_flight.get_mut(peer) {
+            *count -= 1;
+            if *count == 0 {
+                self.in_flight.remove(peer);
+            }
+        }
+        self.close_drained(peer);
+    }
+
+    /// Whether the traffic of `peer` moved to a direct connection that is still open.
+    pub fn is_migrated(&self, peer: &PeerId) -> bool {
+        self.migrated.contains(peer)
+    }
+
+    fn close_drained(&mut self, peer: &PeerId) {
+        if self.in_flight.contains_key(peer) || !self.draining.remove(peer) {
+            return;
+        }
+        for connection in self.relayed.get(peer).into_iter().flatten() {
+            self.to_close.push_back((*peer, *connection));
+        }
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn on_established(
+        &mut self,
+        peer: PeerId,
+        connection: ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        let connections = if endpoint.is_relayed() {
+            &mut self.relayed
+        } else {
+            &mut self.direct
+        };
+        connections.entry(peer).or_default().insert(connection);
+    }
+
+    fn on_closed(&mut self, peer: PeerId, connection: ConnectionId) {
+        for connections in [&mut self.relayed, &mut self.direct] {
+            if let Some(ids) = connections.get_mut(&peer) {
+                ids.remove(&connection);
+                if ids.is_empty() {
+                    connections.remove(&peer);
+                }
+            }
+        }
+        if !self.direct.contains_key(&peer) {
+            self.migrated.remove(&peer);
+            self.draining.remove(&peer);
+        }
+    }
+}
+
+impl NetworkBehaviour for Behaviour {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type OutEvent = Void;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
+        match event {
+            FromSwarm::ConnectionEstablished(ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+                ..
+            }) => self.on_established(peer_id, connection_id, endpoint),
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id,
+                connection_id,
+                ..
+            }) => self.on_closed(peer_id, connection_id),
+            _ => {}
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        void::unreachable(event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<ToSwarm<Self::OutEvent, THandlerInEvent<Self>>> {
+        match self.to_close.pop_front() {
+            Some((peer_id, connection)) => Poll::Ready(ToSwarm::CloseConnection {
+                peer_id,
+                connection: CloseConnection::One(connection),
+            }),
+            None => {
+                self.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dialer(address: &str) -> ConnectedPoint {
+        ConnectedPoint::Dialer {
+            address: address.parse().unwrap(),
+            role_override: Endpoint::Dialer,
+        }
+    }
+
+    fn connected(peer: PeerId) -> Behaviour {
+        let mut behaviour = Behaviour::default();
+        behaviour.on_established(
+            peer,
+            ConnectionId::new_unchecked(1),
+            &dialer("/ip4/192.0.2.1/tcp/4001/p2p-circuit"),
+        );
+        behaviour.on_established(
+            peer,
+            ConnectionId::new_unchecked(2),
+            &dialer("/ip4/198.51.100.1/tcp/4001"),
+        );
+        behaviour
+    }
+
+    fn closing(behaviour: &mut Behaviour) -> Vec<ConnectionId> {
+        behaviour
+            .to_close
+            .drain(..)
+            .map(|(_, connection)| connection)
+            .collect()
+    }
+
+    #[test]
+    fn needs_a_direct_connection() {
+        let peer = PeerId::random();
+        let mut behaviour = connected(peer);
+        behaviour.on_closed(peer, ConnectionId::new_unchecked(2));
+        assert!(!behaviour.migrate(&peer));
+        assert!(!behaviour.is_migrated(&peer));
+        assert!(closing(&mut behaviour).is_empty());
+    }
+
+    #[test]
+    fn closes_relayed_connections_once_requests_are_done() {
+        let peer = PeerId::random();
+        let mut behaviour = connected(peer);
+        behaviour.on_request_started(&peer);
+        behaviour.on_request_started(&peer);
+        assert!(behaviour.migrate(&peer));
+        assert!(behaviour.is_migrated(&peer));
+        assert!(closing(&mut behaviour).is_empty());
+
+        behaviour.on_request_finished(&peer);
+        assert!(closing(&mut behaviour).is_empty());
+        behaviour.on_request_finished(&peer);
+        assert_eq!(
+            closing(&mut behaviour),
+            vec![ConnectionId::new_unchecked(1)]
+        );
+
+        // Later requests do not close anything again.
+        behaviour.on_request_started(&peer);
+        behaviour.on_request_finished(&peer);
+        assert!(closing(&mut behaviour).is_empty());
+    }
+
+    #[test]
+    fn losing_the_direct_connection_stops_the_migration() {
+        let peer = PeerId::random();
+        let mut behaviour = connected(peer);
+        behaviour.on_request_started(&peer);
+        assert!(behaviour.migrate(&peer));
+        behaviour.on_closed(peer, ConnectionId::new_unchecked(2));
+        assert!(!behaviour.is_migrated(&peer));
+        behaviour.on_request_finished(&peer);
+        assert!(closing(&mut behaviour).is_empty());
+    }
+}
@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+/// Exponential backoff between attempts, doubling from `initial` up to `max`.
+///
+/// Each delay is shortened by a random share of up to `jitter`, so peers that lost the same
+/// relay at once do not all come back at the same moment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    /// Between `0.0`, no jitter, and `1.0`, which lets a delay shrink to nothing.
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(5 * 60),
+            jitter: 0.3,
+        }
+    }
+}
+
+impl Backoff {
+    /// The wait before retrying after `failures` consecutive failures, at least one.
+    pub fn delay(&self, failures: u32) -> Duration {
+        self.delay_with(failures, rand::random())
+    }
+
+    /// [`Backoff::delay`] with `random`, between `0.0` and `1.0`, as the share of the jitter
+    /// taken off.
+    fn delay_with(&self, failures: u32, random: f64) -> Duration {
+        let factor = 1u32
+            .checked_shl(failures.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        let delay = self.initial.saturating_mul(factor).min(self.max);
+        delay.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * random)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_jitter(jitter: f64) -> Backoff {
+        Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            jitter,
+        }
+    }
+
+    #[test]
+    fn doubles_up_to_max() {
+        let backoff = with_jitter(0.0);
+        let delays: Vec<u64> = (1..=8)
+            .map(|failures| backoff.delay(failures).as_secs())
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+        // No failures yet counts as the first.
+        assert_eq!(backoff.delay(0), Duration::from_secs(1));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn jitter_only_shortens() {
+        let backoff = with_jitter(0.5);
+        assert_eq!(backoff.delay_with(3, 0.0), Duration::from_secs(4));
+        assert_eq!(backoff.delay_with(3, 0.5), Duration::from_secs(3));
+        assert_eq!(backoff.delay_with(3, 1.0), Duration::from_secs(2));
+        for _ in 0..100 {
+            let delay = backoff.delay(3);
+            assert!(delay > Duration::from_secs(2) && delay <= Duration::from_secs(4));
+        }
+    }
+
+    #[test]
+    fn full_jitter_can_reach_zero() {
+        let backoff = with_jitter(1.0);
+        assert_eq!(backoff.delay_with(3, 1.0), Duration::ZERO);
+        assert_eq!(backoff.delay_with(3, 0.0), Duration::from_secs(4));
+        // Out of range jitter is clamped.
+        assert_eq!(with_jitter(2.0).delay_with(3, 1.0), Duration::ZERO);
+        assert_eq!(with_jitter(-1.0).delay_with(3, 1.0), Duration::from_secs(4));
+    }
+}
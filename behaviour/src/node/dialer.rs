@@ -0,0 +1,691 @@
+use super::NodeEvent;
+use crate::address;
+use crate::behaviour::Behaviour;
+use libp2p::core::multiaddr::Protocol;
+use libp2p::core::ConnectedPoint;
+use libp2p::kad::QueryId;
+use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
+use libp2p::{Multiaddr, PeerId, Swarm};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, oneshot};
+
+/// How a peer was reached by [`NodeHandle::connect`](super::NodeHandle::connect).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DialPath {
+    Direct,
+    Relayed,
+    /// Through a relay first, then directly once DCUtR punched a hole.
+    HolePunched,
+}
+
+/// Addresses kept of each peer, the latest learned.
+const MAX_ADDRESSES: usize = 16;
+/// Peers whose addresses are kept, those learned about longest ago are dropped first.
+const MAX_PEERS: usize = 1024;
+
+/// How long each step of [`NodeHandle::connect`](super::NodeHandle::connect) may take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DialTimeouts {
+    /// The kademlia lookup of a peer without known addresses.
+    pub lookup: Duration,
+    pub direct: Duration,
+    pub relay: Duration,
+    pub hole_punch: Duration,
+}
+
+impl Default for DialTimeouts {
+    fn default() -> Self {
+        Self {
+            lookup: Duration::from_secs(10),
+            direct: Duration::from_secs(5),
+            relay: Duration::from_secs(10),
+            hole_punch: Duration::from_secs(15),
+        }
+    }
+}
+
+/// Dials peers by id: at their direct addresses first, then through relays, and finally
+/// waits for DCUtR to replace the relayed connection with a direct one. Peers without known
+/// addresses are looked up with kademlia first.
+pub(crate) struct Dialer {
+    timeouts: DialTimeouts,
+    /// Whether DCUtR is enabled, without it relayed connections stay relayed.
+    hole_punching: bool,
+    /// Addresses of other peers learned from identify, kademlia and chat.
+    addresses: HashMap<PeerId, Known>,
+    dials: HashMap<PeerId, Dial>,
+    events: broadcast::Sender<NodeEvent>,
+}
+
+#[derive(Debug)]
+struct Known {
+    /// Oldest first.
+    addresses: VecDeque<Multiaddr>,
+    learned: Instant,
+}
+
+#[derive(Debug)]
+struct Dial {
+    step: Step,
+    deadline: Instant,
+    /// Addresses dialed by this step, without `/p2p/<peer id>`, to tell its failures from
+    /// those of other dials of the peer.
+    dialed: Vec<Multiaddr>,
+    /// Relays to reach the peer through, in case it holds a reservation on one of them.
+    relays: Vec<Multiaddr>,
+    /// The relayed connection, while waiting for DCUtR.
+    relayed_address: Option<Multiaddr>,
+    /// The direct connection DCUtR set up.
+    direct_address: Option<Multiaddr>,
+    /// Why the earlier steps did not reach the peer.
+    failures: Vec<String>,
+    senders: Vec<oneshot::Sender<anyhow::Result<DialPath>>>,
+    /// Senders answered as soon as the peer is reached through a relay, while DCUtR goes on.
+    relayed_senders: Vec<oneshot::Sender<anyhow::Result<DialPath>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Lookup(QueryId),
+    Direct,
+    Relay,
+    HolePunch,
+}
+
+impl Dialer {
+    pub(crate) fn new(hole_punching: bool, events: broadcast::Sender<NodeEvent>) -> Self {
+        Self {
+            timeouts: DialTimeouts::default(),
+            hole_punching,
+            addresses: HashMap::new(),
+            dials: HashMap::new(),
+            events,
+        }
+    }
+
+    pub(crate) fn set_timeouts(&mut self, timeouts: DialTimeouts) {
+        self.timeouts = timeouts;
+    }
+
+    pub(crate) fn learn(&mut self, peer: PeerId, addresses: impl IntoIterator<Item = Multiaddr>) {
+        if self.addresses.len() == MAX_PEERS && !self.addresses.contains_key(&peer) {
+            let oldest = self
+                .addresses
+                .iter()
+                .min_by_key(|(_, known)| known.learned)
+                .map(|(peer, _)| *peer);
+            if let Some(oldest) = oldest {
+                self.addresses.remove(&oldest);
+            }
+        }
+        let known = self.addresses.entry(peer).or_insert_with(|| Known {
+            addresses: VecDeque::new(),
+            learned: Instant::now(),
+        });
+        known.learned = Instant::now();
+        for address in addresses {
+            known.addresses.retain(|known| *known != address);
+            known.addresses.push_back(address);
+            if known.addresses.len() > MAX_ADDRESSES {
+                known.addresses.pop_front();
+            }
+        }
+    }
+
+    /// Drops the addresses learned of `peer`, such as when kademlia found none of them
+    /// routable.
+    pub(crate) fn forget(&mut self, peer: &PeerId) {
+        self.addresses.remove(peer);
+    }
+
+    /// Starts reaching `peer`, or joins the attempt under way. `relays` are the relays the
+    /// node holds reservations on. Unless `hole_punch` is set, `sender` is answered as soon as
+    /// the peer is reached through a relay, and DCUtR goes on without it.
+    pub(crate) fn dial(
+        &mut self,
+        swarm: &mut Swarm<Behaviour>,
+        peer: PeerId,
+        relays: Vec<Multiaddr>,
+        hole_punch: bool,
+        sender: oneshot::Sender<anyhow::Result<DialPath>>,
+    ) {
+        if let Some(dial) = self.dials.get_mut(&peer) {
+            match (hole_punch, dial.step) {
+                (false, Step::HolePunch) => {
+                    let _ = sender.send(Ok(DialPath::Relayed));
+                }
+                (false, _) => dial.relayed_senders.push(sender),
+                (true, _) => dial.senders.push(sender),
+            }
+            return;
+        }
+        let (senders, relayed_senders) = if hole_punch {
+            (vec![sender], Vec::new())
+        } else {
+            (Vec::new(), vec![sender])
+        };
+        let dial = Dial {
+            step: Step::Direct,
+            deadline: Instant::now(),
+            dialed: Vec::new(),
+            relays,
+            relayed_address: None,
+            direct_address: None,
+            failures: Vec::new(),
+            senders,
+            relayed_senders,
+        };
+        self.dials.insert(peer, dial);
+        if self.known(&peer).next().is_none() {
+            self.look_up(swarm, peer);
+        } else {
+            self.dial_direct(swarm, peer);
+        }
+    }
+
+    /// When the step of the next dial runs out of time.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.dials.values().map(|dial| dial.deadline).min()
+    }
+
+    pub(crate) fn check_deadlines(&mut self, swarm: &mut Swarm<Behaviour>) {
+        let now = Instant::now();
+        let expired: Vec<(PeerId, Step)> = self
+            .dials
+            .iter()
+            .filter(|(_, dial)| dial.deadline <= now)
+            .map(|(peer, dial)| (*peer, dial.step))
+            .collect();
+        for (peer, step) in expired {
+            match step {
+                Step::Lookup(_) => {
+                    self.fail_step(&peer, "lookup: timed out".to_string());
+                    self.dial_direct(swarm, peer);
+                }
+                Step::Direct => {
+                    self.fail_step(&peer, "direct: timed out".to_string());
+                    self.dial_relay(swarm, peer);
+                }
+                Step::Relay => {
+                    self.fail_step(&peer, "relay: timed out".to_string());
+                    self.give_up(peer);
+                }
+                Step::HolePunch => {
+                    self.fail_step(&peer, "hole punch: timed out".to_string());
+                    self.reached_relayed(peer);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn on_connected(&mut self, peer: &PeerId, endpoint: &ConnectedPoint) {
+        let Some(dial) = self.dials.get_mut(peer) else {
+            return;
+        };
+        let address = endpoint.get_remote_address().clone();
+        match (dial.step, endpoint.is_relayed()) {
+            // Reached once DCUtR reports the upgrade as done.
+            (Step::HolePunch, false) => dial.direct_address = Some(address),
+            (Step::HolePunch, true) => {}
+            (_, false) => self.reached(*peer, DialPath::Direct, Some(address)),
+            (_, true) if self.hole_punching => {
+                dial.step = Step::HolePunch;
+                dial.deadline = Instant::now() + self.timeouts.hole_punch;
+                dial.relayed_address = Some(address);
+                for sender in dial.relayed_senders.drain(..) {
+                    let _ = sender.send(Ok(DialPath::Relayed));
+                }
+            }
+            (_, true) => self.reached(*peer, DialPath::Relayed, Some(address)),
+        }
+    }
+
+    /// A dial of `peer` failed at `addresses`, which are empty when it failed before any
+    /// address was tried. Only failures at the addresses the current step dialed count, not
+    /// those of other dials of the peer, such as the direct one still running when the relay
+    /// step started. Failures without addresses are left to the step's timeout.
+    pub(crate) fn on_dial_failed(
+        &mut self,
+        swarm: &mut Swarm<Behaviour>,
+        peer: &PeerId,
+        addresses: &[Multiaddr],
+        error: &str,
+    ) {
+        let Some(dial) = self.dials.get(peer) else {
+            return;
+        };
+        let own = !addresses.is_empty()
+            && addresses
+                .iter()
+                .all(|address| dial.dialed.contains(&without_peer(address)));
+        match dial.step {
+            Step::Direct if own => {
+                self.fail_step(peer, format!("direct: {error}"));
+                self.dial_relay(swarm, *peer);
+            }
+            Step::Relay if own => {
+                self.fail_step(peer, format!("relay: {error}"));
+                self.give_up(*peer);
+            }
+            _ => {}
+        }
+    }
+
+    /// The last connection to `peer` closed. While waiting for DCUtR, that is the relayed
+    /// connection, without which there is nothing left to punch a hole through.
+    pub(crate) fn on_disconnected(&mut self, peer: &PeerId) {
+        if self
+            .dials
+            .get(peer)
+            .is_some_and(|dial| dial.step == Step::HolePunch)
+        {
+            self.fail_step(peer, "hole punch: relayed connection closed".to_string());
+            self.give_up(*peer);
+        }
+    }
+
+    /// The kademlia query `id` finished, which looks a peer up if it is one of ours.
+    pub(crate) fn on_lookup_done(&mut self, swarm: &mut Swarm<Behaviour>, id: QueryId) {
+        let peer = self
+            .dials
+            .iter()
+            .find(|(_, dial)| dial.step == Step::Lookup(id))
+            .map(|(peer, _)| *peer);
+        if let Some(peer) = peer {
+            self.dial_direct(swarm, peer);
+        }
+    }
+
+    pub(crate) fn on_hole_punch(&mut self, peer: &PeerId, result: Result<(), String>) {
+        let Some(dial) = self.dials.get_mut(peer) else {
+            return;
+        };
+        if dial.step != Step::HolePunch {
+            return;
+        }
+        match result {
+            Ok(()) => {
+                let address = dial.direct_address.clone();
+                self.reached(*peer, DialPath::HolePunched, address);
+            }
+            Err(error) => {
+                self.fail_step(peer, format!("hole punch: {error}"));
+                self.reached_relayed(*peer);
+            }
+        }
+    }
+
+    /// Asks kademlia for the peers closest to `peer`, which connects to it if it is found and
+    /// otherwise teaches its addresses to the routing table.
+    fn look_up(&mut self, swarm: &mut Swarm<Behaviour>, peer: PeerId) {
+        match swarm.behaviour_mut().kad.as_mut() {
+            Some(kad) => {
+                let id = kad.get_closest_peers(peer);
+                self.enter(&peer, Step::Lookup(id), self.timeouts.lookup, Vec::new());
+            }
+            None => self.dial_direct(swarm, peer),
+        }
+    }
+
+    fn dial_direct(&mut self, swarm: &mut Swarm<Behaviour>, peer: PeerId) {
+        let addresses: Vec<Multiaddr> = self
+            .known(&peer)
+            .filter(|address| !is_circuit(address))
+            .collect();
+        if addresses.is_empty() {
+            self.fail_step(&peer, "direct: no known addresses".to_string());
+            return self.dial_relay(swarm, peer);
+        }
+        let dialed = addresses.iter().map(without_peer).collect();
+        let opts = DialOpts::peer_id(peer)
+            .condition(PeerCondition::Disconnected)
+            .addresses(addresses)
+            .build();
+        match swarm.dial(opts) {
+            Ok(()) => self.enter(&peer, Step::Direct, self.timeouts.direct, dialed),
+            Err(e) => {
+                self.fail_step(&peer, format!("direct: {e}"));
+                self.dial_relay(swarm, peer);
+            }
+        }
+    }
+
+    fn dial_relay(&mut self, swarm: &mut Swarm<Behaviour>, peer: PeerId) {
+        let Some(dial) = self.dials.get(&peer) else {
+            return;
+        };
+        let mut addresses: Vec<Multiaddr> = dial
+            .relays
+            .iter()
+            .map(|relay| address::circuit(relay, Some(peer)))
+            .collect();
+        for address in self.known(&peer).filter(is_circuit) {
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+        if addresses.is_empty() {
+            self.fail_step(&peer, "relay: no relay known".to_string());
+            return self.give_up(peer);
+        }
+        let dialed = addresses.iter().map(without_peer).collect();
+        // The direct dial may still be running, which must not hold this one back.
+        let opts = DialOpts::peer_id(peer)
+            .condition(PeerCondition::Always)
+            .addresses(addresses)
+            .build();
+        match swarm.dial(opts) {
+            Ok(()) => self.enter(&peer, Step::Relay, self.timeouts.relay, dialed),
+            Err(e) => {
+                self.fail_step(&peer, format!("relay: {e}"));
+                self.give_up(peer);
+            }
+        }
+    }
+
+    fn known(&self, peer: &PeerId) -> impl Iterator<Item = Multiaddr> + '_ {
+        self.addresses
+            .get(peer)
+            .into_iter()
+            .flat_map(|known| known.addresses.iter().cloned())
+    }
+
+    fn enter(&mut self, peer: &PeerId, step: Step, timeout: Duration, dialed: Vec<Multiaddr>) {
+        if let Some(dial) = self.dials.get_mut(peer) {
+            dial.step = step;
+            dial.deadline = Instant::now() + timeout;
+            dial.dialed = dialed;
+        }
+    }
+
+    fn fail_step(&mut self, peer: &PeerId, failure: String) {
+        if let Some(dial) = self.dials.get_mut(peer) {
+            tracing::debug!(%peer, %failure, "dial step failed");
+            dial.failures.push(failure);
+        }
+    }
+
+    fn reached_relayed(&mut self, peer: PeerId) {
+        let address = self
+            .dials
+            .get(&peer)
+            .and_then(|dial| dial.relayed_address.clone());
+        self.reached(peer, DialPath::Relayed, address);
+    }
+
+    fn reached(&mut self, peer: PeerId, path: DialPath, address: Option<Multiaddr>) {
+        let Some(dial) = self.dials.remove(&peer) else {
+            return;
+        };
+        tracing::info!(%peer, ?path, ?address, failures = ?dial.failures, "peer reached");
+        for sender in dial.senders.into_iter().chain(dial.relayed_senders) {
+            let _ = sender.send(Ok(path));
+        }
+        self.emit(NodeEvent::PeerReached {
+            peer_id: peer,
+            path,
+            address,
+            failures: dial.failures,
+        });
+    }
+
+    fn give_up(&mut self, peer: PeerId) {
+        let Some(dial) = self.dials.remove(&peer) else {
+            return;
+        };
+        let reason = dial.failures.join(", ");
+        tracing::info!(%peer, %reason, "peer unreachable");
+        for sender in dial.senders.into_iter().chain(dial.relayed_senders) {
+            let _ = sender.send(Err(anyhow::anyhow!("{peer} is unreachable: {reason}")));
+        }
+        self.emit(NodeEvent::PeerUnreachable {
+            peer_id: peer,
+            failures: dial.failures,
+        });
+    }
+
+    fn emit(&self, event: NodeEvent) {
+        let _ = self.events.send(event);
+    }
+}
+
+/// `address` without a trailing `/p2p/<peer id>`, which the swarm adds to the addresses it
+/// dials.
+fn without_peer(address: &Multiaddr) -> Multiaddr {
+    let mut address = address.clone();
+    if let Some(Protocol::P2p(_)) = address.iter().last() {
+        address.pop();
+    }
+    address
+}
+
+fn is_circuit(address: &Multiaddr) -> bool {
+    address
+        .iter()
+        .any(|protocol| protocol == Protocol::P2pCircuit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::BehaviourConfig;
+    use libp2p::core::Endpoint;
+    use libp2p::identity::Keypair;
+    use tokio::sync::oneshot::error::TryRecvError;
+
+    /// Never polled, so dials only go as far as the test says.
+    async fn swarm() -> Swarm<Behaviour> {
+        let keypair = Keypair::generate_ed25519();
+        Behaviour::new(&keypair, BehaviourConfig::relay_client())
+            .await
+            .unwrap()
+    }
+
+    fn dialer(timeouts: DialTimeouts) -> Dialer {
+        let mut dialer = Dialer::new(true, broadcast::channel(16).0);
+        dialer.set_timeouts(timeouts);
+        dialer
+    }
+
+    fn relay() -> Multiaddr {
+        format!("/ip4/192.0.2.1/tcp/4001/p2p/{}", PeerId::random())
+            .parse()
+            .unwrap()
+    }
+
+    fn direct() -> Multiaddr {
+        "/ip4/198.51.100.1/tcp/4001".parse().unwrap()
+    }
+
+    fn dialed(address: Multiaddr) -> ConnectedPoint {
+        ConnectedPoint::Dialer {
+            address,
+            role_override: Endpoint::Dialer,
+        }
+    }
+
+    fn step(dialer: &Dialer, peer: &PeerId) -> Option<Step> {
+        dialer.dials.get(peer).map(|dial| dial.step)
+    }
+
+    #[tokio::test]
+    async fn looks_up_peers_without_addresses() {
+        let mut swarm = swarm().await;
+        let mut dialer = dialer(DialTimeouts::default());
+        let peer = PeerId::random();
+        let (sender, _receiver) = oneshot::channel();
+        dialer.dial(&mut swarm, peer, vec![relay()], true, sender);
+        let Some(Step::Lookup(id)) = step(&dialer, &peer) else {
+            panic!("not looking up: {:?}", step(&dialer, &peer));
+        };
+
+        dialer.learn(peer, [direct()]);
+        dialer.on_lookup_done(&mut swarm, id);
+        assert_eq!(step(&dialer, &peer), Some(Step::Direct));
+        assert_eq!(dialer.dials[&peer].dialed, vec![direct()]);
+    }
+
+    #[tokio::test]
+    async fn only_failures_of_its_own_dials_count() {
+        let mut swarm = swarm().await;
+        let mut dialer = dialer(DialTimeouts::default());
+        let peer = PeerId::random();
+        let relay = relay();
+        dialer.learn(peer, [direct()]);
+        let (sender, mut receiver) = oneshot::channel();
+        dialer.dial(&mut swarm, peer, vec![relay.clone()], true, sender);
+        assert_eq!(step(&dialer, &peer), Some(Step::Direct));
+
+        let elsewhere: Multiaddr = "/ip4/203.0.113.1/tcp/4001".parse().unwrap();
+        dialer.on_dial_failed(&mut swarm, &peer, &[], "Aborted");
+        dialer.on_dial_failed(&mut swarm, &peer, &[elsewhere], "refused");
+        assert_eq!(step(&dialer, &peer), Some(Step::Direct));
+
+        // The swarm reports the addresses it dialed with the peer id.
+        let own = direct().with(Protocol::P2p(peer.into()));
+        dialer.on_dial_failed(&mut swarm, &peer, std::slice::from_ref(&own), "refused");
+        assert_eq!(step(&dialer, &peer), Some(Step::Relay));
+
+        // A late failure of the direct dial leaves the relay step alone.
+        dialer.on_dial_failed(&mut swarm, &peer, &[own], "refused");
+        dialer.on_dial_failed(&mut swarm, &peer, &[], "Denied");
+        assert_eq!(step(&dialer, &peer), Some(Step::Relay));
+        assert!(matches!(receiver.try_recv(), Err(TryRecvError::Empty)));
+
+        let circuit = address::circuit(&relay, Some(peer));
+        dialer.on_dial_failed(&mut swarm, &peer, &[circuit], "no reservation");
+        assert_eq!(step(&dialer, &peer), None);
+        let error = receiver.try_recv().unwrap().unwrap_err().to_string();
+        assert!(error.contains("direct: refused") && error.contains("relay: no reservation"));
+    }
+
+    #[tokio::test]
+    async fn answers_relayed_senders_before_the_hole_punch() {
+        let mut swarm = swarm().await;
+        let mut dialer = dialer(DialTimeouts::default());
+        let peer = PeerId::random();
+        let relay = relay();
+        let (waiting, mut waiting_receiver) = oneshot::channel();
+        let (eager, mut eager_receiver) = oneshot::channel();
+        dialer.dial(&mut swarm, peer, vec![relay.clone()], true, waiting);
+        dialer.dial(&mut swarm, peer, vec![relay.clone()], false, eager);
+
+        dialer.on_connected(&peer, &dialed(address::circuit(&relay, Some(peer))));
+        assert_eq!(step(&dialer, &peer), Some(Step::HolePunch));
+        assert_eq!(
+            eager_receiver.try_recv().unwrap().unwrap(),
+            DialPath::Relayed
+        );
+        assert!(matches!(
+            waiting_receiver.try_recv(),
+            Err(TryRecvError::Empty)
+        ));
+
+        // Joining during the hole punch is answered right away as well.
+        let (late, mut late_receiver) = oneshot::channel();
+        dialer.dial(&mut swarm, peer, Vec::new(), false, late);
+        assert_eq!(
+            late_receiver.try_recv().unwrap().unwrap(),
+            DialPath::Relayed
+        );
+
+        dialer.on_connected(&peer, &dialed(direct()));
+        dialer.on_hole_punch(&peer, Ok(()));
+        assert_eq!(step(&dialer, &peer), None);
+        assert_eq!(
+            waiting_receiver.try_recv().unwrap().unwrap(),
+            DialPath::HolePunched
+        );
+    }
+
+    #[tokio::test]
+    async fn hole_punches_end_with_what_was_seen() {
+        let mut swarm = swarm().await;
+        let (events, mut receiver) = broadcast::channel(16);
+        let mut dialer = Dialer::new(true, events);
+        let relay = relay();
+        let circuit = address::circuit(&relay, Some(PeerId::random()));
+
+        // DCUtR reported the upgrade before the direct connection was seen.
+        let peer = PeerId::random();
+        let (sender, _) = oneshot::channel();
+        dialer.dial(&mut swarm, peer, vec![relay.clone()], true, sender);
+        dialer.on_connected(&peer, &dialed(circuit.clone()));
+        dialer.on_hole_punch(&peer, Ok(()));
+        match receiver.try_recv() {
+            Ok(NodeEvent::PeerReached { path, address, .. }) => {
+                assert_eq!((path, address), (DialPath::HolePunched, None));
+            }
+            event => panic!("not reached: {event:?}"),
+        }
+
+        // The relayed connection closed under the hole punch.
+        let peer = PeerId::random();
+        let (sender, mut sender_receiver) = oneshot::channel();
+        dialer.dial(&mut swarm, peer, vec![relay], true, sender);
+        dialer.on_connected(&peer, &dialed(circuit));
+        dialer.on_disconnected(&peer);
+        assert_eq!(step(&dialer, &peer), None);
+        let error = sender_receiver.try_recv().unwrap().unwrap_err().to_string();
+        assert!(error.contains("hole punch: relayed connection closed"));
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(NodeEvent::PeerUnreachable { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn timeouts_move_on_to_the_next_step() {
+        let mut swarm = swarm().await;
+        let mut dialer = dialer(DialTimeouts {
+            lookup: Duration::ZERO,
+            direct: Duration::ZERO,
+            relay: Duration::ZERO,
+            hole_punch: Duration::ZERO,
+        });
+        let peer = PeerId::random();
+        let (sender, mut receiver) = oneshot::channel();
+        dialer.dial(&mut swarm, peer, vec![relay()], true, sender);
+        assert!(matches!(step(&dialer, &peer), Some(Step::Lookup(_))));
+        dialer.check_deadlines(&mut swarm);
+        // Nothing was learned, so straight on to the relays.
+        assert_eq!(step(&dialer, &peer), Some(Step::Relay));
+        dialer.check_deadlines(&mut swarm);
+        assert_eq!(step(&dialer, &peer), None);
+        let error = receiver.try_recv().unwrap().unwrap_err().to_string();
+        assert!(error.contains("lookup: timed out") && error.contains("relay: timed out"));
+    }
+
+    #[test]
+    fn keeps_the_latest_addresses() {
+        let mut dialer = dialer(DialTimeouts::default());
+        let peer = PeerId::random();
+        let addresses: Vec<Multiaddr> = (0..MAX_ADDRESSES as u16 + 4)
+            .map(|port| format!("/ip4/198.51.100.1/tcp/{port}").parse().unwrap())
+            .collect();
+        dialer.learn(peer, addresses.clone());
+        // Learning one again makes it the latest.
+        dialer.learn(peer, [addresses[4].clone()]);
+        let known: Vec<Multiaddr> = dialer.known(&peer).collect();
+        assert_eq!(known.len(), MAX_ADDRESSES);
+        assert_eq!(known[..MAX_ADDRESSES - 1], addresses[5..]);
+        assert_eq!(known[MAX_ADDRESSES - 1], addresses[4]);
+
+        dialer.forget(&peer);
+        assert_eq!(dialer.known(&peer).count(), 0);
+    }
+
+    #[test]
+    fn keeps_the_peers_learned_about_last() {
+        let mut dialer = dialer(DialTimeouts::default());
+        let peers: Vec<PeerId> = (0..=MAX_PEERS).map(|_| PeerId::random()).collect();
+        for peer in &peers {
+            dialer.learn(*peer, [direct()]);
+        }
+        assert_eq!(dialer.addresses.len(), MAX_PEERS);
+        assert_eq!(dialer.known(&peers[MAX_PEERS]).count(), 1);
+    }
+}
@@ -0,0 +1,61 @@
+use libp2p::request_response::OutboundFailure;
+use std::fmt;
+
+/// Why an outbound chat request did not produce a response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatError {
+    /// The chat protocol is not enabled on this node.
+    Disabled,
+    /// The request could not be sent because dialing the peer failed.
+    DialFailure,
+    /// The peer did not answer within the request timeout.
+    Timeout,
+    /// The connection closed before a response was received.
+    ConnectionClosed,
+    /// The peer does not speak any of our chat protocols.
+    UnsupportedProtocols,
+    /// The request exceeds the configured maximum request size and was not sent.
+    RequestTooLarge { size: usize, max: usize },
+    /// The peer answered with more than the configured maximum response size.
+    ResponseTooLarge { size: usize, max: usize },
+    /// The node event loop is no longer running.
+    NodeStopped,
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatError::Disabled => write!(f, "chat protocol is disabled"),
+            ChatError::DialFailure => write!(f, "failed to dial the peer"),
+            ChatError::Timeout => write!(f, "timed out waiting for a response"),
+            ChatError::ConnectionClosed => write!(f, "connection closed before a response"),
+            ChatError::UnsupportedProtocols => write!(f, "peer does not support the chat protocol"),
+            ChatError::RequestTooLarge { size, max } => {
+                write!(
+                    f,
+                    "request of {size} bytes exceeds the maximum of {max} bytes"
+                )
+            }
+            ChatError::ResponseTooLarge { size, max } => {
+                write!(
+                    f,
+                    "response of {size} bytes exceeds the maximum of {max} bytes"
+                )
+            }
+            ChatError::NodeStopped => write!(f, "node has stopped"),
+        }
+    }
+}
+
+impl std::error::Error for ChatError {}
+
+impl From<OutboundFailure> for ChatError {
+    fn from(error: OutboundFailure) -> Self {
+        match error {
+            OutboundFailure::DialFailure => ChatError::DialFailure,
+            OutboundFailure::Timeout => ChatError::Timeout,
+            OutboundFailure::ConnectionClosed => ChatError::ConnectionClosed,
+            OutboundFailure::UnsupportedProtocols => ChatError::UnsupportedProtocols,
+        }
+    }
+}
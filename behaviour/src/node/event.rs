@@ -0,0 +1,201 @@
+use super::{DialPath, Health};
+use codec::chat::{ChatRequest, ChatResponse};
+use libp2p::core::ConnectedPoint;
+use libp2p::request_response::RequestId;
+use libp2p::{Multiaddr, PeerId};
+use std::time::Duration;
+
+/// Events published by a running [`Node`](super::Node) to every subscriber.
+///
+/// Unlike the raw swarm events these are cloneable, so errors are carried as their
+/// `Debug` rendering.
+#[derive(Debug, Clone)]
+pub enum NodeEvent {
+    NewListenAddr {
+        address: Multiaddr,
+    },
+    ExpiredListenAddr {
+        address: Multiaddr,
+    },
+    ListenerClosed {
+        addresses: Vec<Multiaddr>,
+        reason: Option<String>,
+    },
+    ListenerError {
+        error: String,
+    },
+    Dialing {
+        peer_id: PeerId,
+    },
+    ConnectionEstablished {
+        peer_id: PeerId,
+        endpoint: ConnectedPoint,
+        num_established: u32,
+    },
+    ConnectionClosed {
+        peer_id: PeerId,
+        endpoint: ConnectedPoint,
+        num_established: u32,
+        cause: Option<String>,
+    },
+    IncomingConnection {
+        local_addr: Multiaddr,
+        send_back_addr: Multiaddr,
+    },
+    IncomingConnectionError {
+        local_addr: Multiaddr,
+        send_back_addr: Multiaddr,
+        error: String,
+    },
+    OutgoingConnectionError {
+        peer_id: Option<PeerId>,
+        error: String,
+    },
+    Ping {
+        peer: PeerId,
+        rtt: Result<Duration, String>,
+    },
+    Identified {
+        peer_id: PeerId,
+        agent_version: String,
+        listen_addrs: Vec<Multiaddr>,
+        protocols: Vec<String>,
+    },
+    KadRoutingUpdated {
+        peer: PeerId,
+        is_new_peer: bool,
+        addresses: Vec<Multiaddr>,
+    },
+    KadRoutablePeer {
+        peer: PeerId,
+        address: Multiaddr,
+    },
+    KadUnroutablePeer {
+        peer: PeerId,
+    },
+    ChatRequest {
+        peer: PeerId,
+        request_id: RequestId,
+        request: ChatRequest,
+    },
+    ChatResponse {
+        peer: PeerId,
+        request_id: RequestId,
+        response: ChatResponse,
+    },
+    ChatResponseSent {
+        peer: PeerId,
+        request_id: RequestId,
+    },
+    ChatOutboundFailure {
+        peer: PeerId,
+        request_id: RequestId,
+        error: String,
+    },
+    ChatInboundFailure {
+        peer: PeerId,
+        request_id: RequestId,
+        error: String,
+    },
+    RelayReservationAccepted {
+        src_peer_id: PeerId,
+        renewed: bool,
+    },
+    RelayReservationDenied {
+        src_peer_id: PeerId,
+        /// Best guess at the limit that was hit, see [`RelayStatus`](super::RelayStatus).
+        reason: &'static str,
+    },
+    RelayReservationTimedOut {
+        src_peer_id: PeerId,
+    },
+    RelayCircuitAccepted {
+        src_peer_id: PeerId,
+        dst_peer_id: PeerId,
+    },
+    RelayCircuitDenied {
+        src_peer_id: PeerId,
+        dst_peer_id: PeerId,
+        reason: &'static str,
+    },
+    RelayCircuitClosed {
+        src_peer_id: PeerId,
+        dst_peer_id: PeerId,
+        error: Option<String>,
+    },
+    /// A peer presented a voucher, accepted until the given unix time or rejected.
+    RelayVoucherReceived {
+        peer: PeerId,
+        expires: Result<u64, String>,
+    },
+    ReservationAccepted {
+        relay_peer_id: PeerId,
+        renewal: bool,
+    },
+    ReservationFailed {
+        relay_peer_id: PeerId,
+        renewal: bool,
+        error: String,
+    },
+    /// The reservation on a relay was lost or could not be made, and is tried again after
+    /// `delay`.
+    ReservationRetry {
+        relay_peer_id: PeerId,
+        attempt: u32,
+        delay: Duration,
+        error: String,
+    },
+    /// The circuit addresses the node is reachable at through all of its relays.
+    CircuitAddressesChanged {
+        addresses: Vec<Multiaddr>,
+    },
+    /// The connection to a relay or pinned peer changed its [`Health`].
+    ConnectionHealth {
+        peer_id: PeerId,
+        health: Health,
+    },
+    /// The connection to a relay or pinned peer was lost or could not be made, and the peer
+    /// is dialed again after `delay`.
+    RedialScheduled {
+        peer_id: PeerId,
+        attempt: u32,
+        delay: Duration,
+        error: String,
+    },
+    /// DCUtR started punching a hole to a peer connected through a relay.
+    HolePunchStarted {
+        peer_id: PeerId,
+        initiated_locally: bool,
+    },
+    /// A direct connection to the peer replaced the relayed one. Traffic moves to it once the
+    /// requests under way are done when `migrated` is set.
+    HolePunchSucceeded {
+        peer_id: PeerId,
+        migrated: bool,
+    },
+    /// Hole punching failed, and traffic stays on the relayed connection.
+    HolePunchFailed {
+        peer_id: PeerId,
+        error: String,
+    },
+    /// [`NodeHandle::connect`](super::NodeHandle::connect) reached the peer along `path`, at
+    /// `address` unless DCUtR reported the hole punch done before the connection was seen,
+    /// after the earlier ways to it failed for `failures`.
+    PeerReached {
+        peer_id: PeerId,
+        path: DialPath,
+        address: Option<Multiaddr>,
+        failures: Vec<String>,
+    },
+    /// [`NodeHandle::connect`](super::NodeHandle::connect) found no way to the peer.
+    PeerUnreachable {
+        peer_id: PeerId,
+        failures: Vec<String>,
+    },
+    OutboundCircuitEstablished {
+        relay_peer_id: PeerId,
+    },
+    InboundCircuitEstablished {
+        src_peer_id: PeerId,
+    },
+}
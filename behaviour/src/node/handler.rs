@@ -0,0 +1,41 @@
+use async_trait::async_trait;
+use codec::chat::{ChatRequest, ChatResponse};
+use libp2p::PeerId;
+
+/// Processes inbound chat requests for a [`Node`](super::Node).
+///
+/// Every request is handled on its own task, so a slow handler does not stall the swarm.
+#[async_trait]
+pub trait ChatHandler: Send + Sync + 'static {
+    async fn handle(&self, peer: PeerId, request: ChatRequest) -> anyhow::Result<ChatResponse>;
+
+    /// Maps a failed [`ChatHandler::handle`] to the response sent back to `peer`.
+    ///
+    /// Returning `None` sends nothing, which the requester observes as a failed request.
+    fn on_error(&self, peer: PeerId, error: anyhow::Error) -> Option<ChatResponse> {
+        tracing::warn!("chat handler failed for {peer}: {error:?}");
+        None
+    }
+}
+
+/// Answers every request with its own payload.
+#[derive(Debug, Clone, Default)]
+pub struct EchoHandler;
+
+#[async_trait]
+impl ChatHandler for EchoHandler {
+    async fn handle(&self, _peer: PeerId, request: ChatRequest) -> anyhow::Result<ChatResponse> {
+        Ok(ChatResponse(request.0.reply(request.data().clone())))
+    }
+}
+
+/// Acknowledges every request with `ok`.
+#[derive(Debug, Clone, Default)]
+pub struct AckHandler;
+
+#[async_trait]
+impl ChatHandler for AckHandler {
+    async fn handle(&self, _peer: PeerId, request: ChatRequest) -> anyhow::Result<ChatResponse> {
+        Ok(ChatResponse(request.0.reply("ok".as_bytes().to_vec())))
+    }
+}
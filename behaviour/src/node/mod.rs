@@ -0,0 +1,1791 @@
+use crate::audit::AuditLog;
+use crate::bandwidth::{Bandwidth, Traffic};
+use crate::behaviour::{Behaviour, BehaviourConfig, Event};
+use crate::metrics::NodeMetrics;
+use crate::relay_config::{LimiterState, RelayConfig};
+use codec::chat::{ChatCodec, ChatReply, ChatRequest, ChatResponse, CHAT_PROTOCOL_V2};
+use codec::frame::MessageTooLarge;
+use codec::voucher::{VoucherRequest, VoucherResponse};
+use futures::stream::{self, FusedStream, StreamExt};
+use libp2p::core::transport::ListenerId;
+use libp2p::core::ConnectedPoint;
+use libp2p::identity::Keypair;
+use libp2p::kad::{GetProvidersOk, GetRecordOk, QueryId, QueryResult, Quorum, Record};
+use libp2p::metrics::{Metrics, Recorder};
+use libp2p::request_response::{Message, RequestId, ResponseChannel};
+use libp2p::swarm::{AddressScore, DialError, SwarmEvent, THandlerErr};
+use libp2p::{dcutr, identify, kad, ping, relay, request_response, Multiaddr, PeerId, Swarm};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{Instrument, Span};
+
+pub mod backoff;
+pub mod dialer;
+pub mod error;
+pub mod event;
+pub mod handler;
+mod recent;
+pub mod relay_server;
+mod reservations;
+pub mod supervisor;
+
+pub use backoff::Backoff;
+pub use dialer::{DialPath, DialTimeouts};
+pub use error::ChatError;
+pub use event::NodeEvent;
+pub use handler::{AckHandler, ChatHandler, EchoHandler};
+pub use relay_server::{RelayMetrics, RelayStatus};
+pub use supervisor::Health;
+
+use dialer::Dialer;
+use recent::{RecentRequests, Seen};
+use relay_server::RelayState;
+use reservations::Reservations;
+use supervisor::{Reason, Supervisor};
+
+const COMMAND_BUFFER: usize = 64;
+const EVENT_BUFFER: usize = 1024;
+/// Longer than the default ping interval, so an established connection pings at least once.
+const PING_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+enum Command {
+    Dial {
+        address: Multiaddr,
+        sender: oneshot::Sender<anyhow::Result<()>>,
+    },
+    ListenOn {
+        address: Multiaddr,
+        sender: oneshot::Sender<anyhow::Result<ListenerId>>,
+    },
+    AddExternalAddress {
+        address: Multiaddr,
+    },
+    AddChatAddress {
+        peer: PeerId,
+        address: Multiaddr,
+    },
+    SendChat {
+        peer: PeerId,
+        request: ChatRequest,
+        sender: oneshot::Sender<Result<ChatResponse, ChatError>>,
+    },
+    RespondChat {
+        request_id: RequestId,
+        response: ChatResponse,
+        sender: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Peers {
+        sender: oneshot::Sender<Vec<PeerId>>,
+    },
+    RemoveListener {
+        id: ListenerId,
+        sender: oneshot::Sender<bool>,
+    },
+    Ping {
+        peer: PeerId,
+        sender: oneshot::Sender<anyhow::Result<Duration>>,
+    },
+    KadGet {
+        key: Vec<u8>,
+        sender: oneshot::Sender<anyhow::Result<Vec<u8>>>,
+    },
+    KadPut {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        sender: oneshot::Sender<anyhow::Result<()>>,
+    },
+    KadProviders {
+        key: Vec<u8>,
+        sender: oneshot::Sender<anyhow::Result<Vec<PeerId>>>,
+    },
+    RelayStatus {
+        sender: oneshot::Sender<RelayStatus>,
+    },
+    Reserve {
+        address: Multiaddr,
+        sender: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Unreserve {
+        address: Multiaddr,
+        sender: oneshot::Sender<bool>,
+    },
+    Pin {
+        address: Multiaddr,
+        sender: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Unpin {
+        peer: PeerId,
+        sender: oneshot::Sender<bool>,
+    },
+    Connect {
+        peer: PeerId,
+        /// Whether to wait for DCUtR once relayed.
+        hole_punch: bool,
+        sender: oneshot::Sender<anyhow::Result<DialPath>>,
+    },
+    PresentVoucher {
+        relay: PeerId,
+        address: Multiaddr,
+        envelope: Vec<u8>,
+        sender: oneshot::Sender<anyhow::Result<u64>>,
+    },
+}
+
+/// An outstanding kademlia query started from a [`NodeHandle`].
+#[derive(Debug)]
+enum KadQuery {
+    Get(oneshot::Sender<anyhow::Result<Vec<u8>>>),
+    Put(oneshot::Sender<anyhow::Result<()>>),
+    Providers {
+        sender: oneshot::Sender<anyhow::Result<Vec<PeerId>>>,
+        providers: HashSet<PeerId>,
+    },
+}
+
+/// Owns the swarm and drives it until every [`NodeHandle`] has been dropped.
+pub struct Node {
+    swarm: Swarm<Behaviour>,
+    command_receiver: mpsc::Receiver<Command>,
+    event_sender: broadcast::Sender<NodeEvent>,
+    pending_requests: HashMap<RequestId, oneshot::Sender<Result<ChatResponse, ChatError>>>,
+    pending_responses: HashMap<RequestId, ResponseChannel<ChatReply>>,
+    chat_codec: ChatCodec,
+    chat_handler: Option<Arc<dyn ChatHandler>>,
+    handled_sender: mpsc::UnboundedSender<(PeerId, RequestId, Option<ChatResponse>)>,
+    handled_receiver: mpsc::UnboundedReceiver<(PeerId, RequestId, Option<ChatResponse>)>,
+    pending_pings: HashMap<PeerId, Vec<oneshot::Sender<anyhow::Result<Duration>>>>,
+    pending_kad_queries: HashMap<QueryId, KadQuery>,
+    pending_vouchers: HashMap<RequestId, oneshot::Sender<anyhow::Result<u64>>>,
+    /// Whether chat requests are retried, see [`Node::with_chat_retry`].
+    chat_retry: bool,
+    /// Connected peers identified as speaking `/chat/0.2.0`, the only ones retried.
+    chat_v2_peers: HashSet<PeerId>,
+    /// Chat requests in flight, sent once more should their connection close under them, such
+    /// as a direct connection that replaced the relayed ones.
+    chat_retries: HashMap<RequestId, (PeerId, ChatRequest)>,
+    /// Chat requests received lately, so that retries are not handled twice.
+    recent_requests: RecentRequests,
+    /// Spans of the open connections, the parents of everything logged about their peer.
+    connection_spans: HashMap<(PeerId, ConnectedPoint), Span>,
+    /// Spans of the chat requests in flight in either direction.
+    request_spans: HashMap<RequestId, Span>,
+    /// Reservations and circuits served, empty unless the relay server is enabled.
+    relay_state: RelayState,
+    /// Reservations kept on relays, empty unless the relay client is enabled.
+    reservations: Reservations,
+    /// Relays and pinned peers the node stays connected to.
+    supervisor: Supervisor,
+    /// Peers being reached through [`NodeHandle::connect`].
+    dialer: Dialer,
+    metrics: Option<Metrics>,
+}
+
+impl Node {
+    pub async fn new(
+        keypair: &Keypair,
+        config: BehaviourConfig,
+    ) -> anyhow::Result<(Self, NodeHandle)> {
+        let chat_codec = config.chat_codec();
+        let bandwidth = config.bandwidth.clone();
+        let relay_config = config.relay.clone();
+        let relay_limiters = config.relay_limiters.clone();
+        let swarm = Behaviour::new(keypair, config).await?;
+        let (node, handle) = Self::with_swarm(swarm, chat_codec, bandwidth);
+        Ok((node.with_relay_config(relay_config, relay_limiters), handle))
+    }
+
+    /// Wraps an already built swarm. `chat_codec` must carry the same limits as the codec the
+    /// swarm's chat behaviour was built with, and `bandwidth` be the counters of its transport.
+    pub fn with_swarm(
+        swarm: Swarm<Behaviour>,
+        chat_codec: ChatCodec,
+        bandwidth: Bandwidth,
+    ) -> (Self, NodeHandle) {
+        let (command_sender, command_receiver) = mpsc::channel(COMMAND_BUFFER);
+        let (event_sender, _) = broadcast::channel(EVENT_BUFFER);
+        let (handled_sender, handled_receiver) = mpsc::unbounded_channel();
+        let relay_state = RelayState::new(
+            RelayConfig::default(),
+            LimiterState::default(),
+            bandwidth.clone(),
+        );
+        let reservations = Reservations::new(event_sender.clone());
+        let supervisor = Supervisor::new(event_sender.clone());
+        let dialer = Dialer::new(swarm.behaviour().dcutr.is_enabled(), event_sender.clone());
+        let handle = NodeHandle {
+            local_peer_id: *swarm.local_peer_id(),
+            command_sender,
+            event_sender: event_sender.clone(),
+            bandwidth,
+        };
+        let node = Self {
+            swarm,
+            command_receiver,
+            event_sender,
+            pending_requests: HashMap::new(),
+            pending_responses: HashMap::new(),
+            chat_codec,
+            chat_handler: None,
+            handled_sender,
+            handled_receiver,
+            pending_pings: HashMap::new(),
+            pending_kad_queries: HashMap::new(),
+            pending_vouchers: HashMap::new(),
+            chat_retry: false,
+            chat_v2_peers: HashSet::new(),
+            chat_retries: HashMap::new(),
+            recent_requests: RecentRequests::default(),
+            connection_spans: HashMap::new(),
+            request_spans: HashMap::new(),
+            relay_state,
+            reservations,
+            supervisor,
+            dialer,
+            metrics: None,
+        };
+        (node, handle)
+    }
+
+    /// Answers inbound chat requests with `handler` instead of leaving them to
+    /// [`NodeHandle::respond_chat`].
+    pub fn with_chat_handler(mut self, handler: impl ChatHandler) -> Self {
+        self.chat_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// The config and limiter state the swarm's relay server was built with, used to explain
+    /// denials, to accept vouchers and to keep its reservations per IP address up to date.
+    pub fn with_relay_config(mut self, config: RelayConfig, limiters: LimiterState) -> Self {
+        self.relay_state.set_config(config, limiters);
+        self.disconnect_revoked();
+        self
+    }
+
+    /// Sends chat requests to peers identified as speaking `/chat/0.2.0` once more should their
+    /// connection close under them. Off by default, as only peers of this release onward answer
+    /// a retry without handling the request twice.
+    pub fn with_chat_retry(mut self, retry: bool) -> Self {
+        self.chat_retry = retry;
+        self
+    }
+
+    /// Presents `voucher` to every relay before reserving on it, see [`NodeHandle::reserve`].
+    pub fn with_relay_voucher(mut self, voucher: Vec<u8>) -> Self {
+        self.reservations.set_voucher(voucher);
+        self
+    }
+
+    /// How long to wait before reserving on a relay again after losing the reservation.
+    pub fn with_reservation_backoff(mut self, backoff: Backoff) -> Self {
+        self.reservations.set_backoff(backoff);
+        self
+    }
+
+    /// How long to wait before dialing a relay or pinned peer again after losing the
+    /// connection to it.
+    pub fn with_redial_backoff(mut self, backoff: Backoff) -> Self {
+        self.supervisor.set_backoff(backoff);
+        self
+    }
+
+    /// How long [`NodeHandle::connect`] tries each way of reaching a peer.
+    pub fn with_dial_timeouts(mut self, timeouts: DialTimeouts) -> Self {
+        self.dialer.set_timeouts(timeouts);
+        self
+    }
+
+    /// Appends what the relay server does for whom to `log`.
+    pub fn with_audit(mut self, log: AuditLog) -> Self {
+        self.relay_state.set_audit(log);
+        self
+    }
+
+    /// Records swarm and protocol events in `metrics`.
+    pub fn with_metrics(mut self, metrics: NodeMetrics) -> Self {
+        self.relay_state.set_metrics(metrics.relay);
+        self.metrics = Some(metrics.libp2p);
+        self
+    }
+
+    /// Builds a node answering chat requests with `handler` and runs its event loop on a
+    /// background tokio task.
+    pub async fn spawn(
+        keypair: &Keypair,
+        config: BehaviourConfig,
+        handler: impl ChatHandler,
+    ) -> anyhow::Result<NodeHandle> {
+        let (node, handle) = Self::new(keypair, config).await?;
+        node.with_chat_handler(handler).start();
+        Ok(handle)
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        tokio::spawn(self.run())
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            let retry = [
+                self.reservations.next_retry(),
+                self.supervisor.next_dial(),
+                self.dialer.next_deadline(),
+                self.relay_state.next_revocation(),
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            let retry_timer = time::sleep_until(retry.unwrap_or_else(Instant::now).into());
+            tokio::select! {
+                event = self.swarm.select_next_some() => self.handle_swarm_event(event),
+                command = self.command_receiver.recv() => match command {
+                    Some(command) => self.handle_command(command),
+                    None => {
+                        tracing::info!("all node handles dropped, stopping node");
+                        return;
+                    }
+                },
+                Some((peer, request_id, response)) = self.handled_receiver.recv() => {
+                    self.handle_chat_handled(peer, request_id, response)
+                }
+                _ = retry_timer, if retry.is_some() => {
+                    self.supervisor.dial_due(&mut self.swarm);
+                    self.reservations.retry_due(&mut self.swarm);
+                    self.dialer.check_deadlines(&mut self.swarm);
+                    self.relay_state.revoke_refused();
+                    self.disconnect_revoked();
+                }
+            }
+        }
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Dial { address, sender } => {
+                let _ = sender.send(self.swarm.dial(address).map_err(Into::into));
+            }
+            Command::ListenOn { address, sender } => {
+                let _ = sender.send(self.swarm.listen_on(address).map_err(Into::into));
+            }
+            Command::AddExternalAddress { address } => {
+                self.swarm
+                    .add_external_address(address, AddressScore::Infinite);
+            }
+            Command::AddChatAddress { peer, address } => {
+                self.dialer.learn(peer, [address.clone()]);
+                if let Some(chat) = self.swarm.behaviour_mut().chat.as_mut() {
+                    chat.add_address(&peer, address);
+                }
+            }
+            Command::SendChat {
+                peer,
+                request,
+                sender,
+            } => {
+                let max = self.chat_codec.max_request_size;
+                match self.swarm.behaviour_mut().chat.as_mut() {
+                    Some(_) if request.0.frame_size() > max => {
+                        let size = request.0.frame_size();
+                        let _ = sender.send(Err(ChatError::RequestTooLarge { size, max }));
+                    }
+                    Some(chat) => {
+                        let size = request.data().len();
+                        let request_id = chat.send_request(&peer, request.clone());
+                        if self.chat_retry && self.chat_v2_peers.contains(&peer) {
+                            self.chat_retries.insert(request_id, (peer, request));
+                        }
+                        self.chat_started(&peer);
+                        self.pending_requests.insert(request_id, sender);
+                        let span = self.request_span(peer, request_id, "outbound");
+                        tracing::debug!(parent: &span, size, "sending request");
+                    }
+                    None => {
+                        let _ = sender.send(Err(ChatError::Disabled));
+                    }
+                }
+            }
+            Command::RespondChat {
+                request_id,
+                response,
+                sender,
+            } => {
+                let max = self.chat_codec.max_response_size;
+                let result = match check_response_size(&response, max) {
+                    Ok(()) => self.respond_chat(request_id, response),
+                    // Left open for a response that fits.
+                    Err(too_large) => Err(too_large.into()),
+                };
+                let _ = sender.send(result);
+            }
+            Command::Peers { sender } => {
+                let _ = sender.send(self.swarm.connected_peers().copied().collect());
+            }
+            Command::RemoveListener { id, sender } => {
+                let _ = sender.send(self.swarm.remove_listener(id));
+            }
+            Command::Ping { peer, sender } => {
+                if self.swarm.behaviour().ping.as_ref().is_none() {
+                    let _ = sender.send(Err(anyhow::anyhow!("ping is disabled")));
+                    return;
+                }
+                // Pings run on every connection, so all it takes is being connected.
+                if !self.swarm.is_connected(&peer) {
+                    if let Err(e) = self.swarm.dial(peer) {
+                        let _ = sender.send(Err(e.into()));
+                        return;
+                    }
+                }
+                self.pending_pings.entry(peer).or_default().push(sender);
+            }
+            Command::KadGet { key, sender } => match self.swarm.behaviour_mut().kad.as_mut() {
+                Some(kad) => {
+                    let id = kad.get_record(key.into());
+                    self.pending_kad_queries.insert(id, KadQuery::Get(sender));
+                }
+                None => {
+                    let _ = sender.send(Err(anyhow::anyhow!("kademlia is disabled")));
+                }
+            },
+            Command::KadPut { key, value, sender } => {
+                match self.swarm.behaviour_mut().kad.as_mut() {
+                    Some(kad) => match kad.put_record(Record::new(key, value), Quorum::One) {
+                        Ok(id) => {
+                            self.pending_kad_queries.insert(id, KadQuery::Put(sender));
+                        }
+                        Err(e) => {
+                            let _ = sender.send(Err(e.into()));
+                        }
+                    },
+                    None => {
+                        let _ = sender.send(Err(anyhow::anyhow!("kademlia is disabled")));
+                    }
+                }
+            }
+            Command::KadProviders { key, sender } => {
+                match self.swarm.behaviour_mut().kad.as_mut() {
+                    Some(kad) => {
+                        let id = kad.get_providers(key.into());
+                        let query = KadQuery::Providers {
+                            sender,
+                            providers: HashSet::new(),
+                        };
+                        self.pending_kad_queries.insert(id, query);
+                    }
+                    None => {
+                        let _ = sender.send(Err(anyhow::anyhow!("kademlia is disabled")));
+                    }
+                }
+            }
+            Command::RelayStatus { sender } => {
+                let _ = sender.send(self.relay_state.status());
+            }
+            Command::Reserve { address, sender } => {
+                let result = self.reservations.add(&mut self.swarm, address.clone());
+                if let (Ok(()), Some(peer)) = (&result, PeerId::try_from_multiaddr(&address)) {
+                    self.supervisor
+                        .add(&mut self.swarm, peer, address, Reason::Relay);
+                }
+                let _ = sender.send(result);
+            }
+            Command::Unreserve { address, sender } => {
+                if let Some(peer) = PeerId::try_from_multiaddr(&address) {
+                    self.supervisor.remove(&peer, Reason::Relay);
+                }
+                let _ = sender.send(self.reservations.remove(&mut self.swarm, &address));
+            }
+            Command::Pin { address, sender } => {
+                let result = match PeerId::try_from_multiaddr(&address) {
+                    Some(peer) => {
+                        self.supervisor
+                            .add(&mut self.swarm, peer, address, Reason::Pin);
+                        Ok(())
+                    }
+                    None => Err(anyhow::anyhow!("address {address} has no peer id")),
+                };
+                let _ = sender.send(result);
+            }
+            Command::Unpin { peer, sender } => {
+                let _ = sender.send(self.supervisor.remove(&peer, Reason::Pin));
+            }
+            Command::Connect {
+                peer,
+                hole_punch,
+                sender,
+            } => match self.connected_path(&peer) {
+                Some(path) => {
+                    let _ = sender.send(Ok(path));
+                }
+                None => {
+                    let relays = self.reservations.relays();
+                    self.dialer
+                        .dial(&mut self.swarm, peer, relays, hole_punch, sender);
+                }
+            },
+            Command::PresentVoucher {
+                relay,
+                address,
+                envelope,
+                sender,
+            } => match self.swarm.behaviour_mut().voucher.as_mut() {
+                Some(voucher) => {
+                    voucher.add_address(&relay, address);
+                    let request_id = voucher.send_request(&relay, VoucherRequest { envelope });
+                    self.pending_vouchers.insert(request_id, sender);
+                }
+                None => {
+                    let _ = sender.send(Err(anyhow::anyhow!("relay vouchers are disabled")));
+                }
+            },
+        }
+    }
+
+    fn handle_swarm_event(&mut self, event: SwarmEvent<Event, THandlerErr<Behaviour>>) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record(&event);
+        }
+        let event = match event {
+            SwarmEvent::Behaviour(event) => match self.handle_behaviour_event(event) {
+                Some(event) => event,
+                None => return,
+            },
+            SwarmEvent::NewListenAddr {
+                listener_id,
+                address,
+            } => {
+                self.reservations
+                    .on_new_listen_addr(&mut self.swarm, listener_id, &address);
+                NodeEvent::NewListenAddr { address }
+            }
+            SwarmEvent::ExpiredListenAddr {
+                listener_id,
+                address,
+            } => {
+                self.reservations
+                    .on_expired_listen_addr(&mut self.swarm, listener_id, &address);
+                NodeEvent::ExpiredListenAddr { address }
+            }
+            SwarmEvent::ListenerClosed {
+                listener_id,
+                addresses,
+                reason,
+            } => {
+                let reason = reason.err().map(|e| format!("{e:?}"));
+                self.reservations.on_listener_closed(
+                    &mut self.swarm,
+                    listener_id,
+                    reason.as_deref(),
+                );
+                NodeEvent::ListenerClosed { addresses, reason }
+            }
+            SwarmEvent::ListenerError { error, .. } => NodeEvent::ListenerError {
+                error: format!("{error:?}"),
+            },
+            SwarmEvent::Dialing(peer_id) => NodeEvent::Dialing { peer_id },
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint,
+                num_established,
+                established_in,
+                ..
+            } => {
+                let span = tracing::info_span!(
+                    "connection",
+                    peer = %peer_id,
+                    remote = %endpoint.get_remote_address(),
+                    relayed = endpoint.is_relayed(),
+                );
+                tracing::info!(
+                    parent: &span,
+                    num_established = num_established.get(),
+                    ?established_in,
+                    "connection established"
+                );
+                self.connection_spans
+                    .insert((peer_id, endpoint.clone()), span);
+                self.relay_state.on_connected(peer_id, &endpoint);
+                self.supervisor.on_connected(&peer_id);
+                self.reservations.on_connected(&mut self.swarm, &peer_id);
+                self.dialer.on_connected(&peer_id, &endpoint);
+                NodeEvent::ConnectionEstablished {
+                    peer_id,
+                    endpoint,
+                    num_established: num_established.get(),
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                endpoint,
+                num_established,
+                cause,
+            } => {
+                let cause = cause.map(|e| format!("{e:?}"));
+                if num_established == 0 {
+                    self.chat_v2_peers.remove(&peer_id);
+                    self.relay_state.on_disconnected(&peer_id);
+                    self.supervisor.on_disconnected(&peer_id, cause.as_deref());
+                    self.dialer.on_disconnected(&peer_id);
+                }
+                if let Some(span) = self.connection_spans.remove(&(peer_id, endpoint.clone())) {
+                    tracing::info!(parent: &span, num_established, ?cause, "connection closed");
+                }
+                NodeEvent::ConnectionClosed {
+                    peer_id,
+                    endpoint,
+                    num_established,
+                    cause,
+                }
+            }
+            SwarmEvent::IncomingConnection {
+                local_addr,
+                send_back_addr,
+            } => NodeEvent::IncomingConnection {
+                local_addr,
+                send_back_addr,
+            },
+            SwarmEvent::IncomingConnectionError {
+                local_addr,
+                send_back_addr,
+                error,
+            } => NodeEvent::IncomingConnectionError {
+                local_addr,
+                send_back_addr,
+                error: format!("{error:?}"),
+            },
+            SwarmEvent::OutgoingConnectionError { peer_id, error } => {
+                let addresses: Vec<Multiaddr> = match &error {
+                    DialError::Transport(errors) => {
+                        errors.iter().map(|(address, _)| address.clone()).collect()
+                    }
+                    DialError::WrongPeerId { endpoint, .. } => {
+                        vec![endpoint.get_remote_address().clone()]
+                    }
+                    _ => Vec::new(),
+                };
+                let error = format!("{error:?}");
+                if let Some(peer) = &peer_id {
+                    // Pings wait for a connection, which this dial was to open.
+                    if !self.swarm.is_connected(peer) {
+                        for sender in self.pending_pings.remove(peer).unwrap_or_default() {
+                            let _ = sender.send(Err(anyhow::anyhow!("dialing failed: {error}")));
+                        }
+                    }
+                    self.supervisor.on_dial_failed(peer, &error);
+                    self.dialer
+                        .on_dial_failed(&mut self.swarm, peer, &addresses, &error);
+                }
+                NodeEvent::OutgoingConnectionError { peer_id, error }
+            }
+            _ => return,
+        };
+        // Nobody listening is fine, the node keeps running for the handles.
+        let _ = self.event_sender.send(event);
+    }
+
+    fn handle_behaviour_event(&mut self, event: Event) -> Option<NodeEvent> {
+        if let Some(metrics) = &self.metrics {
+            match &event {
+                Event::Ping(event) => metrics.record(event),
+                Event::Identify(event) => metrics.record(event),
+                Event::Kademlia(event) => metrics.record(event),
+                Event::Gossipsub(event) => metrics.record(event),
+                Event::Relay(event) => metrics.record(event),
+                Event::Dcutr(event) => metrics.record(event),
+                _ => {}
+            }
+        }
+        match event {
+            Event::Ping(ping::Event { peer, result }) => {
+                let rtt = match result {
+                    Ok(ping::Success::Ping { rtt }) => Ok(rtt),
+                    Ok(ping::Success::Pong) => return None,
+                    Err(e) => Err(format!("{e:?}")),
+                };
+                tracing::debug!(parent: &self.peer_span(&peer), ?rtt, "ping");
+                self.supervisor.on_ping(&peer, rtt.is_ok());
+                for sender in self.pending_pings.remove(&peer).unwrap_or_default() {
+                    let _ = sender.send(rtt.clone().map_err(anyhow::Error::msg));
+                }
+                Some(NodeEvent::Ping { peer, rtt })
+            }
+            Event::Identify(identify::Event::Received { peer_id, info }) => {
+                tracing::debug!(
+                    parent: &self.peer_span(&peer_id),
+                    agent_version = %info.agent_version,
+                    protocols = ?info.protocols,
+                    "identified"
+                );
+                // Routes to peers behind relays only become known through their circuit
+                // addresses, which they publish over identify.
+                let speaks_kad = info
+                    .protocols
+                    .iter()
+                    .any(|protocol| protocol.as_bytes() == kad::protocol::DEFAULT_PROTO_NAME);
+                self.dialer
+                    .learn(peer_id, info.listen_addrs.iter().cloned());
+                let speaks_chat_v2 = info
+                    .protocols
+                    .iter()
+                    .any(|protocol| protocol == CHAT_PROTOCOL_V2);
+                if speaks_chat_v2 && self.swarm.is_connected(&peer_id) {
+                    self.chat_v2_peers.insert(peer_id);
+                } else {
+                    self.chat_v2_peers.remove(&peer_id);
+                }
+                if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
+                    if speaks_kad {
+                        for address in &info.listen_addrs {
+                            kad.add_address(&peer_id, address.clone());
+                        }
+                    }
+                }
+                Some(NodeEvent::Identified {
+                    peer_id,
+                    agent_version: info.agent_version,
+                    listen_addrs: info.listen_addrs,
+                    protocols: info.protocols,
+                })
+            }
+            Event::Kademlia(kad::KademliaEvent::RoutingUpdated {
+                peer,
+                is_new_peer,
+                addresses,
+                ..
+            }) => {
+                tracing::debug!(
+                    parent: &self.peer_span(&peer),
+                    is_new_peer,
+                    "kademlia routing updated"
+                );
+                let addresses = addresses.into_vec();
+                self.dialer.learn(peer, addresses.iter().cloned());
+                Some(NodeEvent::KadRoutingUpdated {
+                    peer,
+                    is_new_peer,
+                    addresses,
+                })
+            }
+            Event::Kademlia(kad::KademliaEvent::RoutablePeer { peer, address }) => {
+                self.dialer.learn(peer, [address.clone()]);
+                Some(NodeEvent::KadRoutablePeer { peer, address })
+            }
+            Event::Kademlia(kad::KademliaEvent::UnroutablePeer { peer }) => {
+                self.dialer.forget(&peer);
+                Some(NodeEvent::KadUnroutablePeer { peer })
+            }
+            Event::Kademlia(kad::KademliaEvent::OutboundQueryProgressed {
+                id,
+                result,
+                step,
+                ..
+            }) => {
+                self.handle_kad_query(id, result, step.last);
+                None
+            }
+            Event::Chat(event) => self.handle_chat_event(event),
+            Event::Dcutr(event) => self.handle_dcutr_event(event),
+            Event::Relay(event) => self.handle_relay_event(event),
+            Event::Voucher(event) => self.handle_voucher_event(event),
+            Event::RelayClient(event) => match event {
+                relay::client::Event::ReservationReqAccepted {
+                    relay_peer_id,
+                    renewal,
+                    ..
+                } => {
+                    self.reservations.on_reservation_accepted(&relay_peer_id);
+                    Some(NodeEvent::ReservationAccepted {
+                        relay_peer_id,
+                        renewal,
+                    })
+                }
+                // The listener through the relay closes as well, which is where the
+                // reservation manager picks the failure up.
+                relay::client::Event::ReservationReqFailed {
+                    relay_peer_id,
+                    renewal,
+                    error,
+                } => Some(NodeEvent::ReservationFailed {
+                    relay_peer_id,
+                    renewal,
+                    error: format!("{error:?}"),
+                }),
+                relay::client::Event::OutboundCircuitEstablished { relay_peer_id, .. } => {
+                    Some(NodeEvent::OutboundCircuitEstablished { relay_peer_id })
+                }
+                relay::client::Event::InboundCircuitEstablished { src_peer_id, .. } => {
+                    Some(NodeEvent::InboundCircuitEstablished { src_peer_id })
+                }
+                event => {
+                    tracing::debug!("RelayClient: {event:?}");
+                    None
+                }
+            },
+            event => {
+                tracing::debug!("{event:?}");
+                None
+            }
+        }
+    }
+
+    fn handle_relay_event(&mut self, event: relay::Event) -> Option<NodeEvent> {
+        // Only denials have a reason.
+        let reason = self.relay_state.on_event(&event).unwrap_or_default();
+        self.disconnect_revoked();
+        match event {
+            relay::Event::ReservationReqAccepted {
+                src_peer_id,
+                renewed,
+            } => Some(NodeEvent::RelayReservationAccepted {
+                src_peer_id,
+                renewed,
+            }),
+            relay::Event::ReservationReqDenied { src_peer_id } => {
+                tracing::info!(
+                    parent: &self.peer_span(&src_peer_id),
+                    reason,
+                    "reservation denied"
+                );
+                Some(NodeEvent::RelayReservationDenied {
+                    src_peer_id,
+                    reason,
+                })
+            }
+            relay::Event::ReservationTimedOut { src_peer_id } => {
+                Some(NodeEvent::RelayReservationTimedOut { src_peer_id })
+            }
+            relay::Event::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            } => Some(NodeEvent::RelayCircuitAccepted {
+                src_peer_id,
+                dst_peer_id,
+            }),
+            relay::Event::CircuitReqDenied {
+                src_peer_id,
+                dst_peer_id,
+            } => {
+                tracing::info!(
+                    parent: &self.peer_span(&src_peer_id),
+                    %dst_peer_id,
+                    reason,
+                    "circuit denied"
+                );
+                Some(NodeEvent::RelayCircuitDenied {
+                    src_peer_id,
+                    dst_peer_id,
+                    reason,
+                })
+            }
+            relay::Event::CircuitClosed {
+                src_peer_id,
+                dst_peer_id,
+                error,
+            } => Some(NodeEvent::RelayCircuitClosed {
+                src_peer_id,
+                dst_peer_id,
+                error: error.map(|e| e.to_string()),
+            }),
+            event => {
+                tracing::debug!("Relay: {event:?}");
+                None
+            }
+        }
+    }
+
+    /// Disconnects the peers whose reservations the ACL revoked, which ends them.
+    fn disconnect_revoked(&mut self) {
+        for peer in self.relay_state.take_revoked() {
+            tracing::info!(parent: &self.peer_span(&peer), "reservation revoked, disconnecting");
+            let _ = self.swarm.disconnect_peer_id(peer);
+        }
+    }
+
+    fn handle_voucher_event(
+        &mut self,
+        event: request_response::Event<VoucherRequest, VoucherResponse>,
+    ) -> Option<NodeEvent> {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message:
+                    Message::Request {
+                        request, channel, ..
+                    },
+            } => {
+                let response = self.relay_state.on_voucher(peer, request);
+                let expires = match &response {
+                    VoucherResponse::Accepted { expires } => Ok(*expires),
+                    VoucherResponse::Rejected { reason } => Err(reason.clone()),
+                };
+                tracing::info!(parent: &self.peer_span(&peer), ?expires, "voucher received");
+                if let Some(voucher) = self.swarm.behaviour_mut().voucher.as_mut() {
+                    let _ = voucher.send_response(channel, response);
+                }
+                Some(NodeEvent::RelayVoucherReceived { peer, expires })
+            }
+            request_response::Event::Message {
+                message:
+                    Message::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            } => {
+                let result = match response {
+                    VoucherResponse::Accepted { expires } => Ok(expires),
+                    VoucherResponse::Rejected { reason } => {
+                        Err(anyhow::anyhow!("relay rejected the voucher: {reason}"))
+                    }
+                };
+                self.handle_voucher_result(request_id, result);
+                None
+            }
+            request_response::Event::OutboundFailure {
+                request_id, error, ..
+            } => {
+                self.handle_voucher_result(request_id, Err(error.into()));
+                None
+            }
+            event => {
+                tracing::debug!("Voucher: {event:?}");
+                None
+            }
+        }
+    }
+
+    /// Hands the outcome of presenting a voucher to whoever presented it.
+    fn handle_voucher_result(&mut self, request_id: RequestId, result: anyhow::Result<u64>) {
+        let Some(result) =
+            self.reservations
+                .on_voucher_response(&mut self.swarm, request_id, result)
+        else {
+            return;
+        };
+        if let Some(sender) = self.pending_vouchers.remove(&request_id) {
+            let _ = sender.send(result);
+        }
+    }
+
+    fn handle_chat_event(
+        &mut self,
+        event: request_response::Event<ChatRequest, ChatReply>,
+    ) -> Option<NodeEvent> {
+        match event {
+            request_response::Event::Message { peer, message } => match message {
+                Message::Request {
+                    request_id,
+                    request,
+                    channel,
+                } => {
+                    self.pending_responses.insert(request_id, channel);
+                    self.chat_started(&peer);
+                    let span = self.request_span(peer, request_id, "inbound");
+                    tracing::debug!(parent: &span, size = request.data().len(), "request received");
+                    match self
+                        .recent_requests
+                        .received(peer, &request.0.id, request_id)
+                    {
+                        None => {}
+                        Some(Seen::Pending {
+                            request_id: first, ..
+                        }) => {
+                            tracing::debug!(parent: &span, %first, "retry of a request being handled");
+                            return None;
+                        }
+                        Some(Seen::Answered(response)) => {
+                            tracing::debug!(parent: &span, "retry of an answered request");
+                            if let (Some(channel), Some(chat)) = (
+                                self.pending_responses.remove(&request_id),
+                                self.swarm.behaviour_mut().chat.as_mut(),
+                            ) {
+                                let _ = chat.send_response(channel, Ok(response));
+                            }
+                            return None;
+                        }
+                    }
+                    if let Some(handler) = self.chat_handler.clone() {
+                        let handled_sender = self.handled_sender.clone();
+                        let request = request.clone();
+                        let max = self.chat_codec.max_response_size;
+                        let task = async move {
+                            let response = match handler.handle(peer, request).await {
+                                Ok(response) => match check_response_size(&response, max) {
+                                    Ok(()) => Some(response),
+                                    Err(too_large) => handler.on_error(peer, too_large.into()),
+                                },
+                                Err(e) => handler.on_error(peer, e),
+                            };
+                            let _ = handled_sender.send((peer, request_id, response));
+                        };
+                        tokio::spawn(task.instrument(span));
+                    }
+                    Some(NodeEvent::ChatRequest {
+                        peer,
+                        request_id,
+                        request,
+                    })
+                }
+                Message::Response {
+                    request_id,
+                    response: Err(MessageTooLarge { size, max }),
+                } => {
+                    self.chat_retries.remove(&request_id);
+                    self.chat_finished(&peer);
+                    if let Some(span) = self.request_spans.remove(&request_id) {
+                        tracing::warn!(parent: &span, size, max, "response too large");
+                    }
+                    let error = ChatError::ResponseTooLarge { size, max };
+                    if let Some(sender) = self.pending_requests.remove(&request_id) {
+                        let _ = sender.send(Err(error.clone()));
+                    }
+                    Some(NodeEvent::ChatOutboundFailure {
+                        peer,
+                        request_id,
+                        error: error.to_string(),
+                    })
+                }
+                Message::Response {
+                    request_id,
+                    response: Ok(response),
+                } => {
+                    self.chat_retries.remove(&request_id);
+                    self.chat_finished(&peer);
+                    if let Some(span) = self.request_spans.remove(&request_id) {
+                        let size = response.data().len();
+                        tracing::debug!(parent: &span, size, "response received");
+                    }
+                    if let Some(sender) = self.pending_requests.remove(&request_id) {
+                        let _ = sender.send(Ok(response.clone()));
+                    }
+                    Some(NodeEvent::ChatResponse {
+                        peer,
+                        request_id,
+                        response,
+                    })
+                }
+            },
+            request_response::Event::ResponseSent { peer, request_id } => {
+                self.chat_finished(&peer);
+                if let Some(span) = self.request_spans.remove(&request_id) {
+                    tracing::debug!(parent: &span, "response sent");
+                }
+                Some(NodeEvent::ChatResponseSent { peer, request_id })
+            }
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                self.chat_finished(&peer);
+                let closed = matches!(error, request_response::OutboundFailure::ConnectionClosed);
+                let retry = self.chat_retries.remove(&request_id);
+                if let Some((peer, request)) = retry.filter(|_| closed) {
+                    self.retry_chat(request_id, peer, request);
+                    return None;
+                }
+                if let Some(span) = self.request_spans.remove(&request_id) {
+                    tracing::warn!(parent: &span, %error, "request failed");
+                }
+                if let Some(sender) = self.pending_requests.remove(&request_id) {
+                    let _ = sender.send(Err(error.clone().into()));
+                }
+                Some(NodeEvent::ChatOutboundFailure {
+                    peer,
+                    request_id,
+                    error: format!("{error:?}"),
+                })
+            }
+            request_response::Event::InboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                if let Some(span) = self.request_spans.remove(&request_id) {
+                    tracing::warn!(parent: &span, %error, "responding failed");
+                }
+                self.pending_responses.remove(&request_id);
+                self.chat_finished(&peer);
+                Some(NodeEvent::ChatInboundFailure {
+                    peer,
+                    request_id,
+                    error: format!("{error:?}"),
+                })
+            }
+        }
+    }
+
+    /// Holds up the migration of `peer` off its relayed connections until the chat request
+    /// that started is done.
+    fn chat_started(&mut self, peer: &PeerId) {
+        if let Some(migrate) = self.swarm.behaviour_mut().migrate.as_mut() {
+            migrate.on_request_started(peer);
+        }
+    }
+
+    fn chat_finished(&mut self, peer: &PeerId) {
+        if let Some(migrate) = self.swarm.behaviour_mut().migrate.as_mut() {
+            migrate.on_request_finished(peer);
+        }
+    }
+
+    /// Sends a request once more after the connection it went out on closed. After a direct
+    /// connection that replaced the relayed ones, this reaches the peer through its circuit
+    /// addresses again. The peer answers a retry of a request it already got without handling
+    /// it twice, see [`RecentRequests`].
+    fn retry_chat(&mut self, request_id: RequestId, peer: PeerId, request: ChatRequest) {
+        let Some(chat) = self.swarm.behaviour_mut().chat.as_mut() else {
+            return;
+        };
+        let retry_id = chat.send_request(&peer, request);
+        self.chat_started(&peer);
+        if let Some(sender) = self.pending_requests.remove(&request_id) {
+            self.pending_requests.insert(retry_id, sender);
+        }
+        if let Some(span) = self.request_spans.remove(&request_id) {
+            tracing::info!(
+                parent: &span,
+                %retry_id,
+                "connection closed, sending the request again"
+            );
+            self.request_spans.insert(retry_id, span);
+        }
+    }
+
+    fn handle_dcutr_event(&mut self, event: dcutr::Event) -> Option<NodeEvent> {
+        match event {
+            dcutr::Event::InitiatedDirectConnectionUpgrade { remote_peer_id, .. } => {
+                Some(NodeEvent::HolePunchStarted {
+                    peer_id: remote_peer_id,
+                    initiated_locally: true,
+                })
+            }
+            dcutr::Event::RemoteInitiatedDirectConnectionUpgrade { remote_peer_id, .. } => {
+                Some(NodeEvent::HolePunchStarted {
+                    peer_id: remote_peer_id,
+                    initiated_locally: false,
+                })
+            }
+            dcutr::Event::DirectConnectionUpgradeSucceeded { remote_peer_id } => {
+                let migrated = self
+                    .swarm
+                    .behaviour_mut()
+                    .migrate
+                    .as_mut()
+                    .is_some_and(|migrate| migrate.migrate(&remote_peer_id));
+                tracing::info!(
+                    parent: &self.peer_span(&remote_peer_id),
+                    migrated,
+                    "hole punch succeeded"
+                );
+                self.dialer.on_hole_punch(&remote_peer_id, Ok(()));
+                Some(NodeEvent::HolePunchSucceeded {
+                    peer_id: remote_peer_id,
+                    migrated,
+                })
+            }
+            // The relayed connection stays, and with it the traffic on it.
+            dcutr::Event::DirectConnectionUpgradeFailed {
+                remote_peer_id,
+                error,
+            } => {
+                tracing::info!(
+                    parent: &self.peer_span(&remote_peer_id),
+                    ?error,
+                    "hole punch failed"
+                );
+                let error = format!("{error:?}");
+                self.dialer
+                    .on_hole_punch(&remote_peer_id, Err(error.clone()));
+                Some(NodeEvent::HolePunchFailed {
+                    peer_id: remote_peer_id,
+                    error,
+                })
+            }
+        }
+    }
+
+    /// How the node is connected to `peer`, if it is.
+    fn connected_path(&self, peer: &PeerId) -> Option<DialPath> {
+        let mut relayed = self
+            .connection_spans
+            .keys()
+            .filter(|(p, _)| p == peer)
+            .map(|(_, endpoint)| endpoint.is_relayed())
+            .peekable();
+        relayed.peek()?;
+        Some(if self.is_migrated(peer) {
+            DialPath::HolePunched
+        } else if relayed.any(|relayed| !relayed) {
+            DialPath::Direct
+        } else {
+            DialPath::Relayed
+        })
+    }
+
+    fn is_migrated(&self, peer: &PeerId) -> bool {
+        self.swarm
+            .behaviour()
+            .migrate
+            .as_ref()
+            .is_some_and(|migrate| migrate.is_migrated(peer))
+    }
+
+    /// The span of a connection to `peer`, or the current span while there is none.
+    fn peer_span(&self, peer: &PeerId) -> Span {
+        self.connection_spans
+            .iter()
+            .find(|((connected, _), _)| connected == peer)
+            .map(|(_, span)| span.clone())
+            .unwrap_or_else(Span::current)
+    }
+
+    /// Opens the span of a chat request, which lasts until its response or failure.
+    fn request_span(&mut self, peer: PeerId, request_id: RequestId, direction: &str) -> Span {
+        let span = tracing::info_span!(
+            parent: &self.peer_span(&peer),
+            "request",
+            %peer,
+            %request_id,
+            protocol = "chat",
+            direction,
+        );
+        self.request_spans.insert(request_id, span.clone());
+        span
+    }
+
+    fn handle_kad_query(&mut self, id: QueryId, result: QueryResult, last: bool) {
+        let Some(query) = self.pending_kad_queries.remove(&id) else {
+            if last {
+                self.dialer.on_lookup_done(&mut self.swarm, id);
+            }
+            return;
+        };
+        match (query, result) {
+            (KadQuery::Get(sender), QueryResult::GetRecord(result)) => match result {
+                Ok(GetRecordOk::FoundRecord(found)) => {
+                    // The first record answers the query, there is no need to wait for more.
+                    if let Some(mut query) = self
+                        .swarm
+                        .behaviour_mut()
+                        .kad
+                        .as_mut()
+                        .and_then(|kad| kad.query_mut(&id))
+                    {
+                        query.finish();
+                    }
+                    let _ = sender.send(Ok(found.record.value));
+                }
+                Ok(GetRecordOk::FinishedWithNoAdditionalRecord { .. }) => {
+                    let _ = sender.send(Err(anyhow::anyhow!("record not found")));
+                }
+                Err(e) => {
+                    let _ = sender.send(Err(e.into()));
+                }
+            },
+            (KadQuery::Put(sender), QueryResult::PutRecord(result)) => {
+                let _ = sender.send(result.map(|_| ()).map_err(Into::into));
+            }
+            (
+                KadQuery::Providers {
+                    sender,
+                    mut providers,
+                },
+                QueryResult::GetProviders(result),
+            ) => match result {
+                Ok(GetProvidersOk::FoundProviders {
+                    providers: found, ..
+                }) if !last => {
+                    providers.extend(found);
+                    let query = KadQuery::Providers { sender, providers };
+                    self.pending_kad_queries.insert(id, query);
+                }
+                Ok(GetProvidersOk::FoundProviders {
+                    providers: found, ..
+                }) => {
+                    providers.extend(found);
+                    let _ = sender.send(Ok(providers.into_iter().collect()));
+                }
+                Ok(GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => {
+                    let _ = sender.send(Ok(providers.into_iter().collect()));
+                }
+                Err(e) => {
+                    let _ = sender.send(Err(e.into()));
+                }
+            },
+            (query, result) => {
+                tracing::warn!("unexpected result {result:?} for kademlia query {query:?}");
+            }
+        }
+    }
+
+    fn handle_chat_handled(
+        &mut self,
+        peer: PeerId,
+        request_id: RequestId,
+        response: Option<ChatResponse>,
+    ) {
+        let max = self.chat_codec.max_response_size;
+        let span = self
+            .request_spans
+            .get(&request_id)
+            .cloned()
+            .unwrap_or_else(Span::none);
+        let response = match response {
+            // Only the response `ChatHandler::on_error` made of an oversized one gets here too
+            // large, there is nobody left to ask for a smaller one.
+            Some(response) => match check_response_size(&response, max) {
+                Ok(()) => Some(response),
+                Err(too_large) => {
+                    tracing::warn!(parent: &span, "dropping response to {request_id:?}: {too_large}");
+                    let _ = self.event_sender.send(NodeEvent::ChatInboundFailure {
+                        peer,
+                        request_id,
+                        error: too_large.to_string(),
+                    });
+                    None
+                }
+            },
+            None => None,
+        };
+        let Some(response) = response else {
+            // Dropping the channels tells the requesters that no response is coming.
+            for id in std::iter::once(request_id).chain(self.recent_requests.forget(request_id)) {
+                self.pending_responses.remove(&id);
+            }
+            return;
+        };
+        if let Err(e) = self.respond_chat(request_id, response) {
+            tracing::debug!(parent: &span, "not responding to {request_id:?}: {e}");
+        }
+    }
+
+    /// Answers the chat request `request_id` along with the retries of it that came in while
+    /// it was handled, and keeps `response` for those still to come.
+    fn respond_chat(
+        &mut self,
+        request_id: RequestId,
+        response: ChatResponse,
+    ) -> anyhow::Result<()> {
+        let duplicates = self.recent_requests.answered(request_id, &response);
+        let channels: Vec<_> = std::iter::once(request_id)
+            .chain(duplicates)
+            .filter_map(|id| self.pending_responses.remove(&id))
+            .collect();
+        let chat = match self.swarm.behaviour_mut().chat.as_mut() {
+            Some(chat) if !channels.is_empty() => chat,
+            _ => return Err(anyhow::anyhow!("unknown chat request {request_id:?}")),
+        };
+        let mut sent = false;
+        for channel in channels {
+            sent |= chat.send_response(channel, Ok(response.clone())).is_ok();
+        }
+        if !sent {
+            return Err(anyhow::anyhow!("connection to requester closed"));
+        }
+        Ok(())
+    }
+}
+
+/// Fails for a response the codec would refuse to write, measured as the encoded frame.
+fn check_response_size(response: &ChatResponse, max: usize) -> Result<(), MessageTooLarge> {
+    let size = response.0.frame_size();
+    if size > max {
+        return Err(MessageTooLarge { size, max });
+    }
+    Ok(())
+}
+
+/// Cheap, cloneable access to a running [`Node`].
+#[derive(Debug, Clone)]
+pub struct NodeHandle {
+    local_peer_id: PeerId,
+    command_sender: mpsc::Sender<Command>,
+    event_sender: broadcast::Sender<NodeEvent>,
+    bandwidth: Bandwidth,
+}
+
+impl NodeHandle {
+    pub fn local_peer_id(&self) -> PeerId {
+        self.local_peer_id
+    }
+
+    pub async fn dial(&self, address: Multiaddr) -> anyhow::Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::Dial { address, sender }).await?;
+        receiver.await?
+    }
+
+    pub async fn listen_on(&self, address: Multiaddr) -> anyhow::Result<ListenerId> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::ListenOn { address, sender }).await?;
+        receiver.await?
+    }
+
+    pub async fn add_external_address(&self, address: Multiaddr) -> anyhow::Result<()> {
+        self.send(Command::AddExternalAddress { address }).await
+    }
+
+    pub async fn add_chat_address(&self, peer: PeerId, address: Multiaddr) -> anyhow::Result<()> {
+        self.send(Command::AddChatAddress { peer, address }).await
+    }
+
+    /// Sends `request` to `peer` and resolves once the response, a failure or the request
+    /// timeout arrives. Should the connection close under the request, it is sent once more if
+    /// the node was built [`with_chat_retry`](Node::with_chat_retry) and `peer` was identified as
+    /// speaking `/chat/0.2.0`. Peers tell a retry by the envelope id.
+    pub async fn send_chat(
+        &self,
+        peer: PeerId,
+        request: ChatRequest,
+    ) -> Result<ChatResponse, ChatError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::SendChat {
+            peer,
+            request,
+            sender,
+        })
+        .await
+        .map_err(|_| ChatError::NodeStopped)?;
+        receiver.await.map_err(|_| ChatError::NodeStopped)?
+    }
+
+    /// Answers an inbound [`NodeEvent::ChatRequest`].
+    pub async fn respond_chat(
+        &self,
+        request_id: RequestId,
+        response: ChatResponse,
+    ) -> anyhow::Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::RespondChat {
+            request_id,
+            response,
+            sender,
+        })
+        .await?;
+        receiver.await?
+    }
+
+    pub async fn peers(&self) -> anyhow::Result<Vec<PeerId>> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::Peers { sender }).await?;
+        Ok(receiver.await?)
+    }
+
+    pub async fn remove_listener(&self, id: ListenerId) -> anyhow::Result<bool> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::RemoveListener { id, sender }).await?;
+        Ok(receiver.await?)
+    }
+
+    /// Measures the round trip time to `peer`, dialing it first if necessary.
+    pub async fn ping(&self, peer: PeerId) -> anyhow::Result<Duration> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::Ping { peer, sender }).await?;
+        tokio::time::timeout(PING_TIMEOUT, receiver)
+            .await
+            .map_err(|_| anyhow::anyhow!("no ping from {peer} within {PING_TIMEOUT:?}"))??
+    }
+
+    /// Looks up the value stored under `key` in the DHT.
+    pub async fn kad_get(&self, key: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::KadGet { key, sender }).await?;
+        receiver.await?
+    }
+
+    /// Stores `value` under `key` locally and on at least one other peer.
+    pub async fn kad_put(&self, key: Vec<u8>, value: Vec<u8>) -> anyhow::Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::KadPut { key, value, sender }).await?;
+        receiver.await?
+    }
+
+    pub async fn kad_providers(&self, key: Vec<u8>) -> anyhow::Result<Vec<PeerId>> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::KadProviders { key, sender }).await?;
+        receiver.await?
+    }
+
+    /// Live reservations, open circuits and denials of the relay server.
+    pub async fn relay_status(&self) -> anyhow::Result<RelayStatus> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::RelayStatus { sender }).await?;
+        Ok(receiver.await?)
+    }
+
+    /// Keeps a reservation on the relay at `address`, which has to end in `/p2p/<peer id>`,
+    /// listening through it and reserving again whenever the reservation is lost. The circuit
+    /// addresses gained are reported by [`NodeEvent::CircuitAddressesChanged`].
+    pub async fn reserve(&self, address: Multiaddr) -> anyhow::Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::Reserve { address, sender }).await?;
+        receiver.await?
+    }
+
+    /// Stops listening through the relay at `address`, returning whether it was reserved on.
+    pub async fn unreserve(&self, address: Multiaddr) -> anyhow::Result<bool> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::Unreserve { address, sender }).await?;
+        Ok(receiver.await?)
+    }
+
+    /// Keeps the node connected to the peer at `address`, which has to end in
+    /// `/p2p/<peer id>`, dialing it again whenever the connection is lost. How the connection
+    /// is doing is reported by [`NodeEvent::ConnectionHealth`].
+    pub async fn pin(&self, address: Multiaddr) -> anyhow::Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::Pin { address, sender }).await?;
+        receiver.await?
+    }
+
+    /// Stops redialing `peer`, returning whether it was pinned.
+    pub async fn unpin(&self, peer: PeerId) -> anyhow::Result<bool> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::Unpin { peer, sender }).await?;
+        Ok(receiver.await?)
+    }
+
+    /// Connects to `peer` at the addresses learned from identify, kademlia and chat: directly
+    /// first, then through the relays reserved on or known to the peer, and once relayed lets
+    /// DCUtR try a direct connection. A peer without known addresses is looked up with
+    /// kademlia first. Each step has its own timeout, see
+    /// [`Node::with_dial_timeouts`], and [`NodeEvent::PeerReached`] explains which one worked.
+    pub async fn connect(&self, peer: PeerId) -> anyhow::Result<DialPath> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::Connect {
+            peer,
+            hole_punch: true,
+            sender,
+        })
+        .await?;
+        receiver.await?
+    }
+
+    /// Like [`NodeHandle::connect`], but resolves as [`DialPath::Relayed`] as soon as the peer
+    /// is reached through a relay, while DCUtR goes on in the background.
+    pub async fn reach(&self, peer: PeerId) -> anyhow::Result<DialPath> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::Connect {
+            peer,
+            hole_punch: false,
+            sender,
+        })
+        .await?;
+        receiver.await?
+    }
+
+    /// Presents a voucher to the relay at `address`, which has to end in `/p2p/<peer id>`, and
+    /// resolves with its expiry once the relay accepted it.
+    pub async fn present_voucher(
+        &self,
+        address: Multiaddr,
+        envelope: Vec<u8>,
+    ) -> anyhow::Result<u64> {
+        let relay = PeerId::try_from_multiaddr(&address)
+            .ok_or_else(|| anyhow::anyhow!("relay address {address} has no peer id"))?;
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::PresentVoucher {
+            relay,
+            address,
+            envelope,
+            sender,
+        })
+        .await?;
+        receiver.await?
+    }
+
+    /// Bytes exchanged so far, per peer and negotiated protocol.
+    pub fn bandwidth(&self) -> Vec<Traffic> {
+        self.bandwidth.snapshot()
+    }
+
+    /// Subscribes to node events. Subscribers that fall behind skip the events they missed.
+    pub fn events(&self) -> impl FusedStream<Item = NodeEvent> + Send + Unpin + 'static {
+        let receiver = self.event_sender.subscribe();
+        Box::pin(stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("node event subscriber lagged, skipped {skipped} events");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }))
+    }
+
+    async fn send(&self, command: Command) -> anyhow::Result<()> {
+        self.command_sender
+            .send(command)
+            .await
+            .map_err(|_| anyhow::anyhow!("node has stopped"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use codec::chat::ChatEnvelope;
+    use libp2p::core::multiaddr::Protocol;
+    use libp2p::core::transport::MemoryTransport;
+
+    /// Echoes requests, failing those that say `fail`.
+    struct FailingEcho;
+
+    #[async_trait]
+    impl ChatHandler for FailingEcho {
+        async fn handle(
+            &self,
+            _peer: PeerId,
+            request: ChatRequest,
+        ) -> anyhow::Result<ChatResponse> {
+            if request.data() == b"fail" {
+                anyhow::bail!("told to fail");
+            }
+            Ok(ChatResponse(request.0.reply(request.data().clone())))
+        }
+
+        fn on_error(&self, _peer: PeerId, error: anyhow::Error) -> Option<ChatResponse> {
+            Some(ChatResponse::from(format!("error: {error}").into_bytes()))
+        }
+    }
+
+    /// A node on the memory transport listening at the address returned, answering chat
+    /// requests with `handler` if there is one.
+    async fn node(
+        config: BehaviourConfig,
+        handler: Option<FailingEcho>,
+    ) -> (NodeHandle, Multiaddr) {
+        let keypair = Keypair::generate_ed25519();
+        let chat_codec = config.chat_codec();
+        let bandwidth = config.bandwidth.clone();
+        let swarm = Behaviour::with_transport(&keypair, config, MemoryTransport::default())
+            .await
+            .unwrap();
+        let (mut node, handle) = Node::with_swarm(swarm, chat_codec, bandwidth);
+        if let Some(handler) = handler {
+            node = node.with_chat_handler(handler);
+        }
+        node.start();
+        let address = Multiaddr::empty().with(Protocol::Memory(rand::random::<u64>() >> 1));
+        handle.listen_on(address.clone()).await.unwrap();
+        (handle, address)
+    }
+
+    fn config() -> BehaviourConfig {
+        BehaviourConfig {
+            kad: false,
+            ..Default::default()
+        }
+    }
+
+    /// A node sending to a node answering with [`FailingEcho`], whose handle keeps it running.
+    async fn pair(config: BehaviourConfig) -> (NodeHandle, NodeHandle) {
+        let (server, address) = node(super::tests::config(), Some(FailingEcho)).await;
+        let (client, _) = node(config, None).await;
+        client
+            .add_chat_address(server.local_peer_id(), address)
+            .await
+            .unwrap();
+        (client, server)
+    }
+
+    async fn within<T>(future: impl std::future::Future<Output = T>) -> T {
+        time::timeout(Duration::from_secs(10), future)
+            .await
+            .expect("timed out")
+    }
+
+    #[tokio::test]
+    async fn chat_requests_get_the_handlers_response() {
+        let (client, server) = pair(config()).await;
+        let peer = server.local_peer_id();
+        let request = ChatEnvelope::text("hello");
+        let response = within(client.send_chat(peer, ChatRequest(request.clone())))
+            .await
+            .unwrap();
+        assert_eq!(response.data(), b"hello");
+        assert_eq!(response.0.reply_to, Some(request.id));
+
+        let response = within(client.send_chat(peer, ChatRequest::from(b"fail".to_vec())))
+            .await
+            .unwrap();
+        assert_eq!(response.data(), b"error: told to fail");
+    }
+
+    #[tokio::test]
+    async fn chat_failures_are_reported() {
+        let (client, server) = pair(BehaviourConfig {
+            chat_max_request_size: 1024,
+            chat_max_response_size: 256,
+            ..config()
+        })
+        .await;
+        let peer = server.local_peer_id();
+        let error = within(client.send_chat(peer, ChatRequest::from(vec![0; 2048])))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            ChatError::RequestTooLarge { max: 1024, .. }
+        ));
+        // Echoed back larger than the client takes.
+        let error = within(client.send_chat(peer, ChatRequest::from(vec![0; 512])))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            ChatError::ResponseTooLarge { max: 256, .. }
+        ));
+
+        let error = within(client.send_chat(PeerId::random(), ChatRequest::from(Vec::new())))
+            .await
+            .unwrap_err();
+        assert_eq!(error, ChatError::DialFailure);
+    }
+
+    #[tokio::test]
+    async fn chat_requests_are_answered_once() {
+        let (server, address) = node(config(), None).await;
+        let (client, _) = node(config(), None).await;
+        let peer = server.local_peer_id();
+        client.add_chat_address(peer, address).await.unwrap();
+        let mut events = server.events();
+        let sent = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client
+                    .send_chat(peer, ChatRequest::from(b"hi".to_vec()))
+                    .await
+            }
+        });
+        let (request_id, request) = within(async {
+            loop {
+                if let NodeEvent::ChatRequest {
+                    request_id,
+                    request,
+                    ..
+                } = events.select_next_some().await
+                {
+                    return (request_id, request);
+                }
+            }
+        })
+        .await;
+        assert_eq!(request.data(), b"hi");
+
+        let response = ChatResponse(request.0.reply(b"ho".to_vec()));
+        server
+            .respond_chat(request_id, response.clone())
+            .await
+            .unwrap();
+        let answer = within(sent).await.unwrap().unwrap();
+        assert_eq!(answer.data(), b"ho");
+        assert!(server.respond_chat(request_id, response).await.is_err());
+    }
+}
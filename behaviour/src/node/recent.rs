@@ -0,0 +1,249 @@
+use codec::chat::ChatResponse;
+use libp2p::request_response::RequestId;
+use libp2p::PeerId;
+use std::collections::{HashMap, VecDeque};
+
+/// Answered chat requests kept per peer by the id of their envelope.
+const ANSWERED_PER_PEER: usize = 32;
+/// Peers whose requests are kept.
+const MAX_PEERS: usize = 256;
+
+/// The chat requests received lately, so that a request sent again after its connection
+/// closed is answered instead of handled a second time.
+///
+/// Only answered requests are ever evicted, each peer's oldest first, and peers with none
+/// pending least recently heard from first. Pending ones are bounded by the requests in flight.
+///
+/// Requests from `/chat/0.1.0` peers carry no id, theirs are assigned on receipt and never
+/// match.
+#[derive(Debug, Default)]
+pub(crate) struct RecentRequests {
+    peers: HashMap<PeerId, PeerRequests>,
+    /// The keys of `peers`, least recently heard from first.
+    order: VecDeque<PeerId>,
+    /// The keys of the requests still waiting for their response.
+    pending: HashMap<RequestId, (PeerId, String)>,
+}
+
+#[derive(Debug, Default)]
+struct PeerRequests {
+    requests: HashMap<String, Seen>,
+    /// The ids of the answered `requests`, oldest first.
+    answered: VecDeque<String>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Seen {
+    /// Still being handled as `request_id`, to be answered along with `duplicates`.
+    Pending {
+        request_id: RequestId,
+        duplicates: Vec<RequestId>,
+    },
+    Answered(ChatResponse),
+}
+
+impl RecentRequests {
+    /// Remembers the request with envelope `id` from `peer`, or hands back how far the one it
+    /// repeats got. A pending one is answered along with `request_id` from then on.
+    pub(crate) fn received(
+        &mut self,
+        peer: PeerId,
+        id: &str,
+        request_id: RequestId,
+    ) -> Option<Seen> {
+        self.touch(peer);
+        let requests = &mut self.peers.entry(peer).or_default().requests;
+        if let Some(seen) = requests.get_mut(id) {
+            if let Seen::Pending { duplicates, .. } = seen {
+                duplicates.push(request_id);
+            }
+            return Some(seen.clone());
+        }
+        requests.insert(
+            id.to_string(),
+            Seen::Pending {
+                request_id,
+                duplicates: Vec::new(),
+            },
+        );
+        self.pending.insert(request_id, (peer, id.to_string()));
+        None
+    }
+
+    /// Keeps `response` for retries of `request_id`, returning the retries that came in while
+    /// it was handled.
+    pub(crate) fn answered(
+        &mut self,
+        request_id: RequestId,
+        response: &ChatResponse,
+    ) -> Vec<RequestId> {
+        let Some((peer, id)) = self.pending.remove(&request_id) else {
+            return Vec::new();
+        };
+        let Some(requests) = self.peers.get_mut(&peer) else {
+            return Vec::new();
+        };
+        requests.answered.push_back(id.clone());
+        if requests.answered.len() > ANSWERED_PER_PEER {
+            if let Some(oldest) = requests.answered.pop_front() {
+                requests.requests.remove(&oldest);
+            }
+        }
+        match requests
+            .requests
+            .insert(id, Seen::Answered(response.clone()))
+        {
+            Some(Seen::Pending { duplicates, .. }) => duplicates,
+            _ => Vec::new(),
+        }
+    }
+
+    /// Forgets `request_id`, which got no response, so that a retry is handled anew,
+    /// returning the retries that came in while it was handled.
+    pub(crate) fn forget(&mut self, request_id: RequestId) -> Vec<RequestId> {
+        let Some((peer, id)) = self.pending.remove(&request_id) else {
+            return Vec::new();
+        };
+        let Some(requests) = self.peers.get_mut(&peer) else {
+            return Vec::new();
+        };
+        let seen = requests.requests.remove(&id);
+        if requests.requests.is_empty() {
+            self.peers.remove(&peer);
+            self.order.retain(|p| *p != peer);
+        }
+        match seen {
+            Some(Seen::Pending { duplicates, .. }) => duplicates,
+            _ => Vec::new(),
+        }
+    }
+
+    /// Marks `peer` as the one heard from last, making room for it if it is new.
+    fn touch(&mut self, peer: PeerId) {
+        if self.peers.contains_key(&peer) {
+            self.order.retain(|p| *p != peer);
+        } else if self.peers.len() >= MAX_PEERS {
+            let peers = &self.peers;
+            let idle = self.order.iter().position(|p| {
+                peers[p]
+                    .requests
+                    .values()
+                    .all(|seen| matches!(seen, Seen::Answered(_)))
+            });
+            if let Some(idle) = idle.and_then(|n| self.order.remove(n)) {
+                self.peers.remove(&idle);
+            }
+        }
+        self.order.push_back(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec::chat::{ChatCodec, ChatProtocol, ChatRequest};
+    use libp2p::request_response::{Behaviour, Config, ProtocolSupport};
+
+    /// Request ids can only be had from a behaviour sending requests.
+    fn request_ids(n: usize) -> Vec<RequestId> {
+        let protocols = ChatProtocol::all()
+            .into_iter()
+            .map(|protocol| (protocol, ProtocolSupport::Full));
+        let mut behaviour = Behaviour::new(ChatCodec::default(), protocols, Config::default());
+        let peer = PeerId::random();
+        (0..n)
+            .map(|_| behaviour.send_request(&peer, ChatRequest::from(Vec::new())))
+            .collect()
+    }
+
+    fn duplicates(seen: Option<Seen>) -> Vec<RequestId> {
+        match seen {
+            Some(Seen::Pending { duplicates, .. }) => duplicates,
+            seen => panic!("not pending: {seen:?}"),
+        }
+    }
+
+    #[test]
+    fn retries_are_answered_with_the_first_request() {
+        let ids = request_ids(4);
+        let peer = PeerId::random();
+        let mut recent = RecentRequests::default();
+        assert!(recent.received(peer, "a", ids[0]).is_none());
+        assert_eq!(duplicates(recent.received(peer, "a", ids[1])), vec![ids[1]]);
+        // Ids are per peer.
+        assert!(recent.received(PeerId::random(), "a", ids[2]).is_none());
+
+        let response = ChatResponse::from(b"pong".to_vec());
+        assert_eq!(recent.answered(ids[0], &response), vec![ids[1]]);
+        match recent.received(peer, "a", ids[3]) {
+            Some(Seen::Answered(answer)) => assert_eq!(answer.data(), response.data()),
+            seen => panic!("not answered: {seen:?}"),
+        }
+        assert!(recent.answered(ids[0], &response).is_empty());
+    }
+
+    #[test]
+    fn forgotten_requests_are_handled_anew() {
+        let ids = request_ids(3);
+        let peer = PeerId::random();
+        let mut recent = RecentRequests::default();
+        recent.received(peer, "a", ids[0]);
+        recent.received(peer, "a", ids[1]);
+        assert_eq!(recent.forget(ids[0]), vec![ids[1]]);
+        assert!(recent.received(peer, "a", ids[2]).is_none());
+        assert_eq!(recent.peers[&peer].requests.len(), 1);
+    }
+
+    #[test]
+    fn pending_requests_are_never_evicted() {
+        let ids = request_ids(ANSWERED_PER_PEER + 3);
+        let peer = PeerId::random();
+        let mut recent = RecentRequests::default();
+        recent.received(peer, "pending", ids[0]);
+        let response = ChatResponse::from(Vec::new());
+        for (n, id) in ids[1..=ANSWERED_PER_PEER + 1].iter().enumerate() {
+            assert!(recent.received(peer, &n.to_string(), *id).is_none());
+            recent.answered(*id, &response);
+        }
+        let requests = &recent.peers[&peer].requests;
+        assert_eq!(requests.len(), ANSWERED_PER_PEER + 1);
+        // The oldest answer made way, the pending request still collects its retries.
+        assert!(!requests.contains_key("0"));
+        let retry = ids[ANSWERED_PER_PEER + 2];
+        assert_eq!(
+            duplicates(recent.received(peer, "pending", retry)),
+            vec![retry]
+        );
+        assert_eq!(recent.answered(ids[0], &response), vec![retry]);
+    }
+
+    #[test]
+    fn peers_are_bounded_separately() {
+        let ids = request_ids(ANSWERED_PER_PEER + MAX_PEERS + 3);
+        let (quiet, busy) = (PeerId::random(), PeerId::random());
+        let mut recent = RecentRequests::default();
+        let response = ChatResponse::from(Vec::new());
+        recent.received(quiet, "a", ids[0]);
+        recent.answered(ids[0], &response);
+        for (n, id) in ids[1..=ANSWERED_PER_PEER + 1].iter().enumerate() {
+            recent.received(busy, &n.to_string(), *id);
+            recent.answered(*id, &response);
+        }
+        assert!(matches!(
+            recent.received(quiet, "a", ids[0]),
+            Some(Seen::Answered(_))
+        ));
+
+        // New peers push out the ones least recently heard from with nothing pending.
+        let pending = PeerId::random();
+        recent.received(pending, "a", ids[ANSWERED_PER_PEER + 2]);
+        for id in &ids[ANSWERED_PER_PEER + 3..] {
+            recent.received(PeerId::random(), "a", *id);
+            recent.answered(*id, &response);
+        }
+        assert_eq!(recent.peers.len(), MAX_PEERS);
+        assert!(recent.peers.contains_key(&pending));
+        assert!(!recent.peers.contains_key(&busy));
+        assert!(!recent.peers.contains_key(&quiet));
+    }
+}
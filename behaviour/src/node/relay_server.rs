@@ -0,0 +1,756 @@
+use crate::address;
+use crate::audit::{AuditEvent, AuditLog, AuditRecord};
+use crate::bandwidth::Bandwidth;
+use crate::relay_acl::Acl;
+use crate::relay_config::{LimiterState, RelayConfig, RelayRequest};
+use codec::voucher::{VoucherRequest, VoucherResponse};
+use libp2p::core::ConnectedPoint;
+use libp2p::{relay, PeerId};
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::{Registry, Unit};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// Protocol of the substream a relay opens to the destination of a circuit.
+pub const STOP_PROTOCOL: &str = "/libp2p/circuit/relay/0.2.0/stop";
+
+/// What a relay server is serving right now, see [`NodeHandle::relay_status`].
+///
+/// [`NodeHandle::relay_status`]: super::NodeHandle::relay_status
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RelayStatus {
+    pub reservations: Vec<ReservationStatus>,
+    pub circuits: Vec<CircuitStatus>,
+    pub denials: Vec<DenialCount>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReservationStatus {
+    pub peer: PeerId,
+    pub expires_in_secs: u64,
+    pub renewals: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CircuitStatus {
+    pub src: PeerId,
+    pub dst: PeerId,
+    /// Bytes relayed to and from `dst` since the circuit opened, shared between concurrent
+    /// circuits to the same destination.
+    pub bytes: u64,
+    pub duration_secs: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DenialCount {
+    /// `reservation` or `circuit`.
+    pub request: &'static str,
+    /// The config key of the limit or the ACL rule that refused the request. Requests over
+    /// `max_reservations` or `max_reservations_per_peer` are refused by libp2p before any
+    /// limiter runs and count as `reservation_limit`, those of circuits as `circuit_limit`.
+    pub reason: &'static str,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DenialLabels {
+    request: &'static str,
+    reason: &'static str,
+}
+
+/// Relay server metrics beyond the per-event counts of `libp2p::metrics`.
+#[derive(Debug, Clone)]
+pub struct RelayMetrics {
+    reservations: Gauge,
+    circuits: Gauge,
+    denials: Family<DenialLabels, Counter>,
+    circuit_bytes: Counter,
+    circuit_duration: Histogram,
+}
+
+impl RelayMetrics {
+    pub fn new(registry: &mut Registry) -> Self {
+        let registry = registry.sub_registry_with_prefix("p2p_relay");
+        let metrics = Self {
+            reservations: Gauge::default(),
+            circuits: Gauge::default(),
+            denials: Family::default(),
+            circuit_bytes: Counter::default(),
+            circuit_duration: Histogram::new(exponential_buckets(1.0, 2.0, 10)),
+        };
+        registry.register(
+            "reservations",
+            "Live reservations",
+            metrics.reservations.clone(),
+        );
+        registry.register("circuits", "Open circuits", metrics.circuits.clone());
+        registry.register(
+            "denials",
+            "Denied reservation and circuit requests by reason",
+            metrics.denials.clone(),
+        );
+        registry.register_with_unit(
+            "circuit",
+            "Approximate bytes relayed by closed circuits",
+            Unit::Bytes,
+            metrics.circuit_bytes.clone(),
+        );
+        registry.register_with_unit(
+            "circuit_duration",
+            "Lifetime of closed circuits",
+            Unit::Seconds,
+            metrics.circuit_duration.clone(),
+        );
+        metrics
+    }
+}
+
+#[derive(Debug)]
+struct Reservation {
+    expires: Instant,
+    renewals: u32,
+}
+
+#[derive(Debug)]
+struct Circuit {
+    src: PeerId,
+    dst: PeerId,
+    /// Where `src` connected from, kept since it may disconnect before the circuit closes.
+    src_ip: Option<IpAddr>,
+    opened: Instant,
+    /// Stop protocol traffic with `dst` when the circuit opened.
+    baseline: u64,
+}
+
+/// Follows the reservations and circuits of a relay server from its events.
+#[derive(Debug)]
+pub(crate) struct RelayState {
+    /// The limits the relay was built with.
+    config: RelayConfig,
+    /// What the relay's limiters share with us.
+    limiters: LimiterState,
+    acl: Acl,
+    bandwidth: Bandwidth,
+    reservations: HashMap<PeerId, Reservation>,
+    circuits: Vec<Circuit>,
+    denials: BTreeMap<(&'static str, &'static str), u64>,
+    /// Addresses of the peers connected directly, for the audit log.
+    peer_ips: HashMap<PeerId, IpAddr>,
+    /// Reservation holders the ACL no longer admits, for the node to disconnect.
+    revoked: Vec<PeerId>,
+    metrics: Option<RelayMetrics>,
+    audit: Option<AuditLog>,
+}
+
+impl RelayState {
+    pub(crate) fn new(config: RelayConfig, limiters: LimiterState, bandwidth: Bandwidth) -> Self {
+        Self {
+            acl: limiters.acl(&config.acl),
+            config,
+            limiters,
+            bandwidth,
+            reservations: HashMap::new(),
+            circuits: Vec::new(),
+            denials: BTreeMap::new(),
+            peer_ips: HashMap::new(),
+            revoked: Vec::new(),
+            metrics: None,
+            audit: None,
+        }
+    }
+
+    /// Replaces the limits, revoking the reservations the new ACL refuses.
+    pub(crate) fn set_config(&mut self, config: RelayConfig, limiters: LimiterState) {
+        self.acl = limiters.acl(&config.acl);
+        self.config = config;
+        self.limiters = limiters;
+        self.revoke_refused();
+    }
+
+    pub(crate) fn set_metrics(&mut self, metrics: RelayMetrics) {
+        self.metrics = Some(metrics);
+    }
+
+    pub(crate) fn set_audit(&mut self, audit: AuditLog) {
+        self.audit = Some(audit);
+    }
+
+    /// Updates the state and returns the reason of a denial.
+    pub(crate) fn on_event(&mut self, event: &relay::Event) -> Option<&'static str> {
+        let reason = match event {
+            relay::Event::ReservationReqAccepted {
+                src_peer_id,
+                renewed,
+            } => {
+                self.decision(src_peer_id, RelayRequest::Reservation);
+                let expires = Instant::now() + self.config.reservation_duration();
+                self.reservations
+                    .entry(*src_peer_id)
+                    .and_modify(|reservation| {
+                        reservation.expires = expires;
+                        reservation.renewals += u32::from(*renewed);
+                    })
+                    .or_insert(Reservation {
+                        expires,
+                        renewals: 0,
+                    });
+                self.audit(AuditEvent::ReservationAccepted, src_peer_id, |_| {});
+                None
+            }
+            relay::Event::ReservationTimedOut { src_peer_id } => {
+                self.reservations.remove(src_peer_id);
+                self.limiters.ip_reservations.release(src_peer_id);
+                None
+            }
+            relay::Event::ReservationReqDenied { src_peer_id } => {
+                let reason = match self.decision(src_peer_id, RelayRequest::Reservation) {
+                    Some(Err(reason)) => reason,
+                    None => "reservation_limit",
+                    Some(Ok(())) => "unknown",
+                };
+                self.release_unreserved(src_peer_id);
+                self.audit(AuditEvent::ReservationDenied, src_peer_id, |record| {
+                    record.reason = Some(reason.to_string());
+                });
+                Some(self.deny("reservation", reason))
+            }
+            relay::Event::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            } => {
+                self.decision(src_peer_id, RelayRequest::Circuit);
+                let dst_ip = self.peer_ips.get(dst_peer_id).copied();
+                match self.acl.check(*dst_peer_id, dst_ip, true) {
+                    Ok(()) => {
+                        let baseline = self.stop_traffic(dst_peer_id);
+                        self.circuits.push(Circuit {
+                            src: *src_peer_id,
+                            dst: *dst_peer_id,
+                            src_ip: self.peer_ips.get(src_peer_id).copied(),
+                            opened: Instant::now(),
+                            baseline,
+                        });
+                        self.audit(AuditEvent::CircuitOpened, src_peer_id, |record| {
+                            record.dst = Some(*dst_peer_id);
+                        });
+                        None
+                    }
+                    Err(reason) => {
+                        // The destination stopped passing since the last sweep. libp2p cannot
+                        // refuse it, so the circuit ends along with its reservation.
+                        self.audit(AuditEvent::CircuitOpened, src_peer_id, |record| {
+                            record.dst = Some(*dst_peer_id);
+                        });
+                        self.audit(AuditEvent::CircuitClosed, src_peer_id, |record| {
+                            record.dst = Some(*dst_peer_id);
+                            record.bytes = Some(0);
+                            record.duration_ms = Some(0);
+                            record.reason = Some("acl_revoked".to_string());
+                        });
+                        self.revoke(*dst_peer_id, reason);
+                        None
+                    }
+                }
+            }
+            relay::Event::CircuitReqDenied {
+                src_peer_id,
+                dst_peer_id,
+            } => {
+                let reason = match self.decision(src_peer_id, RelayRequest::Circuit) {
+                    Some(Err(reason)) => reason,
+                    None => "circuit_limit",
+                    // The destination is only looked up once the limiters let a request through.
+                    Some(Ok(())) if !self.reservations.contains_key(dst_peer_id) => {
+                        "no_reservation"
+                    }
+                    Some(Ok(())) => "unknown",
+                };
+                self.audit(AuditEvent::CircuitDenied, src_peer_id, |record| {
+                    record.dst = Some(*dst_peer_id);
+                    record.reason = Some(reason.to_string());
+                });
+                Some(self.deny("circuit", reason))
+            }
+            relay::Event::ReservationReqAcceptFailed { src_peer_id, .. }
+            | relay::Event::ReservationReqDenyFailed { src_peer_id, .. } => {
+                self.decision(src_peer_id, RelayRequest::Reservation);
+                self.release_unreserved(src_peer_id);
+                None
+            }
+            relay::Event::CircuitReqAcceptFailed { src_peer_id, .. }
+            | relay::Event::CircuitReqDenyFailed { src_peer_id, .. } => {
+                self.decision(src_peer_id, RelayRequest::Circuit);
+                None
+            }
+            relay::Event::CircuitClosed {
+                src_peer_id,
+                dst_peer_id,
+                error,
+            } => {
+                let position = self
+                    .circuits
+                    .iter()
+                    .position(|c| c.src == *src_peer_id && c.dst == *dst_peer_id);
+                if let Some(circuit) = position.map(|i| self.circuits.remove(i)) {
+                    let bytes = self
+                        .stop_traffic(&circuit.dst)
+                        .saturating_sub(circuit.baseline);
+                    let duration = circuit.opened.elapsed();
+                    if let Some(metrics) = &self.metrics {
+                        metrics.circuit_bytes.inc_by(bytes);
+                        metrics.circuit_duration.observe(duration.as_secs_f64());
+                    }
+                    self.audit(AuditEvent::CircuitClosed, src_peer_id, |record| {
+                        record.src_ip = circuit.src_ip;
+                        record.dst = Some(*dst_peer_id);
+                        record.bytes = Some(bytes);
+                        record.duration_ms =
+                            Some(duration.as_millis().try_into().unwrap_or(u64::MAX));
+                        record.reason = Some(match error {
+                            Some(error) => error.to_string(),
+                            None => "closed".to_string(),
+                        });
+                    });
+                }
+                None
+            }
+            _ => None,
+        };
+        self.update_gauges();
+        reason
+    }
+
+    /// Peers to disconnect since the ACL refused them after they reserved.
+    pub(crate) fn take_revoked(&mut self) -> Vec<PeerId> {
+        std::mem::take(&mut self.revoked)
+    }
+
+    /// When the first voucher of a reservation holder expires, for the node to call
+    /// [`revoke_refused`](Self::revoke_refused) then.
+    pub(crate) fn next_revocation(&self) -> Option<Instant> {
+        let now = Instant::now();
+        self.reservations
+            .keys()
+            .filter_map(|peer| self.acl.voucher_expires_in(peer))
+            .min()
+            .map(|expires_in| now + expires_in)
+    }
+
+    /// Revokes the reservations of the peers the ACL no longer admits.
+    pub(crate) fn revoke_refused(&mut self) {
+        let refused: Vec<_> = self
+            .reservations
+            .keys()
+            .filter_map(|peer| {
+                let ip = self.peer_ips.get(peer).copied();
+                self.acl
+                    .check(*peer, ip, true)
+                    .err()
+                    .map(|reason| (*peer, reason))
+            })
+            .collect();
+        for (peer, reason) in refused {
+            self.revoke(peer, reason);
+        }
+        self.update_gauges();
+    }
+
+    fn revoke(&mut self, peer: PeerId, reason: &'static str) {
+        self.reservations.remove(&peer);
+        self.revoked.push(peer);
+        self.audit(AuditEvent::ReservationRevoked, &peer, |record| {
+            record.reason = Some(reason.to_string());
+        });
+    }
+
+    /// Answers a voucher presented by `peer`.
+    pub(crate) fn on_voucher(&self, peer: PeerId, request: VoucherRequest) -> VoucherResponse {
+        match self.acl.accept_voucher(peer, &request.envelope) {
+            Ok(expires) => VoucherResponse::Accepted { expires },
+            Err(e) => VoucherResponse::Rejected {
+                reason: e.to_string(),
+            },
+        }
+    }
+
+    pub(crate) fn on_connected(&mut self, peer: PeerId, endpoint: &ConnectedPoint) {
+        if endpoint.is_relayed() {
+            return;
+        }
+        if let Some(ip) = address::ip(endpoint.get_remote_address()) {
+            self.peer_ips.insert(peer, ip);
+        }
+    }
+
+    /// The relay drops the reservations of a peer along with its last connection.
+    pub(crate) fn on_disconnected(&mut self, peer: &PeerId) {
+        self.peer_ips.remove(peer);
+        self.limiters.ip_reservations.release(peer);
+        if self.reservations.remove(peer).is_some() {
+            self.update_gauges();
+        }
+    }
+
+    pub(crate) fn status(&self) -> RelayStatus {
+        let now = Instant::now();
+        let mut reservations: Vec<ReservationStatus> = self
+            .reservations
+            .iter()
+            .map(|(peer, reservation)| ReservationStatus {
+                peer: *peer,
+                expires_in_secs: reservation.expires.saturating_duration_since(now).as_secs(),
+                renewals: reservation.renewals,
+            })
+            .collect();
+        reservations.sort_by_key(|reservation| reservation.peer);
+        let circuits = self
+            .circuits
+            .iter()
+            .map(|circuit| CircuitStatus {
+                src: circuit.src,
+                dst: circuit.dst,
+                bytes: self
+                    .stop_traffic(&circuit.dst)
+                    .saturating_sub(circuit.baseline),
+                duration_secs: circuit.opened.elapsed().as_secs_f64(),
+            })
+            .collect();
+        let denials = self
+            .denials
+            .iter()
+            .map(|(&(request, reason), &count)| DenialCount {
+                request,
+                reason,
+                count,
+            })
+            .collect();
+        RelayStatus {
+            reservations,
+            circuits,
+            denials,
+        }
+    }
+
+    /// Gives back the IP address slot the limiter took for a request of `peer` that did not
+    /// end in a reservation. Renewals keep the slot of the reservation they renew.
+    fn release_unreserved(&self, peer: &PeerId) {
+        if !self.reservations.contains_key(peer) {
+            self.limiters.ip_reservations.release(peer);
+        }
+    }
+
+    /// Takes what the limiters decided about the latest `request` of `peer`, once the relay
+    /// reported its outcome.
+    fn decision(&self, peer: &PeerId, request: RelayRequest) -> Option<Result<(), &'static str>> {
+        self.limiters.rejections.take(peer, request)
+    }
+
+    fn deny(&mut self, request: &'static str, reason: &'static str) -> &'static str {
+        *self.denials.entry((request, reason)).or_default() += 1;
+        if let Some(metrics) = &self.metrics {
+            metrics
+                .denials
+                .get_or_create(&DenialLabels { request, reason })
+                .inc();
+        }
+        reason
+    }
+
+    /// Writes a record about `src`, filled in by `fill`. Failures are logged rather than
+    /// stopping the relay.
+    fn audit(&mut self, event: AuditEvent, src: &PeerId, fill: impl FnOnce(&mut AuditRecord)) {
+        let Some(audit) = self.audit.as_mut() else {
+            return;
+        };
+        let mut record = AuditRecord::new(event, *src, self.peer_ips.get(src).copied());
+        fill(&mut record);
+        if let Err(e) = audit.write(&record) {
+            tracing::warn!("writing audit record failed: {e:#}");
+        }
+    }
+
+    fn stop_traffic(&self, dst: &PeerId) -> u64 {
+        let (inbound, outbound) = self.bandwidth.traffic(dst, STOP_PROTOCOL);
+        inbound + outbound
+    }
+
+    fn update_gauges(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.reservations.set(self.reservations.len() as i64);
+            metrics.circuits.set(self.circuits.len() as i64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit;
+    use crate::relay_acl::{AclConfig, Voucher};
+    use libp2p::identity::Keypair;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    fn state(name: &str, acl: AclConfig) -> (RelayState, LimiterState, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("relay-state-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+        let config = RelayConfig {
+            acl,
+            ..Default::default()
+        };
+        let limiters = LimiterState::default();
+        let mut state = RelayState::new(config, limiters.clone(), Bandwidth::default());
+        state.set_audit(AuditLog::open(path.clone(), u64::MAX, 1).unwrap());
+        (state, limiters, path)
+    }
+
+    fn records(path: &Path) -> Vec<AuditRecord> {
+        let mut records = Vec::new();
+        audit::scan(path, |record| {
+            records.push(record);
+            Ok(())
+        })
+        .unwrap();
+        records
+    }
+
+    fn events(records: &[AuditRecord]) -> Vec<AuditEvent> {
+        records.iter().map(|record| record.event).collect()
+    }
+
+    fn reserve(state: &mut RelayState, peer: PeerId, renewed: bool) {
+        let event = relay::Event::ReservationReqAccepted {
+            src_peer_id: peer,
+            renewed,
+        };
+        assert_eq!(state.on_event(&event), None);
+    }
+
+    #[test]
+    fn follows_reservations_and_circuits() {
+        let (mut state, _, path) = state("follow", AclConfig::default());
+        let (src, dst) = (PeerId::random(), PeerId::random());
+        reserve(&mut state, dst, false);
+        reserve(&mut state, dst, true);
+        let circuit = relay::Event::CircuitReqAccepted {
+            src_peer_id: src,
+            dst_peer_id: dst,
+        };
+        assert_eq!(state.on_event(&circuit), None);
+
+        let status = state.status();
+        assert_eq!(status.reservations.len(), 1);
+        assert_eq!(status.reservations[0].peer, dst);
+        assert_eq!(status.reservations[0].renewals, 1);
+        assert!(status.reservations[0].expires_in_secs > 0);
+        assert_eq!(status.circuits.len(), 1);
+        assert_eq!((status.circuits[0].src, status.circuits[0].dst), (src, dst));
+
+        let closed = relay::Event::CircuitClosed {
+            src_peer_id: src,
+            dst_peer_id: dst,
+            error: None,
+        };
+        state.on_event(&closed);
+        state.on_event(&relay::Event::ReservationTimedOut { src_peer_id: dst });
+        let status = state.status();
+        assert!(status.reservations.is_empty());
+        assert!(status.circuits.is_empty());
+        assert!(status.denials.is_empty());
+
+        let records = records(&path);
+        assert_eq!(
+            events(&records),
+            vec![
+                AuditEvent::ReservationAccepted,
+                AuditEvent::ReservationAccepted,
+                AuditEvent::CircuitOpened,
+                AuditEvent::CircuitClosed,
+            ]
+        );
+        let closed = &records[3];
+        assert_eq!((closed.src, closed.dst), (src, Some(dst)));
+        assert_eq!(closed.bytes, Some(0));
+        assert!(closed.duration_ms.is_some());
+        assert_eq!(closed.reason.as_deref(), Some("closed"));
+    }
+
+    #[test]
+    fn denials_carry_the_reason_decided() {
+        let (mut state, limiters, path) = state("denials", AclConfig::default());
+        let (peer, dst) = (PeerId::random(), PeerId::random());
+        let reservation_denied = relay::Event::ReservationReqDenied { src_peer_id: peer };
+        let circuit_denied = relay::Event::CircuitReqDenied {
+            src_peer_id: peer,
+            dst_peer_id: dst,
+        };
+
+        limiters.rejections.record(
+            peer,
+            RelayRequest::Reservation,
+            Err("max_reservations_per_ip"),
+        );
+        assert_eq!(
+            state.on_event(&reservation_denied),
+            Some("max_reservations_per_ip")
+        );
+        // Refused by libp2p before any limiter ran.
+        assert_eq!(
+            state.on_event(&reservation_denied),
+            Some("reservation_limit")
+        );
+        assert_eq!(state.on_event(&circuit_denied), Some("circuit_limit"));
+        limiters
+            .rejections
+            .record(peer, RelayRequest::Circuit, Ok(()));
+        assert_eq!(state.on_event(&circuit_denied), Some("no_reservation"));
+
+        let denials: Vec<_> = state
+            .status()
+            .denials
+            .iter()
+            .map(|denial| (denial.request, denial.reason, denial.count))
+            .collect();
+        assert_eq!(
+            denials,
+            vec![
+                ("circuit", "circuit_limit", 1),
+                ("circuit", "no_reservation", 1),
+                ("reservation", "max_reservations_per_ip", 1),
+                ("reservation", "reservation_limit", 1),
+            ]
+        );
+        let reasons: Vec<_> = records(&path)
+            .into_iter()
+            .map(|record| (record.event, record.dst, record.reason.unwrap()))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (
+                    AuditEvent::ReservationDenied,
+                    None,
+                    "max_reservations_per_ip".to_string()
+                ),
+                (
+                    AuditEvent::ReservationDenied,
+                    None,
+                    "reservation_limit".to_string()
+                ),
+                (
+                    AuditEvent::CircuitDenied,
+                    Some(dst),
+                    "circuit_limit".to_string()
+                ),
+                (
+                    AuditEvent::CircuitDenied,
+                    Some(dst),
+                    "no_reservation".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn reservations_refused_by_a_new_acl_are_revoked() {
+        let (mut state, limiters, path) = state("new-acl", AclConfig::default());
+        let (kept, refused) = (PeerId::random(), PeerId::random());
+        reserve(&mut state, kept, false);
+        reserve(&mut state, refused, false);
+        assert!(state.take_revoked().is_empty());
+
+        let config = RelayConfig {
+            acl: AclConfig {
+                deny_peers: vec![refused],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        state.set_config(config, limiters);
+        assert_eq!(state.take_revoked(), vec![refused]);
+        assert!(state.take_revoked().is_empty());
+        let status = state.status();
+        assert_eq!(status.reservations.len(), 1);
+        assert_eq!(status.reservations[0].peer, kept);
+
+        let revoked = records(&path).pop().unwrap();
+        assert_eq!(revoked.event, AuditEvent::ReservationRevoked);
+        assert_eq!(revoked.src, refused);
+        assert_eq!(revoked.reason.as_deref(), Some("denied_peer"));
+    }
+
+    #[test]
+    fn circuits_to_refused_destinations_open_and_close() {
+        let (src, dst) = (PeerId::random(), PeerId::random());
+        let acl = AclConfig {
+            deny_peers: vec![dst],
+            ..Default::default()
+        };
+        let (mut state, _, path) = state("refused-circuit", acl);
+        // Reserved before the peer was denied.
+        state.reservations.insert(
+            dst,
+            Reservation {
+                expires: Instant::now() + Duration::from_secs(60),
+                renewals: 0,
+            },
+        );
+        let circuit = relay::Event::CircuitReqAccepted {
+            src_peer_id: src,
+            dst_peer_id: dst,
+        };
+        assert_eq!(state.on_event(&circuit), None);
+        assert_eq!(state.take_revoked(), vec![dst]);
+        let status = state.status();
+        assert!(status.reservations.is_empty());
+        assert!(status.circuits.is_empty());
+        assert!(status.denials.is_empty());
+
+        let records = records(&path);
+        assert_eq!(
+            events(&records),
+            vec![
+                AuditEvent::CircuitOpened,
+                AuditEvent::CircuitClosed,
+                AuditEvent::ReservationRevoked,
+            ]
+        );
+        assert_eq!(records[1].dst, Some(dst));
+        assert_eq!(records[1].reason.as_deref(), Some("acl_revoked"));
+        assert_eq!(records[2].src, dst);
+        assert_eq!(records[2].reason.as_deref(), Some("denied_peer"));
+    }
+
+    #[test]
+    fn revocations_are_due_when_vouchers_expire() {
+        let issuer = Keypair::generate_ed25519();
+        let acl = AclConfig {
+            voucher_issuers: vec![issuer.public().to_peer_id()],
+            ..Default::default()
+        };
+        let (mut state, _, _) = state("voucher", acl);
+        let peer = PeerId::random();
+        assert_eq!(state.next_revocation(), None);
+
+        let voucher = Voucher::issue(&issuer, peer, Duration::from_secs(60)).unwrap();
+        assert!(matches!(
+            state.on_voucher(peer, VoucherRequest { envelope: voucher }),
+            VoucherResponse::Accepted { .. }
+        ));
+        // Only reservations are revoked.
+        assert_eq!(state.next_revocation(), None);
+        reserve(&mut state, peer, false);
+        let due = state.next_revocation().unwrap() - Instant::now();
+        assert!(due > Duration::from_secs(59) && due <= Duration::from_secs(61));
+        state.revoke_refused();
+        assert!(state.take_revoked().is_empty());
+    }
+}
@@ -0,0 +1,519 @@
+use super::backoff::Backoff;
+use super::NodeEvent;
+use crate::address;
+use crate::behaviour::Behaviour;
+use anyhow::bail;
+use codec::voucher::VoucherRequest;
+use libp2p::core::transport::ListenerId;
+use libp2p::request_response::RequestId;
+use libp2p::{Multiaddr, PeerId, Swarm};
+use std::collections::HashMap;
+use std::time::Instant;
+use tokio::sync::broadcast;
+
+/// Keeps a reservation on every relay the node was asked to listen through. The relay client
+/// closes the listener of a relay once its reservation failed, could not be renewed or the
+/// connection to the relay dropped, after which the relay is reserved on again with
+/// [`Backoff`]. Reserving waits for a connection to the relay, which is the supervisor's to
+/// dial, so that a dropped relay is not dialed by two backoff loops.
+pub(crate) struct Reservations {
+    relays: HashMap<PeerId, Relay>,
+    backoff: Backoff,
+    /// Presented to every relay before reserving on it.
+    voucher: Option<Vec<u8>>,
+    events: broadcast::Sender<NodeEvent>,
+}
+
+#[derive(Debug)]
+struct Relay {
+    address: Multiaddr,
+    phase: Phase,
+    /// Circuit addresses the node is reachable at through the relay.
+    addresses: Vec<Multiaddr>,
+    /// Attempts that failed since the last accepted reservation.
+    failures: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    PresentingVoucher(RequestId),
+    Listening(ListenerId),
+    Waiting(Instant),
+    /// Due, but waiting for the connection to the relay.
+    Connecting,
+}
+
+impl Reservations {
+    pub(crate) fn new(events: broadcast::Sender<NodeEvent>) -> Self {
+        Self {
+            relays: HashMap::new(),
+            backoff: Backoff::default(),
+            voucher: None,
+            events,
+        }
+    }
+
+    pub(crate) fn set_backoff(&mut self, backoff: Backoff) {
+        self.backoff = backoff;
+    }
+
+    pub(crate) fn set_voucher(&mut self, voucher: Vec<u8>) {
+        self.voucher = Some(voucher);
+    }
+
+    /// Starts reserving on the relay at `address`, which has to end in `/p2p/<peer id>`.
+    pub(crate) fn add(
+        &mut self,
+        swarm: &mut Swarm<Behaviour>,
+        address: Multiaddr,
+    ) -> anyhow::Result<()> {
+        let Some(peer) = PeerId::try_from_multiaddr(&address) else {
+            bail!("relay address {address} has no peer id");
+        };
+        if swarm.behaviour().relay_client.as_ref().is_none() {
+            bail!("the relay client is disabled");
+        }
+        if self.relays.contains_key(&peer) {
+            return Ok(());
+        }
+        let relay = Relay {
+            address,
+            phase: Phase::Connecting,
+            addresses: Vec::new(),
+            failures: 0,
+        };
+        self.relays.insert(peer, relay);
+        if swarm.is_connected(&peer) {
+            self.reserve(swarm, peer);
+        }
+        Ok(())
+    }
+
+    /// Gives up the reservation on the relay at `address`, returning whether there was one.
+    pub(crate) fn remove(&mut self, swarm: &mut Swarm<Behaviour>, address: &Multiaddr) -> bool {
+        let Some(relay) =
+            PeerId::try_from_multiaddr(address).and_then(|peer| self.relays.remove(&peer))
+        else {
+            return false;
+        };
+        if let Phase::Listening(id) = relay.phase {
+            swarm.remove_listener(id);
+        }
+        if !relay.addresses.is_empty() {
+            self.publish(swarm);
+        }
+        true
+    }
+
+    /// The addresses of all relays.
+    pub(crate) fn relays(&self) -> Vec<Multiaddr> {
+        self.relays
+            .values()
+            .map(|relay| relay.address.clone())
+            .collect()
+    }
+
+    /// The circuit addresses of all relays.
+    pub(crate) fn addresses(&self) -> Vec<Multiaddr> {
+        self.relays
+            .values()
+            .flat_map(|relay| relay.addresses.iter().cloned())
+            .collect()
+    }
+
+    /// When the next relay is due to be reserved on again.
+    pub(crate) fn next_retry(&self) -> Option<Instant> {
+        self.relays
+            .values()
+            .filter_map(|relay| match relay.phase {
+                Phase::Waiting(at) => Some(at),
+                _ => None,
+            })
+            .min()
+    }
+
+    pub(crate) fn retry_due(&mut self, swarm: &mut Swarm<Behaviour>) {
+        let now = Instant::now();
+        let due: Vec<PeerId> = self
+            .relays
+            .iter()
+            .filter(|(_, relay)| matches!(relay.phase, Phase::Waiting(at) if at <= now))
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in due {
+            if swarm.is_connected(&peer) {
+                self.reserve(swarm, peer);
+            } else if let Some(relay) = self.relays.get_mut(&peer) {
+                relay.phase = Phase::Connecting;
+            }
+        }
+    }
+
+    /// Takes the answer to a voucher presented by the manager and hands back the answers to
+    /// everyone else's.
+    pub(crate) fn on_voucher_response(
+        &mut self,
+        swarm: &mut Swarm<Behaviour>,
+        request_id: RequestId,
+        result: anyhow::Result<u64>,
+    ) -> Option<anyhow::Result<u64>> {
+        let presenting =
+            |phase: Phase| matches!(phase, Phase::PresentingVoucher(id) if id == request_id);
+        let Some(peer) = self.find(presenting) else {
+            return Some(result);
+        };
+        match result {
+            Ok(expires) => {
+                tracing::info!(relay = %peer, expires, "voucher accepted");
+                self.listen(swarm, peer);
+            }
+            Err(e) => self.failed(peer, format!("{e:#}")),
+        }
+        None
+    }
+
+    /// Reserves on `peer` right away if it is a relay waiting for its next attempt or for the
+    /// connection, as the connection to it is back.
+    pub(crate) fn on_connected(&mut self, swarm: &mut Swarm<Behaviour>, peer: &PeerId) {
+        let waiting = self
+            .relays
+            .get(peer)
+            .is_some_and(|relay| matches!(relay.phase, Phase::Waiting(_) | Phase::Connecting));
+        if waiting {
+            self.reserve(swarm, *peer);
+        }
+    }
+
+    pub(crate) fn on_reservation_accepted(&mut self, relay: &PeerId) {
+        if let Some(relay) = self.relays.get_mut(relay) {
+            relay.failures = 0;
+        }
+    }
+
+    pub(crate) fn on_new_listen_addr(
+        &mut self,
+        swarm: &mut Swarm<Behaviour>,
+        listener: ListenerId,
+        address: &Multiaddr,
+    ) {
+        let Some(relay) = self.listening(listener) else {
+            return;
+        };
+        if !relay.addresses.contains(address) {
+            relay.addresses.push(address.clone());
+            self.publish(swarm);
+        }
+    }
+
+    pub(crate) fn on_expired_listen_addr(
+        &mut self,
+        swarm: &mut Swarm<Behaviour>,
+        listener: ListenerId,
+        address: &Multiaddr,
+    ) {
+        let Some(relay) = self.listening(listener) else {
+            return;
+        };
+        let count = relay.addresses.len();
+        relay.addresses.retain(|a| a != address);
+        if relay.addresses.len() != count {
+            self.publish(swarm);
+        }
+    }
+
+    pub(crate) fn on_listener_closed(
+        &mut self,
+        swarm: &mut Swarm<Behaviour>,
+        listener: ListenerId,
+        reason: Option<&str>,
+    ) {
+        let listening = |phase: Phase| matches!(phase, Phase::Listening(id) if id == listener);
+        let Some(peer) = self.find(listening) else {
+            return;
+        };
+        let had_addresses = self
+            .relays
+            .get_mut(&peer)
+            .map(|relay| !std::mem::take(&mut relay.addresses).is_empty())
+            .unwrap_or_default();
+        if had_addresses {
+            self.publish(swarm);
+        }
+        self.failed(
+            peer,
+            reason
+                .unwrap_or("connection to the relay closed")
+                .to_string(),
+        );
+    }
+
+    /// Presents the voucher to the relay if there is one, and listens through it otherwise.
+    fn reserve(&mut self, swarm: &mut Swarm<Behaviour>, peer: PeerId) {
+        let (Some(relay), Some(envelope)) = (self.relays.get_mut(&peer), &self.voucher) else {
+            return self.listen(swarm, peer);
+        };
+        let Some(voucher) = swarm.behaviour_mut().voucher.as_mut() else {
+            return self.listen(swarm, peer);
+        };
+        voucher.add_address(&peer, relay.address.clone());
+        let request = VoucherRequest {
+            envelope: envelope.clone(),
+        };
+        relay.phase = Phase::PresentingVoucher(voucher.send_request(&peer, request));
+    }
+
+    fn listen(&mut self, swarm: &mut Swarm<Behaviour>, peer: PeerId) {
+        let Some(relay) = self.relays.get_mut(&peer) else {
+            return;
+        };
+        match swarm.listen_on(address::circuit(&relay.address, None)) {
+            Ok(id) => relay.phase = Phase::Listening(id),
+            Err(e) => self.failed(peer, e.to_string()),
+        }
+    }
+
+    fn failed(&mut self, peer: PeerId, error: String) {
+        let Some(relay) = self.relays.get_mut(&peer) else {
+            return;
+        };
+        relay.failures += 1;
+        let attempt = relay.failures;
+        let delay = self.backoff.delay(attempt);
+        relay.phase = Phase::Waiting(Instant::now() + delay);
+        tracing::info!(relay = %peer, attempt, ?delay, %error, "reserving again later");
+        self.emit(NodeEvent::ReservationRetry {
+            relay_peer_id: peer,
+            attempt,
+            delay,
+            error,
+        });
+    }
+
+    /// Tells connected peers about the changed circuit addresses, so that they reach identify
+    /// and, through it, the kademlia routing tables of the peers.
+    fn publish(&self, swarm: &mut Swarm<Behaviour>) {
+        let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+        if let Some(identify) = swarm.behaviour_mut().identify.as_mut() {
+            identify.push(peers);
+        }
+        self.emit(NodeEvent::CircuitAddressesChanged {
+            addresses: self.addresses(),
+        });
+    }
+
+    fn listening(&mut self, listener: ListenerId) -> Option<&mut Relay> {
+        self.relays
+            .values_mut()
+            .find(|relay| matches!(relay.phase, Phase::Listening(id) if id == listener))
+    }
+
+    fn find(&self, f: impl Fn(Phase) -> bool) -> Option<PeerId> {
+        self.relays
+            .iter()
+            .find(|(_, relay)| f(relay.phase))
+            .map(|(peer, _)| *peer)
+    }
+
+    fn emit(&self, event: NodeEvent) {
+        let _ = self.events.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::BehaviourConfig;
+    use libp2p::identity::Keypair;
+    use std::time::Duration;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    /// Never polled, so no connection to a relay is ever made.
+    async fn swarm() -> Swarm<Behaviour> {
+        let keypair = Keypair::generate_ed25519();
+        Behaviour::new(&keypair, BehaviourConfig::relay_client())
+            .await
+            .unwrap()
+    }
+
+    fn reservations() -> (Reservations, broadcast::Receiver<NodeEvent>) {
+        let (events, receiver) = broadcast::channel(16);
+        let mut reservations = Reservations::new(events);
+        reservations.set_backoff(Backoff {
+            initial: Duration::ZERO,
+            max: Duration::ZERO,
+            jitter: 0.0,
+        });
+        (reservations, receiver)
+    }
+
+    fn relay() -> (PeerId, Multiaddr) {
+        let peer = PeerId::random();
+        let address = format!("/ip4/192.0.2.1/tcp/4001/p2p/{peer}")
+            .parse()
+            .unwrap();
+        (peer, address)
+    }
+
+    fn phase(reservations: &Reservations, peer: &PeerId) -> Phase {
+        reservations.relays[peer].phase
+    }
+
+    fn listener(reservations: &Reservations, peer: &PeerId) -> ListenerId {
+        match phase(reservations, peer) {
+            Phase::Listening(id) => id,
+            phase => panic!("not listening: {phase:?}"),
+        }
+    }
+
+    fn circuit_addresses(events: &mut broadcast::Receiver<NodeEvent>) -> Vec<Multiaddr> {
+        match events.try_recv() {
+            Ok(NodeEvent::CircuitAddressesChanged { addresses }) => addresses,
+            event => panic!("not a change of addresses: {event:?}"),
+        }
+    }
+
+    fn retry(events: &mut broadcast::Receiver<NodeEvent>) -> (u32, String) {
+        match events.try_recv() {
+            Ok(NodeEvent::ReservationRetry { attempt, error, .. }) => (attempt, error),
+            event => panic!("not a retry: {event:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn reserves_once_connected() {
+        let mut swarm = swarm().await;
+        let (mut reservations, _) = reservations();
+        let (peer, address) = relay();
+        reservations.add(&mut swarm, address.clone()).unwrap();
+        assert!(matches!(phase(&reservations, &peer), Phase::Connecting));
+        assert_eq!(reservations.relays(), vec![address.clone()]);
+        // Adding it again changes nothing.
+        reservations.add(&mut swarm, address).unwrap();
+        assert!(matches!(phase(&reservations, &peer), Phase::Connecting));
+
+        reservations.on_connected(&mut swarm, &peer);
+        listener(&reservations, &peer);
+        assert!(reservations
+            .add(&mut swarm, "/ip4/192.0.2.1/tcp/4001".parse().unwrap())
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn listens_once_the_voucher_is_accepted() {
+        let mut swarm = swarm().await;
+        let (mut reservations, mut events) = reservations();
+        reservations.set_voucher(b"voucher".to_vec());
+        let (peer, address) = relay();
+        reservations.add(&mut swarm, address).unwrap();
+        reservations.on_connected(&mut swarm, &peer);
+        let Phase::PresentingVoucher(request_id) = phase(&reservations, &peer) else {
+            panic!("not presenting the voucher");
+        };
+
+        // Answers to vouchers presented by anyone else are handed back.
+        let other = swarm
+            .behaviour_mut()
+            .voucher
+            .as_mut()
+            .unwrap()
+            .send_request(
+                &PeerId::random(),
+                VoucherRequest {
+                    envelope: Vec::new(),
+                },
+            );
+        let handed_back = reservations.on_voucher_response(&mut swarm, other, Ok(1));
+        assert_eq!(handed_back.unwrap().unwrap(), 1);
+
+        assert!(reservations
+            .on_voucher_response(&mut swarm, request_id, Err(anyhow::anyhow!("expired")))
+            .is_none());
+        assert!(matches!(phase(&reservations, &peer), Phase::Waiting(_)));
+        assert_eq!(retry(&mut events), (1, "expired".to_string()));
+
+        reservations.on_connected(&mut swarm, &peer);
+        let Phase::PresentingVoucher(request_id) = phase(&reservations, &peer) else {
+            panic!("not presenting the voucher again");
+        };
+        assert!(reservations
+            .on_voucher_response(&mut swarm, request_id, Ok(1))
+            .is_none());
+        listener(&reservations, &peer);
+    }
+
+    #[tokio::test]
+    async fn closed_listeners_are_retried() {
+        let mut swarm = swarm().await;
+        let (mut reservations, mut events) = reservations();
+        let (peer, address) = relay();
+        reservations.add(&mut swarm, address.clone()).unwrap();
+        reservations.on_connected(&mut swarm, &peer);
+        let id = listener(&reservations, &peer);
+
+        let circuit = address::circuit(&address, None);
+        reservations.on_new_listen_addr(&mut swarm, id, &circuit);
+        assert_eq!(circuit_addresses(&mut events), vec![circuit.clone()]);
+        assert_eq!(reservations.addresses(), vec![circuit]);
+
+        reservations.on_listener_closed(&mut swarm, id, None);
+        assert!(circuit_addresses(&mut events).is_empty());
+        assert_eq!(
+            retry(&mut events),
+            (1, "connection to the relay closed".to_string())
+        );
+        assert!(matches!(phase(&reservations, &peer), Phase::Waiting(_)));
+        assert!(reservations.addresses().is_empty());
+        assert!(reservations.next_retry().is_some());
+
+        // A listener of its own is not ours to retry.
+        reservations.on_listener_closed(&mut swarm, id, Some("closed"));
+        assert_eq!(events.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
+    #[tokio::test]
+    async fn due_retries_wait_for_the_connection() {
+        let mut swarm = swarm().await;
+        let (mut reservations, mut events) = reservations();
+        let (peer, address) = relay();
+        reservations.add(&mut swarm, address).unwrap();
+        reservations.on_connected(&mut swarm, &peer);
+        let id = listener(&reservations, &peer);
+        reservations.on_listener_closed(&mut swarm, id, Some("reservation refused"));
+        assert_eq!(retry(&mut events), (1, "reservation refused".to_string()));
+
+        reservations.retry_due(&mut swarm);
+        assert!(matches!(phase(&reservations, &peer), Phase::Connecting));
+        assert_eq!(reservations.next_retry(), None);
+
+        reservations.on_connected(&mut swarm, &peer);
+        let id = listener(&reservations, &peer);
+        reservations.on_listener_closed(&mut swarm, id, None);
+        assert_eq!(retry(&mut events).0, 2);
+        reservations.on_reservation_accepted(&peer);
+        assert_eq!(reservations.relays[&peer].failures, 0);
+    }
+
+    #[tokio::test]
+    async fn removing_a_relay_withdraws_its_addresses() {
+        let mut swarm = swarm().await;
+        let (mut reservations, mut events) = reservations();
+        let (peer, address) = relay();
+        let (other_peer, other) = relay();
+        for (peer, address) in [(peer, &address), (other_peer, &other)] {
+            reservations.add(&mut swarm, address.clone()).unwrap();
+            reservations.on_connected(&mut swarm, &peer);
+            let id = listener(&reservations, &peer);
+            reservations.on_new_listen_addr(&mut swarm, id, &address::circuit(address, None));
+            circuit_addresses(&mut events);
+        }
+
+        assert!(reservations.remove(&mut swarm, &address));
+        assert_eq!(
+            circuit_addresses(&mut events),
+            vec![address::circuit(&other, None)]
+        );
+        assert_eq!(reservations.relays(), vec![other]);
+        assert!(!reservations.remove(&mut swarm, &address));
+        assert_eq!(events.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+}
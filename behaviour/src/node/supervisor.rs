@@ -0,0 +1,389 @@
+use super::backoff::Backoff;
+use super::NodeEvent;
+use crate::behaviour::Behaviour;
+use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
+use libp2p::swarm::DialError;
+use libp2p::{Multiaddr, PeerId, Swarm};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+use tokio::sync::broadcast;
+
+/// How the connection to a supervised peer is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    /// Connected, and the last ping was answered.
+    Healthy,
+    /// Still connected, but the last ping failed.
+    Degraded,
+    /// Not connected, a dial is scheduled or under way.
+    Disconnected,
+}
+
+/// Why a peer is supervised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Reason {
+    /// The node keeps a reservation on it.
+    Relay,
+    /// It was pinned.
+    Pin,
+}
+
+/// Keeps the node connected to its relays and pinned peers, dialing them again with
+/// [`Backoff`] whenever the last connection closed or a dial failed.
+pub(crate) struct Supervisor {
+    peers: HashMap<PeerId, Supervised>,
+    backoff: Backoff,
+    events: broadcast::Sender<NodeEvent>,
+}
+
+#[derive(Debug)]
+struct Supervised {
+    /// Dropped once empty.
+    reasons: HashSet<Reason>,
+    addresses: Vec<Multiaddr>,
+    state: State,
+    health: Health,
+    /// Dials that failed since the peer was last connected.
+    failures: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Connected,
+    Dialing,
+    Waiting(Instant),
+}
+
+impl Supervisor {
+    pub(crate) fn new(events: broadcast::Sender<NodeEvent>) -> Self {
+        Self {
+            peers: HashMap::new(),
+            backoff: Backoff::default(),
+            events,
+        }
+    }
+
+    pub(crate) fn set_backoff(&mut self, backoff: Backoff) {
+        self.backoff = backoff;
+    }
+
+    /// Starts keeping the node connected to `peer` for `reason`, dialing it at `address` as
+    /// well as at the addresses it was given before.
+    pub(crate) fn add(
+        &mut self,
+        swarm: &mut Swarm<Behaviour>,
+        peer: PeerId,
+        address: Multiaddr,
+        reason: Reason,
+    ) {
+        self.insert(peer, address, reason);
+        if swarm.is_connected(&peer) {
+            self.on_connected(&peer);
+        } else if self.peers[&peer].state != State::Dialing {
+            self.dial(swarm, peer);
+        }
+    }
+
+    /// Drops `reason` for supervising `peer`, returning whether it had been given. The peer is
+    /// left alone once no reason is left.
+    pub(crate) fn remove(&mut self, peer: &PeerId, reason: Reason) -> bool {
+        let Some(supervised) = self.peers.get_mut(peer) else {
+            return false;
+        };
+        let removed = supervised.reasons.remove(&reason);
+        if supervised.reasons.is_empty() {
+            self.peers.remove(peer);
+        }
+        removed
+    }
+
+    /// When the next peer is due to be dialed again.
+    pub(crate) fn next_dial(&self) -> Option<Instant> {
+        self.peers
+            .values()
+            .filter_map(|supervised| match supervised.state {
+                State::Waiting(at) => Some(at),
+                _ => None,
+            })
+            .min()
+    }
+
+    pub(crate) fn dial_due(&mut self, swarm: &mut Swarm<Behaviour>) {
+        let now = Instant::now();
+        let due: Vec<PeerId> = self
+            .peers
+            .iter()
+            .filter(|(_, supervised)| matches!(supervised.state, State::Waiting(at) if at <= now))
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in due {
+            self.dial(swarm, peer);
+        }
+    }
+
+    pub(crate) fn on_connected(&mut self, peer: &PeerId) {
+        let Some(supervised) = self.peers.get_mut(peer) else {
+            return;
+        };
+        supervised.state = State::Connected;
+        supervised.failures = 0;
+        self.set_health(*peer, Health::Healthy);
+    }
+
+    /// The last connection to `peer` closed.
+    pub(crate) fn on_disconnected(&mut self, peer: &PeerId, cause: Option<&str>) {
+        if self.peers.contains_key(peer) {
+            self.failed(*peer, cause.unwrap_or("connection closed").to_string());
+        }
+    }
+
+    pub(crate) fn on_dial_failed(&mut self, peer: &PeerId, error: &str) {
+        let dialing = self
+            .peers
+            .get(peer)
+            .is_some_and(|supervised| supervised.state == State::Dialing);
+        if dialing {
+            self.failed(*peer, error.to_string());
+        }
+    }
+
+    pub(crate) fn on_ping(&mut self, peer: &PeerId, ok: bool) {
+        let connected = self
+            .peers
+            .get(peer)
+            .is_some_and(|supervised| supervised.state == State::Connected);
+        if connected {
+            let health = if ok {
+                Health::Healthy
+            } else {
+                Health::Degraded
+            };
+            self.set_health(*peer, health);
+        }
+    }
+
+    fn insert(&mut self, peer: PeerId, address: Multiaddr, reason: Reason) {
+        let supervised = self.peers.entry(peer).or_insert_with(|| Supervised {
+            reasons: HashSet::new(),
+            addresses: Vec::new(),
+            state: State::Waiting(Instant::now()),
+            health: Health::Disconnected,
+            failures: 0,
+        });
+        supervised.reasons.insert(reason);
+        if !supervised.addresses.contains(&address) {
+            supervised.addresses.push(address);
+        }
+    }
+
+    fn dial(&mut self, swarm: &mut Swarm<Behaviour>, peer: PeerId) {
+        let Some(supervised) = self.peers.get_mut(&peer) else {
+            return;
+        };
+        let opts = DialOpts::peer_id(peer)
+            .condition(PeerCondition::Disconnected)
+            .addresses(supervised.addresses.clone())
+            .build();
+        match swarm.dial(opts) {
+            // Already dialing, the outcome of that dial is ours as well.
+            Ok(()) | Err(DialError::DialPeerConditionFalse(_)) => {
+                supervised.state = State::Dialing;
+            }
+            Err(e) => self.failed(peer, format!("{e:?}")),
+        }
+    }
+
+    fn failed(&mut self, peer: PeerId, error: String) {
+        let Some(supervised) = self.peers.get_mut(&peer) else {
+            return;
+        };
+        supervised.failures += 1;
+        let attempt = supervised.failures;
+        let delay = self.backoff.delay(attempt);
+        supervised.state = State::Waiting(Instant::now() + delay);
+        tracing::info!(%peer, attempt, ?delay, %error, "dialing again later");
+        self.set_health(peer, Health::Disconnected);
+        self.emit(NodeEvent::RedialScheduled {
+            peer_id: peer,
+            attempt,
+            delay,
+            error,
+        });
+    }
+
+    fn set_health(&mut self, peer: PeerId, health: Health) {
+        let Some(supervised) = self.peers.get_mut(&peer) else {
+            return;
+        };
+        if supervised.health != health {
+            supervised.health = health;
+            self.emit(NodeEvent::ConnectionHealth {
+                peer_id: peer,
+                health,
+            });
+        }
+    }
+
+    fn emit(&self, event: NodeEvent) {
+        let _ = self.events.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::BehaviourConfig;
+    use libp2p::identity::Keypair;
+    use std::time::Duration;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    /// Never polled, so dials stay under way until the test says how they went.
+    async fn swarm() -> Swarm<Behaviour> {
+        let keypair = Keypair::generate_ed25519();
+        Behaviour::new(&keypair, BehaviourConfig::relay_client())
+            .await
+            .unwrap()
+    }
+
+    fn supervisor() -> (Supervisor, broadcast::Receiver<NodeEvent>) {
+        let (events, receiver) = broadcast::channel(16);
+        let mut supervisor = Supervisor::new(events);
+        supervisor.set_backoff(Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(1),
+            jitter: 0.0,
+        });
+        (supervisor, receiver)
+    }
+
+    fn peer() -> (PeerId, Multiaddr) {
+        let peer = PeerId::random();
+        let address = format!("/ip4/192.0.2.1/tcp/4001/p2p/{peer}")
+            .parse()
+            .unwrap();
+        (peer, address)
+    }
+
+    fn state(supervisor: &Supervisor, peer: &PeerId) -> State {
+        supervisor.peers[peer].state
+    }
+
+    fn health(events: &mut broadcast::Receiver<NodeEvent>) -> Health {
+        match events.try_recv() {
+            Ok(NodeEvent::ConnectionHealth { health, .. }) => health,
+            event => panic!("not a change of health: {event:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn peers_are_kept_for_every_reason_given() {
+        let mut swarm = swarm().await;
+        let (mut supervisor, _) = supervisor();
+        let (peer, address) = peer();
+        supervisor.add(&mut swarm, peer, address.clone(), Reason::Relay);
+        assert_eq!(state(&supervisor, &peer), State::Dialing);
+        supervisor.add(&mut swarm, peer, address, Reason::Pin);
+        assert_eq!(supervisor.peers[&peer].addresses.len(), 1);
+
+        assert!(supervisor.remove(&peer, Reason::Relay));
+        assert!(!supervisor.remove(&peer, Reason::Relay));
+        assert!(supervisor.peers.contains_key(&peer));
+        assert!(supervisor.remove(&peer, Reason::Pin));
+        assert!(!supervisor.peers.contains_key(&peer));
+        assert!(!supervisor.remove(&peer, Reason::Pin));
+    }
+
+    #[tokio::test]
+    async fn only_failed_dials_of_the_supervisor_count() {
+        let mut swarm = swarm().await;
+        let (mut supervisor, mut events) = supervisor();
+        let (peer, address) = peer();
+        supervisor.add(&mut swarm, peer, address, Reason::Pin);
+        supervisor.on_connected(&peer);
+        assert_eq!(health(&mut events), Health::Healthy);
+
+        // Someone else's dial of a connected peer.
+        supervisor.on_dial_failed(&peer, "refused");
+        supervisor.on_dial_failed(&PeerId::random(), "refused");
+        assert_eq!(state(&supervisor, &peer), State::Connected);
+        assert_eq!(events.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
+    #[tokio::test]
+    async fn disconnected_peers_are_dialed_again() {
+        let mut swarm = swarm().await;
+        let (mut supervisor, mut events) = supervisor();
+        let (peer, address) = peer();
+        supervisor.add(&mut swarm, peer, address, Reason::Relay);
+        supervisor.on_connected(&peer);
+        assert_eq!(health(&mut events), Health::Healthy);
+
+        supervisor.on_disconnected(&peer, None);
+        assert!(matches!(state(&supervisor, &peer), State::Waiting(_)));
+        assert_eq!(health(&mut events), Health::Disconnected);
+        match events.try_recv() {
+            Ok(NodeEvent::RedialScheduled {
+                peer_id,
+                attempt,
+                delay,
+                error,
+            }) => {
+                assert_eq!(peer_id, peer);
+                assert_eq!(attempt, 1);
+                assert_eq!(delay, Duration::from_secs(1));
+                assert_eq!(error, "connection closed");
+            }
+            event => panic!("not a redial: {event:?}"),
+        }
+        assert!(supervisor.next_dial().is_some());
+
+        // Not due yet, nor dialing.
+        supervisor.dial_due(&mut swarm);
+        supervisor.on_dial_failed(&peer, "refused");
+        assert!(matches!(state(&supervisor, &peer), State::Waiting(_)));
+        assert_eq!(events.try_recv().unwrap_err(), TryRecvError::Empty);
+        supervisor.peers.get_mut(&peer).unwrap().state = State::Waiting(Instant::now());
+        supervisor.dial_due(&mut swarm);
+        assert_eq!(state(&supervisor, &peer), State::Dialing);
+        assert_eq!(supervisor.next_dial(), None);
+
+        supervisor.on_dial_failed(&peer, "refused");
+        match events.try_recv() {
+            Ok(NodeEvent::RedialScheduled { attempt, error, .. }) => {
+                assert_eq!((attempt, error.as_str()), (2, "refused"));
+            }
+            event => panic!("not a redial: {event:?}"),
+        }
+        // Unchanged health is not reported again.
+        assert_eq!(events.try_recv().unwrap_err(), TryRecvError::Empty);
+
+        // Peers no longer supervised are left alone.
+        supervisor.on_disconnected(&PeerId::random(), Some("closed"));
+        assert_eq!(events.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
+    #[tokio::test]
+    async fn pings_set_the_health_of_connected_peers() {
+        let mut swarm = swarm().await;
+        let (mut supervisor, mut events) = supervisor();
+        let (peer, address) = peer();
+        supervisor.add(&mut swarm, peer, address, Reason::Pin);
+        // Not connected yet.
+        supervisor.on_ping(&peer, false);
+        assert_eq!(events.try_recv().unwrap_err(), TryRecvError::Empty);
+
+        supervisor.on_connected(&peer);
+        assert_eq!(health(&mut events), Health::Healthy);
+        supervisor.on_ping(&peer, true);
+        assert_eq!(events.try_recv().unwrap_err(), TryRecvError::Empty);
+        supervisor.on_ping(&peer, false);
+        assert_eq!(health(&mut events), Health::Degraded);
+        supervisor.on_ping(&peer, false);
+        assert_eq!(events.try_recv().unwrap_err(), TryRecvError::Empty);
+        supervisor.on_ping(&peer, true);
+        assert_eq!(health(&mut events), Health::Healthy);
+        assert_eq!(supervisor.peers[&peer].failures, 0);
+    }
+}
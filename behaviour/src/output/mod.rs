@@ -0,0 +1,544 @@
+//! Machine-readable rendering of [`NodeEvent`]s.
+//!
+//! In JSON mode every event is printed as one JSON object per line. Each object has
+//!
+//! - `event`: the snake_case name of the [`NodeEvent`] variant, e.g. `connection_established`
+//! - `timestamp`: milliseconds since the unix epoch when the line was written
+//!
+//! and the fields of the variant under their own names. Peer ids and multiaddrs are strings,
+//! durations are `_ms` suffixed numbers and errors are their debug rendering. Endpoints are
+//! `{"role": "dialer"|"listener", "address": <remote address>, "relayed": <bool>}` and chat
+//! messages are `{"id", "timestamp", "content_type", "reply_to", "text"}`, with `body_hex`
+//! instead of `text` for anything but text. Fields are only ever added, never renamed.
+
+use crate::node::NodeEvent;
+use clap::ValueEnum;
+use codec::chat::{ChatEnvelope, CONTENT_TYPE_TEXT};
+use libp2p::core::ConnectedPoint;
+use libp2p::Multiaddr;
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per event, see [`to_json`].
+    Json,
+}
+
+/// Renders `event` as a single line of JSON.
+pub fn json_line(event: &NodeEvent) -> String {
+    to_json(event).to_string()
+}
+
+pub fn to_json(event: &NodeEvent) -> Value {
+    let (name, mut fields) = match event {
+        NodeEvent::NewListenAddr { address } => {
+            ("new_listen_addr", json!({ "address": address.to_string() }))
+        }
+        NodeEvent::ExpiredListenAddr { address } => (
+            "expired_listen_addr",
+            json!({ "address": address.to_string() }),
+        ),
+        NodeEvent::ListenerClosed { addresses, reason } => (
+            "listener_closed",
+            json!({ "addresses": addresses_json(addresses), "reason": reason }),
+        ),
+        NodeEvent::ListenerError { error } => ("listener_error", json!({ "error": error })),
+        NodeEvent::Dialing { peer_id } => ("dialing", json!({ "peer_id": peer_id.to_string() })),
+        NodeEvent::ConnectionEstablished {
+            peer_id,
+            endpoint,
+            num_established,
+        } => (
+            "connection_established",
+            json!({
+                "peer_id": peer_id.to_string(),
+                "endpoint": endpoint_json(endpoint),
+                "num_established": num_established,
+            }),
+        ),
+        NodeEvent::ConnectionClosed {
+            peer_id,
+            endpoint,
+            num_established,
+            cause,
+        } => (
+            "connection_closed",
+            json!({
+                "peer_id": peer_id.to_string(),
+                "endpoint": endpoint_json(endpoint),
+                "num_established": num_established,
+                "cause": cause,
+            }),
+        ),
+        NodeEvent::IncomingConnection {
+            local_addr,
+            send_back_addr,
+        } => (
+            "incoming_connection",
+            json!({
+                "local_addr": local_addr.to_string(),
+                "send_back_addr": send_back_addr.to_string(),
+            }),
+        ),
+        NodeEvent::IncomingConnectionError {
+            local_addr,
+            send_back_addr,
+            error,
+        } => (
+            "incoming_connection_error",
+            json!({
+                "local_addr": local_addr.to_string(),
+                "send_back_addr": send_back_addr.to_string(),
+                "error": error,
+            }),
+        ),
+        NodeEvent::OutgoingConnectionError { peer_id, error } => (
+            "outgoing_connection_error",
+            json!({
+                "peer_id": peer_id.map(|peer_id| peer_id.to_string()),
+                "error": error,
+            }),
+        ),
+        NodeEvent::Ping { peer, rtt } => (
+            "ping",
+            json!({
+                "peer": peer.to_string(),
+                "rtt_ms": rtt.as_ref().ok().map(|rtt| rtt.as_secs_f64() * 1000.0),
+                "error": rtt.as_ref().err(),
+            }),
+        ),
+        NodeEvent::Identified {
+            peer_id,
+            agent_version,
+            listen_addrs,
+            protocols,
+        } => (
+            "identified",
+            json!({
+                "peer_id": peer_id.to_string(),
+                "agent_version": agent_version,
+                "listen_addrs": addresses_json(listen_addrs),
+                "protocols": protocols,
+            }),
+        ),
+        NodeEvent::KadRoutingUpdated {
+            peer,
+            is_new_peer,
+            addresses,
+        } => (
+            "kad_routing_updated",
+            json!({
+                "peer": peer.to_string(),
+                "is_new_peer": is_new_peer,
+                "addresses": addresses_json(addresses),
+            }),
+        ),
+        NodeEvent::KadRoutablePeer { peer, address } => (
+            "kad_routable_peer",
+            json!({ "peer": peer.to_string(), "address": address.to_string() }),
+        ),
+        NodeEvent::KadUnroutablePeer { peer } => {
+            ("kad_unroutable_peer", json!({ "peer": peer.to_string() }))
+        }
+        NodeEvent::ChatRequest {
+            peer,
+            request_id,
+            request,
+        } => (
+            "chat_request",
+            json!({
+                "peer": peer.to_string(),
+                "request_id": request_id.to_string(),
+                "message": message_json(&request.0),
+            }),
+        ),
+        NodeEvent::ChatResponse {
+            peer,
+            request_id,
+            response,
+        } => (
+            "chat_response",
+            json!({
+                "peer": peer.to_string(),
+                "request_id": request_id.to_string(),
+                "message": message_json(&response.0),
+            }),
+        ),
+        NodeEvent::ChatResponseSent { peer, request_id } => (
+            "chat_response_sent",
+            json!({ "peer": peer.to_string(), "request_id": request_id.to_string() }),
+        ),
+        NodeEvent::ChatOutboundFailure {
+            peer,
+            request_id,
+            error,
+        } => (
+            "chat_outbound_failure",
+            json!({
+                "peer": peer.to_string(),
+                "request_id": request_id.to_string(),
+                "error": error,
+            }),
+        ),
+        NodeEvent::ChatInboundFailure {
+            peer,
+            request_id,
+            error,
+        } => (
+            "chat_inbound_failure",
+            json!({
+                "peer": peer.to_string(),
+                "request_id": request_id.to_string(),
+                "error": error,
+            }),
+        ),
+        NodeEvent::RelayReservationAccepted {
+            src_peer_id,
+            renewed,
+        } => (
+            "relay_reservation_accepted",
+            json!({ "src_peer_id": src_peer_id.to_string(), "renewed": renewed }),
+        ),
+        NodeEvent::RelayReservationDenied {
+            src_peer_id,
+            reason,
+        } => (
+            "relay_reservation_denied",
+            json!({ "src_peer_id": src_peer_id.to_string(), "reason": reason }),
+        ),
+        NodeEvent::RelayReservationTimedOut { src_peer_id } => (
+            "relay_reservation_timed_out",
+            json!({ "src_peer_id": src_peer_id.to_string() }),
+        ),
+        NodeEvent::RelayCircuitAccepted {
+            src_peer_id,
+            dst_peer_id,
+        } => (
+            "relay_circuit_accepted",
+            json!({
+                "src_peer_id": src_peer_id.to_string(),
+                "dst_peer_id": dst_peer_id.to_string(),
+            }),
+        ),
+        NodeEvent::RelayCircuitDenied {
+            src_peer_id,
+            dst_peer_id,
+            reason,
+        } => (
+            "relay_circuit_denied",
+            json!({
+                "src_peer_id": src_peer_id.to_string(),
+                "dst_peer_id": dst_peer_id.to_string(),
+                "reason": reason,
+            }),
+        ),
+        NodeEvent::RelayCircuitClosed {
+            src_peer_id,
+            dst_peer_id,
+            error,
+        } => (
+            "relay_circuit_closed",
+            json!({
+                "src_peer_id": src_peer_id.to_string(),
+                "dst_peer_id": dst_peer_id.to_string(),
+                "error": error,
+            }),
+        ),
+        NodeEvent::RelayVoucherReceived { peer, expires } => (
+            "relay_voucher_received",
+            json!({
+                "peer": peer.to_string(),
+                "expires": expires.as_ref().ok(),
+                "error": expires.as_ref().err(),
+            }),
+        ),
+        NodeEvent::ReservationAccepted {
+            relay_peer_id,
+            renewal,
+        } => (
+            "reservation_accepted",
+            json!({ "relay_peer_id": relay_peer_id.to_string(), "renewal": renewal }),
+        ),
+        NodeEvent::ReservationFailed {
+            relay_peer_id,
+            renewal,
+            error,
+        } => (
+            "reservation_failed",
+            json!({
+                "relay_peer_id": relay_peer_id.to_string(),
+                "renewal": renewal,
+                "error": error,
+            }),
+        ),
+        NodeEvent::ReservationRetry {
+            relay_peer_id,
+            attempt,
+            delay,
+            error,
+        } => (
+            "reservation_retry",
+            json!({
+                "relay_peer_id": relay_peer_id.to_string(),
+                "attempt": attempt,
+                "delay_ms": delay.as_millis() as u64,
+                "error": error,
+            }),
+        ),
+        NodeEvent::CircuitAddressesChanged { addresses } => (
+            "circuit_addresses_changed",
+            json!({ "addresses": addresses_json(addresses) }),
+        ),
+        NodeEvent::ConnectionHealth { peer_id, health } => (
+            "connection_health",
+            json!({ "peer_id": peer_id.to_string(), "health": health }),
+        ),
+        NodeEvent::RedialScheduled {
+            peer_id,
+            attempt,
+            delay,
+            error,
+        } => (
+            "redial_scheduled",
+            json!({
+                "peer_id": peer_id.to_string(),
+                "attempt": attempt,
+                "delay_ms": delay.as_millis() as u64,
+                "error": error,
+            }),
+        ),
+        NodeEvent::HolePunchStarted {
+            peer_id,
+            initiated_locally,
+        } => (
+            "hole_punch_started",
+            json!({ "peer_id": peer_id.to_string(), "initiated_locally": initiated_locally }),
+        ),
+        NodeEvent::HolePunchSucceeded { peer_id, migrated } => (
+            "hole_punch_succeeded",
+            json!({ "peer_id": peer_id.to_string(), "migrated": migrated }),
+        ),
+        NodeEvent::HolePunchFailed { peer_id, error } => (
+            "hole_punch_failed",
+            json!({ "peer_id": peer_id.to_string(), "error": error }),
+        ),
+        NodeEvent::PeerReached {
+            peer_id,
+            path,
+            address,
+            failures,
+        } => (
+            "peer_reached",
+            json!({
+                "peer_id": peer_id.to_string(),
+                "path": path,
+                "address": address.as_ref().map(|address| address.to_string()),
+                "failures": failures,
+            }),
+        ),
+        NodeEvent::PeerUnreachable { peer_id, failures } => (
+            "peer_unreachable",
+            json!({ "peer_id": peer_id.to_string(), "failures": failures }),
+        ),
+        NodeEvent::OutboundCircuitEstablished { relay_peer_id } => (
+            "outbound_circuit_established",
+            json!({ "relay_peer_id": relay_peer_id.to_string() }),
+        ),
+        NodeEvent::InboundCircuitEstablished { src_peer_id } => (
+            "inbound_circuit_established",
+            json!({ "src_peer_id": src_peer_id.to_string() }),
+        ),
+    };
+    fields["event"] = json!(name);
+    fields["timestamp"] = json!(now_millis());
+    fields
+}
+
+fn addresses_json(addresses: &[Multiaddr]) -> Vec<String> {
+    addresses
+        .iter()
+        .map(|address| address.to_string())
+        .collect()
+}
+
+fn endpoint_json(endpoint: &ConnectedPoint) -> Value {
+    let (role, address) = match endpoint {
+        ConnectedPoint::Dialer { address, .. } => ("dialer", address),
+        ConnectedPoint::Listener { send_back_addr, .. } => ("listener", send_back_addr),
+    };
+    json!({
+        "role": role,
+        "address": address.to_string(),
+        "relayed": endpoint.is_relayed(),
+    })
+}
+
+fn message_json(message: &ChatEnvelope) -> Value {
+    let mut json = json!({
+        "id": message.id,
+        "timestamp": message.timestamp,
+        "content_type": message.content_type,
+        "reply_to": message.reply_to,
+    });
+    match std::str::from_utf8(&message.body) {
+        Ok(text) if message.content_type == CONTENT_TYPE_TEXT => json["text"] = json!(text),
+        _ => json["body_hex"] = json!(hex::encode(&message.body)),
+    }
+    json
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec::chat::ChatRequest;
+    use libp2p::core::Endpoint;
+    use libp2p::PeerId;
+    use std::collections::BTreeSet;
+    use std::time::Duration;
+
+    /// Parses the line back, checking it is one line with exactly `keys` besides `event` and
+    /// `timestamp`.
+    fn parsed(event: NodeEvent, name: &str, keys: &[&str]) -> Value {
+        let line = json_line(&event);
+        assert!(!line.contains('\n'));
+        let json: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["event"], name);
+        assert!(json["timestamp"].as_u64().unwrap() > 0);
+        let found: BTreeSet<&str> = json
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(|k| k.as_str())
+            .collect();
+        let expected: BTreeSet<&str> = keys.iter().copied().chain(["event", "timestamp"]).collect();
+        assert_eq!(found, expected);
+        json
+    }
+
+    #[test]
+    fn connections_carry_their_endpoint() {
+        let peer_id = PeerId::random();
+        let address: Multiaddr = "/ip4/192.0.2.1/tcp/4001/p2p-circuit".parse().unwrap();
+        let json = parsed(
+            NodeEvent::ConnectionEstablished {
+                peer_id,
+                endpoint: ConnectedPoint::Dialer {
+                    address: address.clone(),
+                    role_override: Endpoint::Dialer,
+                },
+                num_established: 1,
+            },
+            "connection_established",
+            &["peer_id", "endpoint", "num_established"],
+        );
+        assert_eq!(json["peer_id"], peer_id.to_string());
+        assert_eq!(
+            json["endpoint"],
+            json!({ "role": "dialer", "address": address.to_string(), "relayed": true })
+        );
+        assert_eq!(json["num_established"], 1);
+    }
+
+    #[test]
+    fn pings_have_a_round_trip_time_or_an_error() {
+        let peer = PeerId::random();
+        let json = parsed(
+            NodeEvent::Ping {
+                peer,
+                rtt: Ok(Duration::from_millis(12)),
+            },
+            "ping",
+            &["peer", "rtt_ms", "error"],
+        );
+        assert_eq!(json["rtt_ms"], 12.0);
+        assert_eq!(json["error"], Value::Null);
+
+        let json = parsed(
+            NodeEvent::Ping {
+                peer,
+                rtt: Err("Timeout".to_string()),
+            },
+            "ping",
+            &["peer", "rtt_ms", "error"],
+        );
+        assert_eq!(json["rtt_ms"], Value::Null);
+        assert_eq!(json["error"], "Timeout");
+    }
+
+    #[test]
+    fn chat_messages_are_text_or_hex() {
+        let peer = PeerId::random();
+        let mut envelope = ChatEnvelope::text("hello");
+        envelope.reply_to = Some("earlier".to_string());
+        let request_id = request_id();
+        let json = parsed(
+            NodeEvent::ChatRequest {
+                peer,
+                request_id,
+                request: ChatRequest(envelope.clone()),
+            },
+            "chat_request",
+            &["peer", "request_id", "message"],
+        );
+        assert_eq!(json["request_id"], request_id.to_string());
+        assert_eq!(
+            json["message"],
+            json!({
+                "id": envelope.id,
+                "timestamp": envelope.timestamp,
+                "content_type": CONTENT_TYPE_TEXT,
+                "reply_to": "earlier",
+                "text": "hello",
+            })
+        );
+
+        let json = parsed(
+            NodeEvent::ChatRequest {
+                peer,
+                request_id,
+                request: ChatRequest::from(vec![0xff, 0x00]),
+            },
+            "chat_request",
+            &["peer", "request_id", "message"],
+        );
+        assert_eq!(json["message"]["body_hex"], "ff00");
+        assert!(json["message"].get("text").is_none());
+    }
+
+    #[test]
+    fn durations_are_milliseconds() {
+        let json = parsed(
+            NodeEvent::RedialScheduled {
+                peer_id: PeerId::random(),
+                attempt: 3,
+                delay: Duration::from_secs(4),
+                error: "connection closed".to_string(),
+            },
+            "redial_scheduled",
+            &["peer_id", "attempt", "delay_ms", "error"],
+        );
+        assert_eq!(json["attempt"], 3);
+        assert_eq!(json["delay_ms"], 4000);
+    }
+
+    /// Request ids can only be had from a behaviour sending requests.
+    fn request_id() -> libp2p::request_response::RequestId {
+        use codec::chat::{ChatCodec, ChatProtocol};
+        use libp2p::request_response::{Behaviour, Config, ProtocolSupport};
+        let protocols = ChatProtocol::all()
+            .into_iter()
+            .map(|protocol| (protocol, ProtocolSupport::Full));
+        let mut behaviour = Behaviour::new(ChatCodec::default(), protocols, Config::default());
+        behaviour.send_request(&PeerId::random(), ChatRequest::from(Vec::new()))
+    }
+}
@@ -0,0 +1,463 @@
+//! Who may reserve a slot on the relay server and open circuits through it.
+//!
+//! Configured in the `[acl]` table of the relay config:
+//!
+//! ```toml
+//! [acl]
+//! allow_peers = ["12D3KooW..."]
+//! deny_peers = []
+//! allow_ips = ["10.0.0.0/8", "192.0.2.7"]
+//! deny_ips = ["10.66.0.0/16"]
+//! voucher_issuers = ["12D3KooW..."]
+//! ```
+//!
+//! Deny lists always win. Once either allow list has an entry, only the peers and addresses
+//! they list get through. With `voucher_issuers`, a peer that is not in `allow_peers` also has
+//! to present a [`Voucher`] signed by one of the issuers over `/relay-voucher/0.1.0` before it
+//! may reserve. Circuit sources are checked against the lists. Circuits only ever lead to peers
+//! holding a reservation, whose destinations went through the checks when they reserved and go
+//! through them again when their voucher expires and when the list is set. A peer that no
+//! longer passes is disconnected, which ends its reservation.
+
+use crate::address;
+use crate::relay_config::Limiter;
+use anyhow::{bail, Context};
+use libp2p::core::SignedEnvelope;
+use libp2p::identity::Keypair;
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const VOUCHER_DOMAIN: &str = "p2p-relay-voucher";
+const VOUCHER_PAYLOAD_TYPE: &[u8] = b"/p2p/relay-voucher";
+
+/// An IP network such as `10.0.0.0/8`. A plain address is a network of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .with_context(|| format!("invalid address in {s}"))?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .with_context(|| format!("invalid prefix length in {s}"))?,
+            None => max,
+        };
+        if prefix > max {
+            bail!("prefix length of {s} is longer than {max} bits");
+        }
+        Ok(Self { address, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let (bytes, bits) = (usize::from(prefix / 8), prefix % 8);
+    if network[..bytes] != ip[..bytes] {
+        return false;
+    }
+    bits == 0 || (network[bytes] ^ ip[bytes]) >> (8 - bits) == 0
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclConfig {
+    pub allow_peers: Vec<PeerId>,
+    pub deny_peers: Vec<PeerId>,
+    pub allow_ips: Vec<Cidr>,
+    pub deny_ips: Vec<Cidr>,
+    /// Peer ids of the operator keys whose vouchers admit a peer.
+    pub voucher_issuers: Vec<PeerId>,
+}
+
+impl AclConfig {
+    /// Whether there is nothing to enforce.
+    pub fn is_open(&self) -> bool {
+        self.allow_peers.is_empty()
+            && self.deny_peers.is_empty()
+            && self.allow_ips.is_empty()
+            && self.deny_ips.is_empty()
+            && self.voucher_issuers.is_empty()
+    }
+
+    pub fn requires_vouchers(&self) -> bool {
+        !self.voucher_issuers.is_empty()
+    }
+}
+
+/// An operator's permission for `peer` to use a relay, see [`Voucher::issue`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Voucher {
+    pub issuer: PeerId,
+    pub peer: PeerId,
+    /// Seconds since the unix epoch.
+    pub expires: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct VoucherPayload {
+    peer: PeerId,
+    expires: u64,
+}
+
+impl Voucher {
+    /// Signs a voucher for `peer` lasting `valid_for`, encoded as a protobuf signed envelope.
+    pub fn issue(issuer: &Keypair, peer: PeerId, valid_for: Duration) -> anyhow::Result<Vec<u8>> {
+        let payload = VoucherPayload {
+            peer,
+            expires: unix_now() + valid_for.as_secs(),
+        };
+        let envelope = SignedEnvelope::new(
+            issuer,
+            VOUCHER_DOMAIN.to_string(),
+            VOUCHER_PAYLOAD_TYPE.to_vec(),
+            serde_json::to_vec(&payload)?,
+        )?;
+        Ok(envelope.into_protobuf_encoding())
+    }
+
+    /// Decodes a voucher and checks its signature, but not who signed it or its expiry.
+    pub fn open(envelope: &[u8]) -> anyhow::Result<Self> {
+        let envelope = SignedEnvelope::from_protobuf_encoding(envelope)?;
+        let (payload, key) = envelope
+            .payload_and_signing_key(VOUCHER_DOMAIN.to_string(), VOUCHER_PAYLOAD_TYPE)
+            .map_err(|e| anyhow::anyhow!("{e}"))?;
+        let payload: VoucherPayload = serde_json::from_slice(payload)?;
+        Ok(Self {
+            issuer: key.to_peer_id(),
+            peer: payload.peer,
+            expires: payload.expires,
+        })
+    }
+}
+
+/// Expiry of the valid voucher of each peer, in seconds since the unix epoch. Clones share the
+/// vouchers.
+#[derive(Debug, Clone, Default)]
+pub struct Vouchers {
+    inner: Arc<Mutex<HashMap<PeerId, u64>>>,
+}
+
+/// The access control list of a relay server along with the vouchers presented to it.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    config: Arc<AclConfig>,
+    vouchers: Vouchers,
+}
+
+impl Acl {
+    pub fn new(config: AclConfig, vouchers: Vouchers) -> Self {
+        Self {
+            config: Arc::new(config),
+            vouchers,
+        }
+    }
+
+    pub fn config(&self) -> &AclConfig {
+        &self.config
+    }
+
+    /// Limits reservations, or circuit sources when `reservations` is false, to the peers
+    /// admitted by the list.
+    pub(crate) fn limiter(&self, reservations: bool) -> impl Limiter {
+        AclLimiter {
+            acl: self.clone(),
+            reservations,
+        }
+    }
+
+    /// Keeps the voucher in `envelope` if it was issued to `peer` by one of the issuers and
+    /// has not expired, returning its expiry.
+    pub(crate) fn accept_voucher(&self, peer: PeerId, envelope: &[u8]) -> anyhow::Result<u64> {
+        let voucher = Voucher::open(envelope)?;
+        if !self.config.voucher_issuers.contains(&voucher.issuer) {
+            bail!("voucher issuer {} is not trusted", voucher.issuer);
+        }
+        if voucher.peer != peer {
+            bail!("voucher was issued to {}", voucher.peer);
+        }
+        if voucher.expires <= unix_now() {
+            bail!("voucher has expired");
+        }
+        self.lock().insert(peer, voucher.expires);
+        Ok(voucher.expires)
+    }
+
+    /// Whether `peer`, connected from `ip`, may reserve or, when `reservation` is false, open
+    /// a circuit. A refusal carries its reason.
+    pub(crate) fn check(
+        &self,
+        peer: PeerId,
+        ip: Option<IpAddr>,
+        reservation: bool,
+    ) -> Result<(), &'static str> {
+        let config = &self.config;
+        let in_any = |networks: &[Cidr]| {
+            ip.is_some_and(|ip| networks.iter().any(|network| network.contains(&ip)))
+        };
+        if config.deny_peers.contains(&peer) {
+            return Err("denied_peer");
+        }
+        if in_any(&config.deny_ips) {
+            return Err("denied_ip");
+        }
+        let listed = config.allow_peers.contains(&peer);
+        let restricted = !config.allow_peers.is_empty() || !config.allow_ips.is_empty();
+        if restricted && !listed && !in_any(&config.allow_ips) {
+            return Err("not_allowed");
+        }
+        if reservation && config.requires_vouchers() && !listed && !self.has_voucher(&peer) {
+            return Err("no_voucher");
+        }
+        Ok(())
+    }
+
+    /// How long until the voucher of `peer` expires, rounded up to whole seconds, or `None`
+    /// if it holds no voucher that is still valid.
+    pub(crate) fn voucher_expires_in(&self, peer: &PeerId) -> Option<Duration> {
+        let expires = *self.lock().get(peer)?;
+        let now = unix_now();
+        (expires >= now).then(|| Duration::from_secs(expires - now + 1))
+    }
+
+    fn has_voucher(&self, peer: &PeerId) -> bool {
+        let mut vouchers = self.lock();
+        match vouchers.get(peer) {
+            Some(&expires) if expires > unix_now() => true,
+            Some(_) => {
+                vouchers.remove(peer);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PeerId, u64>> {
+        self.vouchers.inner.lock().expect("vouchers lock")
+    }
+}
+
+struct AclLimiter {
+    acl: Acl,
+    reservations: bool,
+}
+
+impl Limiter for AclLimiter {
+    fn try_next(
+        &mut self,
+        peer: PeerId,
+        addr: &Multiaddr,
+        _now: Instant,
+    ) -> Result<(), &'static str> {
+        self.acl.check(peer, address::ip(addr), self.reservations)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_networks_and_plain_addresses() {
+        assert_eq!(cidr("10.1.2.3").to_string(), "10.1.2.3/32");
+        assert_eq!(cidr("2001:db8::1").to_string(), "2001:db8::1/128");
+        assert_eq!(cidr("0.0.0.0/0").to_string(), "0.0.0.0/0");
+        for invalid in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0/8",
+            "10.0.0.0/-1",
+        ] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{invalid} parsed");
+        }
+    }
+
+    #[test]
+    fn contains_addresses_sharing_the_prefix() {
+        assert!(cidr("0.0.0.0/0").contains(&ip("203.0.113.9")));
+        assert!(cidr("::/0").contains(&ip("2001:db8::1")));
+        assert!(cidr("192.0.2.7").contains(&ip("192.0.2.7")));
+        assert!(!cidr("192.0.2.7").contains(&ip("192.0.2.6")));
+        assert!(cidr("2001:db8::1/128").contains(&ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1/128").contains(&ip("2001:db8::2")));
+        // Prefixes ending inside a byte.
+        assert!(cidr("172.16.0.0/12").contains(&ip("172.31.255.255")));
+        assert!(!cidr("172.16.0.0/12").contains(&ip("172.32.0.0")));
+        assert!(cidr("10.128.0.0/9").contains(&ip("10.200.0.1")));
+        assert!(!cidr("10.128.0.0/9").contains(&ip("10.127.0.1")));
+        assert!(cidr("2001:db8::/33").contains(&ip("2001:db8:7fff::1")));
+        assert!(!cidr("2001:db8::/33").contains(&ip("2001:db8:8000::1")));
+    }
+
+    #[test]
+    fn networks_never_contain_the_other_address_family() {
+        assert!(!cidr("0.0.0.0/0").contains(&ip("::ffff:10.0.0.1")));
+        assert!(!cidr("::/0").contains(&ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn deny_lists_win_over_allow_lists() {
+        let (peer, other) = (PeerId::random(), PeerId::random());
+        let acl = Acl::new(
+            AclConfig {
+                allow_peers: vec![peer],
+                deny_peers: vec![other],
+                allow_ips: vec![cidr("10.0.0.0/8")],
+                deny_ips: vec![cidr("10.66.0.0/16")],
+                voucher_issuers: vec![],
+            },
+            Vouchers::default(),
+        );
+        assert_eq!(acl.check(peer, Some(ip("192.0.2.1")), true), Ok(()));
+        assert_eq!(
+            acl.check(peer, Some(ip("10.66.0.1")), true),
+            Err("denied_ip")
+        );
+        assert_eq!(
+            acl.check(other, Some(ip("10.0.0.1")), true),
+            Err("denied_peer")
+        );
+        let stranger = PeerId::random();
+        assert_eq!(acl.check(stranger, Some(ip("10.0.0.1")), true), Ok(()));
+        assert_eq!(
+            acl.check(stranger, Some(ip("192.0.2.1")), true),
+            Err("not_allowed")
+        );
+        assert_eq!(acl.check(stranger, None, false), Err("not_allowed"));
+    }
+
+    #[test]
+    fn vouchers_are_needed_to_reserve_unless_listed() {
+        let issuer = Keypair::generate_ed25519();
+        let listed = PeerId::random();
+        let acl = Acl::new(
+            AclConfig {
+                allow_peers: vec![listed],
+                allow_ips: vec![cidr("192.0.2.0/24")],
+                voucher_issuers: vec![issuer.public().to_peer_id()],
+                ..AclConfig::default()
+            },
+            Vouchers::default(),
+        );
+        let peer = PeerId::random();
+        let address = Some(ip("192.0.2.1"));
+        assert_eq!(acl.check(listed, address, true), Ok(()));
+        assert_eq!(acl.check(peer, address, true), Err("no_voucher"));
+        assert_eq!(acl.check(peer, address, false), Ok(()));
+        let voucher = Voucher::issue(&issuer, peer, Duration::from_secs(60)).unwrap();
+        acl.accept_voucher(peer, &voucher).unwrap();
+        assert_eq!(acl.check(peer, address, true), Ok(()));
+    }
+
+    #[test]
+    fn vouchers_round_trip() {
+        let issuer = Keypair::generate_ed25519();
+        let peer = PeerId::random();
+        let envelope = Voucher::issue(&issuer, peer, Duration::from_secs(60)).unwrap();
+        let voucher = Voucher::open(&envelope).unwrap();
+        assert_eq!(voucher.issuer, issuer.public().to_peer_id());
+        assert_eq!(voucher.peer, peer);
+        assert!(voucher.expires > unix_now());
+        let mut tampered = envelope.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(Voucher::open(&tampered).is_err());
+    }
+
+    #[test]
+    fn only_fresh_vouchers_of_trusted_issuers_for_the_peer_are_accepted() {
+        let issuer = Keypair::generate_ed25519();
+        let acl = Acl::new(
+            AclConfig {
+                voucher_issuers: vec![issuer.public().to_peer_id()],
+                ..AclConfig::default()
+            },
+            Vouchers::default(),
+        );
+        let peer = PeerId::random();
+        let expired = Voucher::issue(&issuer, peer, Duration::ZERO).unwrap();
+        assert!(acl.accept_voucher(peer, &expired).is_err());
+        let stranger = Keypair::generate_ed25519();
+        let untrusted = Voucher::issue(&stranger, peer, Duration::from_secs(60)).unwrap();
+        assert!(acl.accept_voucher(peer, &untrusted).is_err());
+        let valid = Voucher::issue(&issuer, peer, Duration::from_secs(60)).unwrap();
+        assert!(acl.accept_voucher(PeerId::random(), &valid).is_err());
+        assert!(acl.accept_voucher(peer, &valid).is_ok());
+    }
+
+    #[test]
+    fn expired_vouchers_stop_admitting() {
+        let acl = Acl::new(
+            AclConfig {
+                voucher_issuers: vec![PeerId::random()],
+                ..AclConfig::default()
+            },
+            Vouchers::default(),
+        );
+        let peer = PeerId::random();
+        acl.lock().insert(peer, unix_now() - 1);
+        assert_eq!(acl.check(peer, None, true), Err("no_voucher"));
+        assert!(acl.lock().is_empty());
+    }
+}
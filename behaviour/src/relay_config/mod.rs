@@ -0,0 +1,524 @@
+//! Limits of the relay server, read from a TOML file and overridden from the command line.
+//!
+//! Every key of the file is optional. The defaults are those of [`relay::Config::default`],
+//! which has no limit per IP address:
+//!
+//! ```toml
+//! max_reservations = 128
+//! max_reservations_per_peer = 4
+//! max_reservations_per_ip = 8
+//! reservation_duration_secs = 3600
+//! max_circuits = 16
+//! max_circuits_per_peer = 4
+//! max_circuit_duration_secs = 120
+//! max_circuit_bytes = 131072
+//!
+//! [reservation_rate_per_peer]
+//! limit = 30
+//! interval_secs = 120
+//! ```
+//!
+//! `reservation_rate_per_ip`, `circuit_rate_per_peer` and `circuit_rate_per_ip` take the same
+//! form. A rate with a `limit` of `0` is not enforced. Access control lives in the `[acl]`
+//! table, see [`relay_acl`](crate::relay_acl).
+
+use crate::address;
+use crate::relay_acl::{Acl, AclConfig, Vouchers};
+use anyhow::Context;
+use clap::Args;
+use libp2p::relay;
+use libp2p::{Multiaddr, PeerId};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// At most `limit` requests per `interval_secs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub limit: u32,
+    pub interval_secs: u64,
+}
+
+impl RateLimit {
+    const fn new(limit: u32, interval_secs: u64) -> Self {
+        Self {
+            limit,
+            interval_secs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayConfig {
+    pub max_reservations: usize,
+    pub max_reservations_per_peer: usize,
+    /// Live reservations of all peers connecting from one IP address, unlimited if unset.
+    pub max_reservations_per_ip: Option<usize>,
+    pub reservation_duration_secs: u64,
+    pub max_circuits: usize,
+    pub max_circuits_per_peer: usize,
+    pub max_circuit_duration_secs: u64,
+    /// Bytes relayed in each direction before a circuit is closed.
+    pub max_circuit_bytes: u64,
+    pub reservation_rate_per_peer: RateLimit,
+    pub reservation_rate_per_ip: RateLimit,
+    /// Circuits requested by one source peer.
+    pub circuit_rate_per_peer: RateLimit,
+    pub circuit_rate_per_ip: RateLimit,
+    pub acl: AclConfig,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        let defaults = relay::Config::default();
+        Self {
+            max_reservations: defaults.max_reservations,
+            max_reservations_per_peer: defaults.max_reservations_per_peer,
+            max_reservations_per_ip: None,
+            reservation_duration_secs: defaults.reservation_duration.as_secs(),
+            max_circuits: defaults.max_circuits,
+            max_circuits_per_peer: defaults.max_circuits_per_peer,
+            max_circuit_duration_secs: defaults.max_circuit_duration.as_secs(),
+            max_circuit_bytes: defaults.max_circuit_bytes,
+            // The rate limiters of `relay::Config::default`.
+            reservation_rate_per_peer: RateLimit::new(30, 120),
+            reservation_rate_per_ip: RateLimit::new(60, 60),
+            circuit_rate_per_peer: RateLimit::new(30, 120),
+            circuit_rate_per_ip: RateLimit::new(60, 60),
+            acl: AclConfig::default(),
+        }
+    }
+}
+
+impl RelayConfig {
+    pub fn reservation_duration(&self) -> Duration {
+        Duration::from_secs(self.reservation_duration_secs)
+    }
+
+    /// Builds the config of the libp2p relay behaviour, with a fresh set of rate limiters
+    /// sharing `state` with the node.
+    pub fn to_libp2p(&self, state: &LimiterState) -> relay::Config {
+        let mut config = relay::Config {
+            max_reservations: self.max_reservations,
+            max_reservations_per_peer: self.max_reservations_per_peer,
+            reservation_duration: self.reservation_duration(),
+            reservation_rate_limiters: Vec::new(),
+            max_circuits: self.max_circuits,
+            max_circuits_per_peer: self.max_circuits_per_peer,
+            max_circuit_duration: Duration::from_secs(self.max_circuit_duration_secs),
+            max_circuit_bytes: self.max_circuit_bytes,
+            circuit_src_rate_limiters: Vec::new(),
+        };
+        let rejections = &state.rejections;
+        // First, so refused peers do not use up the rates of the others.
+        if !self.acl.is_open() {
+            let acl = state.acl(&self.acl);
+            push_reservation_limiter(&mut config, rejections, acl.limiter(true));
+            push_circuit_limiter(&mut config, rejections, acl.limiter(false));
+        }
+        let rate = self.reservation_rate_per_peer;
+        if let Some(limiter) = window_limiter(rate, peer_key, "reservation_rate_per_peer") {
+            push_reservation_limiter(&mut config, rejections, limiter);
+        }
+        let rate = self.reservation_rate_per_ip;
+        if let Some(limiter) = window_limiter(rate, ip_key, "reservation_rate_per_ip") {
+            push_reservation_limiter(&mut config, rejections, limiter);
+        }
+        // Last, so the reservations it counts are the ones the relay accepts.
+        if let Some(max) = self.max_reservations_per_ip {
+            let limiter = IpReservationLimiter {
+                reservations: state.ip_reservations.clone(),
+                max,
+            };
+            push_reservation_limiter(&mut config, rejections, limiter);
+        }
+        let rate = self.circuit_rate_per_peer;
+        if let Some(limiter) = window_limiter(rate, peer_key, "circuit_rate_per_peer") {
+            push_circuit_limiter(&mut config, rejections, limiter);
+        }
+        let rate = self.circuit_rate_per_ip;
+        if let Some(limiter) = window_limiter(rate, ip_key, "circuit_rate_per_ip") {
+            push_circuit_limiter(&mut config, rejections, limiter);
+        }
+        config
+    }
+}
+
+/// Relay server options of the binaries. Flags override the keys of `--relay-config`.
+#[derive(Debug, Clone, Args)]
+pub struct RelayOpt {
+    /// TOML file with the relay limits, including the rate limits which have no flags
+    #[clap(long)]
+    pub relay_config: Option<PathBuf>,
+
+    #[clap(long)]
+    pub max_reservations: Option<usize>,
+
+    #[clap(long)]
+    pub max_reservations_per_peer: Option<usize>,
+
+    /// Live reservations of all peers connecting from one IP address
+    #[clap(long)]
+    pub max_reservations_per_ip: Option<usize>,
+
+    /// How long a reservation lasts before it has to be renewed, in seconds
+    #[clap(long)]
+    pub reservation_duration: Option<u64>,
+
+    #[clap(long)]
+    pub max_circuits: Option<usize>,
+
+    #[clap(long)]
+    pub max_circuits_per_peer: Option<usize>,
+
+    /// How long a circuit may stay open, in seconds
+    #[clap(long)]
+    pub max_circuit_duration: Option<u64>,
+
+    /// Bytes relayed in each direction before a circuit is closed
+    #[clap(long)]
+    pub max_circuit_bytes: Option<u64>,
+}
+
+impl RelayOpt {
+    pub fn load(&self) -> anyhow::Result<RelayConfig> {
+        let mut config = match &self.relay_config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("reading relay config {}", path.display()))?;
+                toml::from_str(&text)
+                    .with_context(|| format!("parsing relay config {}", path.display()))?
+            }
+            None => RelayConfig::default(),
+        };
+        if let Some(max) = self.max_reservations {
+            config.max_reservations = max;
+        }
+        if let Some(max) = self.max_reservations_per_peer {
+            config.max_reservations_per_peer = max;
+        }
+        if let Some(max) = self.max_reservations_per_ip {
+            config.max_reservations_per_ip = Some(max);
+        }
+        if let Some(secs) = self.reservation_duration {
+            config.reservation_duration_secs = secs;
+        }
+        if let Some(max) = self.max_circuits {
+            config.max_circuits = max;
+        }
+        if let Some(max) = self.max_circuits_per_peer {
+            config.max_circuits_per_peer = max;
+        }
+        if let Some(secs) = self.max_circuit_duration {
+            config.max_circuit_duration_secs = secs;
+        }
+        if let Some(bytes) = self.max_circuit_bytes {
+            config.max_circuit_bytes = bytes;
+        }
+        Ok(config)
+    }
+}
+
+/// State the limiters of a relay server share with the node following its events. Clones
+/// share it.
+///
+/// Kept apart from [`RelayConfig`], which only holds what the operator configured. The node has
+/// to be handed the same state the relay behaviour was built with, see
+/// [`Node::with_relay_config`](crate::node::Node::with_relay_config).
+#[derive(Debug, Clone, Default)]
+pub struct LimiterState {
+    pub(crate) vouchers: Vouchers,
+    pub(crate) ip_reservations: IpReservations,
+    pub(crate) rejections: Rejections,
+}
+
+impl LimiterState {
+    /// The access control list of `config` with the vouchers presented so far.
+    pub(crate) fn acl(&self, config: &AclConfig) -> Acl {
+        Acl::new(config.clone(), self.vouchers.clone())
+    }
+}
+
+/// The IP address each peer holding a reservation reserved from, to cap the reservations per
+/// address. A slot is taken when the limiter lets a request through and given back when the
+/// reservation ends or could not be made.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct IpReservations {
+    reserved: Arc<Mutex<HashMap<PeerId, IpAddr>>>,
+}
+
+impl IpReservations {
+    /// Takes a slot of `ip` for `peer` unless `max` peers hold one. Renewals keep theirs.
+    fn try_reserve(&self, peer: PeerId, ip: IpAddr, max: usize) -> bool {
+        let mut reserved = self.lock();
+        if reserved.contains_key(&peer) {
+            return true;
+        }
+        if reserved
+            .values()
+            .filter(|reserved| **reserved == ip)
+            .count()
+            >= max
+        {
+            return false;
+        }
+        reserved.insert(peer, ip);
+        true
+    }
+
+    /// Gives back the slot of `peer` once it holds no reservation.
+    pub(crate) fn release(&self, peer: &PeerId) {
+        self.lock().remove(peer);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PeerId, IpAddr>> {
+        self.reserved.lock().expect("ip reservations lock")
+    }
+}
+
+/// The kinds of requests a relay limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum RelayRequest {
+    Reservation,
+    Circuit,
+}
+
+/// What the limiters decided about the latest request of each peer, until the relay reports
+/// its outcome. Clones share the decisions.
+///
+/// libp2p runs the limiters in order and stops at the first refusal, so the recorded decision
+/// is the reason of the refusing limiter, or `Ok` once all of them let the request through. No
+/// decision means the request was refused before the limiters ran.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Rejections {
+    inner: Arc<Mutex<DecisionMap>>,
+}
+
+type DecisionMap = HashMap<(PeerId, RelayRequest), Result<(), &'static str>>;
+
+impl Rejections {
+    pub(crate) fn take(
+        &self,
+        peer: &PeerId,
+        request: RelayRequest,
+    ) -> Option<Result<(), &'static str>> {
+        self.lock().remove(&(*peer, request))
+    }
+
+    pub(crate) fn record(
+        &self,
+        peer: PeerId,
+        request: RelayRequest,
+        decision: Result<(), &'static str>,
+    ) {
+        self.lock().insert((peer, request), decision);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, DecisionMap> {
+        self.inner.lock().expect("rejections lock")
+    }
+}
+
+/// Decides whether the relay serves a request, like the rate limiters of libp2p whose trait is
+/// not exported. A refusal carries the reason reported for the denial.
+pub(crate) trait Limiter: Send + 'static {
+    fn try_next(
+        &mut self,
+        peer: PeerId,
+        addr: &Multiaddr,
+        now: Instant,
+    ) -> Result<(), &'static str>;
+}
+
+fn push_reservation_limiter(
+    config: &mut relay::Config,
+    rejections: &Rejections,
+    limiter: impl Limiter,
+) {
+    let limiter = recorded(limiter, rejections.clone(), RelayRequest::Reservation);
+    config.reservation_rate_limiters.push(Box::new(limiter));
+}
+
+fn push_circuit_limiter(
+    config: &mut relay::Config,
+    rejections: &Rejections,
+    limiter: impl Limiter,
+) {
+    let limiter = recorded(limiter, rejections.clone(), RelayRequest::Circuit);
+    config.circuit_src_rate_limiters.push(Box::new(limiter));
+}
+
+/// `limiter` as the closure libp2p takes, recording each decision in `rejections`.
+fn recorded(
+    mut limiter: impl Limiter,
+    rejections: Rejections,
+    request: RelayRequest,
+) -> impl FnMut(PeerId, &Multiaddr, Instant) -> bool + Send + 'static {
+    move |peer, addr, now| {
+        let decision = limiter.try_next(peer, addr, now);
+        rejections.record(peer, request, decision);
+        decision.is_ok()
+    }
+}
+
+struct IpReservationLimiter {
+    reservations: IpReservations,
+    max: usize,
+}
+
+impl Limiter for IpReservationLimiter {
+    fn try_next(
+        &mut self,
+        peer: PeerId,
+        addr: &Multiaddr,
+        _now: Instant,
+    ) -> Result<(), &'static str> {
+        match ip_key(peer, addr) {
+            Some(ip) if !self.reservations.try_reserve(peer, ip, self.max) => {
+                Err("max_reservations_per_ip")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Allows `limit` requests per key in every window of `interval`. Requests without a key,
+/// such as IP limits for peers reached over a relay, are always allowed.
+struct WindowLimiter<K> {
+    limit: u32,
+    interval: Duration,
+    key: fn(PeerId, &Multiaddr) -> Option<K>,
+    windows: HashMap<K, (Instant, u32)>,
+    reason: &'static str,
+}
+
+impl<K: Hash + Eq + Send + 'static> Limiter for WindowLimiter<K> {
+    fn try_next(
+        &mut self,
+        peer: PeerId,
+        addr: &Multiaddr,
+        now: Instant,
+    ) -> Result<(), &'static str> {
+        let Some(key) = (self.key)(peer, addr) else {
+            return Ok(());
+        };
+        let interval = self.interval;
+        if !self.windows.contains_key(&key) {
+            self.windows
+                .retain(|_, (start, _)| now.duration_since(*start) < interval);
+        }
+        let (start, count) = self.windows.entry(key).or_insert((now, 0));
+        if now.duration_since(*start) >= interval {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= self.limit {
+            return Err(self.reason);
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+/// The limiter enforcing `rate`, refusing with `reason`, `None` if its limit is `0`.
+fn window_limiter<K: Hash + Eq + Send + 'static>(
+    rate: RateLimit,
+    key: fn(PeerId, &Multiaddr) -> Option<K>,
+    reason: &'static str,
+) -> Option<WindowLimiter<K>> {
+    (rate.limit != 0).then(|| WindowLimiter {
+        limit: rate.limit,
+        interval: Duration::from_secs(rate.interval_secs),
+        key,
+        windows: HashMap::new(),
+        reason,
+    })
+}
+
+fn peer_key(peer: PeerId, _: &Multiaddr) -> Option<PeerId> {
+    Some(peer)
+}
+
+fn ip_key(_: PeerId, addr: &Multiaddr) -> Option<IpAddr> {
+    address::ip(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(ip: &str) -> Multiaddr {
+        format!("/ip4/{ip}/tcp/4001").parse().unwrap()
+    }
+
+    #[test]
+    fn window_limiter_allows_limit_per_interval_and_key() {
+        let rate = RateLimit::new(2, 10);
+        let mut limiter = window_limiter(rate, peer_key, "rate").unwrap();
+        let (peer, other) = (PeerId::random(), PeerId::random());
+        let addr = address("192.0.2.1");
+        let start = Instant::now();
+        assert_eq!(limiter.try_next(peer, &addr, start), Ok(()));
+        assert_eq!(limiter.try_next(peer, &addr, start), Ok(()));
+        assert_eq!(limiter.try_next(peer, &addr, start), Err("rate"));
+        assert_eq!(limiter.try_next(other, &addr, start), Ok(()));
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.try_next(peer, &addr, later), Ok(()));
+    }
+
+    #[test]
+    fn window_limiter_allows_requests_without_a_key() {
+        let mut limiter = window_limiter(RateLimit::new(1, 10), ip_key, "rate").unwrap();
+        let relayed: Multiaddr = "/p2p-circuit".parse().unwrap();
+        for _ in 0..3 {
+            assert_eq!(
+                limiter.try_next(PeerId::random(), &relayed, Instant::now()),
+                Ok(())
+            );
+        }
+        assert!(window_limiter(RateLimit::new(0, 10), ip_key, "rate").is_none());
+    }
+
+    #[test]
+    fn ip_limiter_counts_requests_as_they_pass() {
+        let reservations = IpReservations::default();
+        let mut limiter = IpReservationLimiter {
+            reservations: reservations.clone(),
+            max: 1,
+        };
+        let (first, second) = (PeerId::random(), PeerId::random());
+        let addr = address("192.0.2.1");
+        let now = Instant::now();
+        assert_eq!(limiter.try_next(first, &addr, now), Ok(()));
+        // Before the relay reports the first reservation.
+        let refused = limiter.try_next(second, &addr, now);
+        assert_eq!(refused, Err("max_reservations_per_ip"));
+        assert_eq!(limiter.try_next(first, &addr, now), Ok(()));
+        assert_eq!(limiter.try_next(second, &address("192.0.2.2"), now), Ok(()));
+        reservations.release(&first);
+        let third = PeerId::random();
+        assert_eq!(limiter.try_next(third, &addr, now), Ok(()));
+    }
+
+    #[test]
+    fn decisions_are_recorded_per_peer_and_request() {
+        let rejections = Rejections::default();
+        let limiter = window_limiter(RateLimit::new(1, 10), peer_key, "rate").unwrap();
+        let mut limiter = recorded(limiter, rejections.clone(), RelayRequest::Circuit);
+        let (peer, addr) = (PeerId::random(), address("192.0.2.1"));
+        assert!(limiter(peer, &addr, Instant::now()));
+        assert!(!limiter(peer, &addr, Instant::now()));
+        assert_eq!(rejections.take(&peer, RelayRequest::Reservation), None);
+        assert_eq!(
+            rejections.take(&peer, RelayRequest::Circuit),
+            Some(Err("rate"))
+        );
+        assert_eq!(rejections.take(&peer, RelayRequest::Circuit), None);
+    }
+}
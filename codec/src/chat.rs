@@ -1,96 +1,415 @@
+use crate::frame::{read_frame, write_frame, MessageTooLarge};
 use async_trait::async_trait;
-use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed};
 use libp2p::futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use libp2p::request_response::{Codec, ProtocolName};
+use serde::{Deserialize, Serialize};
 use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Raw bytes, kept for peers that predate the envelope.
 pub const CHAT_PROTOCOL: &str = "/chat/0.1.0";
+/// CBOR encoded [`ChatEnvelope`].
+pub const CHAT_PROTOCOL_V2: &str = "/chat/0.2.0";
 
-#[derive(Debug, Clone)]
-pub struct ChatProtocol;
+/// Default limit for both requests and responses.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+pub const CONTENT_TYPE_BYTES: &str = "application/octet-stream";
+pub const CONTENT_TYPE_TEXT: &str = "text/plain; charset=utf-8";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatProtocol {
+    V1,
+    V2,
+}
+
+impl ChatProtocol {
+    /// Every supported version, preferred first.
+    pub fn all() -> [ChatProtocol; 2] {
+        [ChatProtocol::V2, ChatProtocol::V1]
+    }
+}
 
 impl ProtocolName for ChatProtocol {
     fn protocol_name(&self) -> &[u8] {
-        CHAT_PROTOCOL.as_bytes()
+        match self {
+            ChatProtocol::V1 => CHAT_PROTOCOL.as_bytes(),
+            ChatProtocol::V2 => CHAT_PROTOCOL_V2.as_bytes(),
+        }
+    }
+}
+
+/// A chat message with the metadata carried by `/chat/0.2.0`.
+///
+/// Messages from `/chat/0.1.0` peers only carry a body, their id and timestamp are assigned on
+/// receipt.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatEnvelope {
+    pub id: String,
+    /// Milliseconds since the unix epoch on the sender's clock.
+    pub timestamp: u64,
+    pub content_type: String,
+    pub body: Vec<u8>,
+    pub reply_to: Option<String>,
+}
+
+impl ChatEnvelope {
+    pub fn new(content_type: impl Into<String>, body: Vec<u8>) -> Self {
+        Self {
+            id: new_message_id(),
+            timestamp: now_millis(),
+            content_type: content_type.into(),
+            body,
+            reply_to: None,
+        }
+    }
+
+    pub fn bytes(body: Vec<u8>) -> Self {
+        Self::new(CONTENT_TYPE_BYTES, body)
+    }
+
+    pub fn text(body: impl Into<String>) -> Self {
+        Self::new(CONTENT_TYPE_TEXT, body.into().into_bytes())
+    }
+
+    /// Length of the `/chat/0.2.0` frame carrying this message, the larger of the two encodings,
+    /// so a message within a limit by this measure fits on either protocol.
+    pub fn frame_size(&self) -> usize {
+        self.to_cbor().map(|data| data.len()).unwrap_or(usize::MAX)
+    }
+
+    fn to_cbor(&self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        ciborium::ser::into_writer(self, &mut data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        Ok(data)
+    }
+
+    /// A new message of the same content type answering this one.
+    pub fn reply(&self, body: Vec<u8>) -> Self {
+        Self {
+            reply_to: Some(self.id.clone()),
+            ..Self::new(self.content_type.clone(), body)
+        }
     }
 }
 
+fn new_message_id() -> String {
+    let id: [u8; 16] = rand::random();
+    id.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
 #[derive(Debug, Clone)]
-pub struct ChatRequest(pub Vec<u8>);
+pub struct ChatRequest(pub ChatEnvelope);
 
 impl ChatRequest {
     pub fn data(&self) -> &Vec<u8> {
-        self.0.as_ref()
+        &self.0.body
+    }
+}
+
+impl From<Vec<u8>> for ChatRequest {
+    fn from(body: Vec<u8>) -> Self {
+        ChatRequest(ChatEnvelope::bytes(body))
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct ChatResponse(pub Vec<u8>);
+pub struct ChatResponse(pub ChatEnvelope);
+
+impl ChatResponse {
+    pub fn data(&self) -> &Vec<u8> {
+        &self.0.body
+    }
+}
+
+impl From<Vec<u8>> for ChatResponse {
+    fn from(body: Vec<u8>) -> Self {
+        ChatResponse(ChatEnvelope::bytes(body))
+    }
+}
+
+/// A chat response as the requester reads it: the response, or the size of a response that
+/// exceeded `max_response_size` and was left unread.
+pub type ChatReply = Result<ChatResponse, MessageTooLarge>;
 
+/// Length-prefixed chat codec.
+///
+/// Requests above the configured limits are rejected with an [`io::Error`] wrapping
+/// [`MessageTooLarge`]. Responses that are too large are read as a [`ChatReply`] error instead,
+/// which libp2p hands back to the requester rather than closing the connection over it.
 #[derive(Debug, Clone)]
-pub struct ChatCodec;
+pub struct ChatCodec {
+    pub max_request_size: usize,
+    pub max_response_size: usize,
+}
+
+impl ChatCodec {
+    pub fn new(max_request_size: usize, max_response_size: usize) -> Self {
+        Self {
+            max_request_size,
+            max_response_size,
+        }
+    }
+
+    async fn read_envelope<T>(
+        protocol: &ChatProtocol,
+        io: &mut T,
+        max: usize,
+    ) -> io::Result<ChatEnvelope>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_frame(io, max).await?;
+        if data.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        match protocol {
+            ChatProtocol::V1 => Ok(ChatEnvelope::bytes(data)),
+            ChatProtocol::V2 => ciborium::de::from_reader(data.as_slice())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        }
+    }
+
+    async fn write_envelope<T>(
+        protocol: &ChatProtocol,
+        io: &mut T,
+        envelope: ChatEnvelope,
+        max: usize,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let data = match protocol {
+            ChatProtocol::V1 => envelope.body,
+            ChatProtocol::V2 => envelope.to_cbor()?,
+        };
+        write_frame(io, &data, max).await?;
+        io.close().await?;
+        Ok(())
+    }
+}
+
+impl Default for ChatCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_MAX_MESSAGE_SIZE)
+    }
+}
 
 #[async_trait]
 impl Codec for ChatCodec {
     type Protocol = ChatProtocol;
     type Request = ChatRequest;
-    type Response = ChatResponse;
+    type Response = ChatReply;
 
     async fn read_request<T>(
         &mut self,
-        _protocol: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
     ) -> io::Result<Self::Request>
     where
         T: AsyncRead + Unpin + Send,
     {
-        let data = read_length_prefixed(io, 1024).await?;
-        if data.is_empty() {
-            return Err(io::ErrorKind::UnexpectedEof.into());
-        }
-        Ok(ChatRequest(data))
+        Self::read_envelope(protocol, io, self.max_request_size)
+            .await
+            .map(ChatRequest)
     }
 
     async fn read_response<T>(
         &mut self,
-        _protocol: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
     ) -> io::Result<Self::Response>
     where
         T: AsyncRead + Unpin + Send,
     {
-        let data = read_length_prefixed(io, 1024).await?;
-        if data.is_empty() {
-            return Err(io::ErrorKind::UnexpectedEof.into());
+        match Self::read_envelope(protocol, io, self.max_response_size).await {
+            Ok(envelope) => Ok(Ok(ChatResponse(envelope))),
+            Err(e) => match MessageTooLarge::from_io_error(&e) {
+                Some(too_large) => Ok(Err(*too_large)),
+                None => Err(e),
+            },
         }
-        Ok(ChatResponse(data))
     }
 
     async fn write_request<T>(
         &mut self,
-        _protocol: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
         req: Self::Request,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Unpin + Send,
     {
-        write_length_prefixed(io, req.0).await?;
-        io.close().await?;
-        Ok(())
+        Self::write_envelope(protocol, io, req.0, self.max_request_size).await
     }
 
     async fn write_response<T>(
         &mut self,
-        _protocol: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
         res: Self::Response,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Unpin + Send,
     {
-        write_length_prefixed(io, res.0).await?;
-        io.close().await?;
-        Ok(())
+        match res {
+            Ok(response) => {
+                Self::write_envelope(protocol, io, response.0, self.max_response_size).await
+            }
+            // Only ever read, there is nothing to send for it.
+            Err(too_large) => Err(io::Error::new(io::ErrorKind::InvalidInput, too_large)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::futures::executor::block_on;
+    use libp2p::futures::io::Cursor;
+
+    fn write(protocol: ChatProtocol, envelope: &ChatEnvelope, max: usize) -> io::Result<()> {
+        let mut io = Cursor::new(Vec::new());
+        block_on(ChatCodec::write_envelope(
+            &protocol,
+            &mut io,
+            envelope.clone(),
+            max,
+        ))
+    }
+
+    /// Writes `request` with `writer` and reads it back with `reader`.
+    fn request_round_trip(
+        protocol: ChatProtocol,
+        writer: &mut ChatCodec,
+        reader: &mut ChatCodec,
+        request: ChatRequest,
+    ) -> io::Result<ChatRequest> {
+        block_on(async {
+            let mut io = Cursor::new(Vec::new());
+            writer.write_request(&protocol, &mut io, request).await?;
+            io.set_position(0);
+            reader.read_request(&protocol, &mut io).await
+        })
+    }
+
+    fn response_round_trip(
+        protocol: ChatProtocol,
+        writer: &mut ChatCodec,
+        reader: &mut ChatCodec,
+        response: ChatResponse,
+    ) -> io::Result<ChatReply> {
+        block_on(async {
+            let mut io = Cursor::new(Vec::new());
+            writer
+                .write_response(&protocol, &mut io, Ok(response))
+                .await?;
+            io.set_position(0);
+            reader.read_response(&protocol, &mut io).await
+        })
+    }
+
+    #[test]
+    fn v2_carries_the_whole_envelope() {
+        let mut codec = ChatCodec::default();
+        let request = ChatEnvelope::text("hello");
+        let read = request_round_trip(
+            ChatProtocol::V2,
+            &mut codec.clone(),
+            &mut codec,
+            ChatRequest(request.clone()),
+        )
+        .unwrap();
+        assert_eq!(read.0, request);
+
+        let response = request.reply(b"hi".to_vec());
+        assert_eq!(response.reply_to.as_deref(), Some(request.id.as_str()));
+        assert_eq!(response.content_type, CONTENT_TYPE_TEXT);
+        let read = response_round_trip(
+            ChatProtocol::V2,
+            &mut codec.clone(),
+            &mut codec,
+            ChatResponse(response.clone()),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(read.0, response);
+    }
+
+    #[test]
+    fn v1_carries_the_body_only() {
+        let mut codec = ChatCodec::default();
+        let mut sent = ChatEnvelope::text("hello");
+        sent.timestamp = 1;
+        let read = request_round_trip(
+            ChatProtocol::V1,
+            &mut codec.clone(),
+            &mut codec,
+            ChatRequest(sent.clone()),
+        )
+        .unwrap();
+        assert_eq!(read.data(), &sent.body);
+        assert_eq!(read.0.content_type, CONTENT_TYPE_BYTES);
+        assert_ne!(read.0.id, sent.id);
+        assert_eq!(read.0.id.len(), 32);
+        assert!(read.0.timestamp > sent.timestamp);
+
+        let response = sent.reply(b"hi".to_vec());
+        let read = response_round_trip(
+            ChatProtocol::V1,
+            &mut codec.clone(),
+            &mut codec,
+            ChatResponse(response),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(read.data(), b"hi");
+        assert_eq!(read.0.reply_to, None);
+    }
+
+    #[test]
+    fn oversized_messages_fail_only_requests() {
+        let envelope = ChatEnvelope::bytes(vec![0; 100]);
+        for protocol in ChatProtocol::all() {
+            let mut writer = ChatCodec::default();
+            let mut reader = ChatCodec::new(10, 10);
+            let error = request_round_trip(
+                protocol,
+                &mut writer,
+                &mut reader,
+                ChatRequest(envelope.clone()),
+            )
+            .unwrap_err();
+            assert!(MessageTooLarge::from_io_error(&error).is_some());
+
+            let reply = response_round_trip(
+                protocol,
+                &mut writer,
+                &mut reader,
+                ChatResponse(envelope.clone()),
+            )
+            .unwrap();
+            assert!(reply.is_err(), "{protocol:?}");
+        }
+    }
+
+    #[test]
+    fn frame_size_is_the_limit_on_both_protocols() {
+        let envelope = ChatEnvelope::text("hello");
+        let size = envelope.frame_size();
+        assert!(size > envelope.body.len());
+        for protocol in ChatProtocol::all() {
+            assert!(write(protocol, &envelope, size).is_ok());
+        }
+        let error = write(ChatProtocol::V2, &envelope, size - 1).unwrap_err();
+        assert!(MessageTooLarge::from_io_error(&error).is_some());
     }
 }
@@ -0,0 +1,126 @@
+use libp2p::core::upgrade::{read_varint, write_length_prefixed};
+use libp2p::futures::{AsyncRead, AsyncReadExt, AsyncWrite};
+use std::{fmt, io};
+
+/// A length-prefixed message was larger than the limit configured for it.
+///
+/// Travels inside the [`io::Error`] returned by [`read_frame`] and [`write_frame`], use
+/// [`MessageTooLarge::from_io_error`] to get it back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageTooLarge {
+    pub size: usize,
+    pub max: usize,
+}
+
+impl MessageTooLarge {
+    pub fn from_io_error(error: &io::Error) -> Option<&MessageTooLarge> {
+        error.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for MessageTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "message of {} bytes exceeds the maximum of {} bytes",
+            self.size, self.max
+        )
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
+
+/// Reads one length-prefixed message of at most `max` bytes.
+pub async fn read_frame<T>(io: &mut T, max: usize) -> io::Result<Vec<u8>>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let size = read_varint(io).await?;
+    if size > max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            MessageTooLarge { size, max },
+        ));
+    }
+    let mut data = vec![0; size];
+    io.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+/// Writes one length-prefixed message, refusing to send more than `max` bytes.
+pub async fn write_frame<T>(io: &mut T, data: &[u8], max: usize) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    if data.len() > max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            MessageTooLarge {
+                size: data.len(),
+                max,
+            },
+        ));
+    }
+    write_length_prefixed(io, data).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::futures::executor::block_on;
+    use libp2p::futures::io::Cursor;
+
+    const MAX: usize = 300;
+
+    fn frame(size: usize) -> Vec<u8> {
+        let mut io = Cursor::new(Vec::new());
+        block_on(write_length_prefixed(&mut io, vec![7; size])).unwrap();
+        io.into_inner()
+    }
+
+    #[test]
+    fn frame_at_the_limit_round_trips() {
+        let mut io = Cursor::new(Vec::new());
+        block_on(write_frame(&mut io, &[7; MAX], MAX)).unwrap();
+        let mut io = Cursor::new(io.into_inner());
+        assert_eq!(block_on(read_frame(&mut io, MAX)).unwrap(), vec![7; MAX]);
+    }
+
+    #[test]
+    fn writing_above_the_limit_fails_without_writing() {
+        let mut io = Cursor::new(Vec::new());
+        let error = block_on(write_frame(&mut io, &[7; MAX + 1], MAX)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            MessageTooLarge::from_io_error(&error),
+            Some(&MessageTooLarge {
+                size: MAX + 1,
+                max: MAX
+            })
+        );
+        assert!(io.into_inner().is_empty());
+    }
+
+    #[test]
+    fn reading_above_the_limit_fails() {
+        let mut io = Cursor::new(frame(MAX + 1));
+        let error = block_on(read_frame(&mut io, MAX)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            MessageTooLarge::from_io_error(&error),
+            Some(&MessageTooLarge {
+                size: MAX + 1,
+                max: MAX
+            })
+        );
+    }
+
+    #[test]
+    fn reading_a_truncated_frame_fails() {
+        let mut data = frame(MAX);
+        data.pop();
+        let error = block_on(read_frame(&mut Cursor::new(data), MAX)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(MessageTooLarge::from_io_error(&error).is_none());
+    }
+}
@@ -1 +1,4 @@
 pub mod chat;
+pub mod frame;
+pub mod serde_codec;
+pub mod voucher;
@@ -0,0 +1,317 @@
+use crate::chat::DEFAULT_MAX_MESSAGE_SIZE;
+use crate::frame::{read_frame, write_frame};
+use async_trait::async_trait;
+use libp2p::futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use libp2p::request_response::Codec;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+
+/// Serialization used by a [`SerdeCodec`] on the wire.
+pub trait Format: Send + 'static {
+    fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>>;
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> io::Result<T>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Format for Json {
+    fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> io::Result<T> {
+        serde_json::from_slice(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+impl Format for Cbor {
+    fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        ciborium::ser::into_writer(value, &mut data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        Ok(data)
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> io::Result<T> {
+        ciborium::de::from_reader(data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+/// Protocol name negotiated by a [`SerdeCodec`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerdeProtocol(pub &'static str);
+
+impl AsRef<[u8]> for SerdeProtocol {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+/// Carries the type parameters of [`SerdeCodec`] without owning any of them, so the codec is
+/// `Send` and `Sync` whatever they are.
+type Types<Req, Resp, F> = fn() -> (Req, Resp, F);
+
+/// Request-response codec for any serde types, framed and size limited like the chat codec.
+///
+/// Declare new protocols with [`serde_protocol!`](crate::serde_protocol).
+pub struct SerdeCodec<Req, Resp, F> {
+    pub max_request_size: usize,
+    pub max_response_size: usize,
+    _marker: PhantomData<Types<Req, Resp, F>>,
+}
+
+impl<Req, Resp, F> SerdeCodec<Req, Resp, F> {
+    pub fn new(max_request_size: usize, max_response_size: usize) -> Self {
+        Self {
+            max_request_size,
+            max_response_size,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<Req, Resp, F> Default for SerdeCodec<Req, Resp, F> {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_MAX_MESSAGE_SIZE)
+    }
+}
+
+impl<Req, Resp, F> Clone for SerdeCodec<Req, Resp, F> {
+    fn clone(&self) -> Self {
+        Self::new(self.max_request_size, self.max_response_size)
+    }
+}
+
+impl<Req, Resp, F> fmt::Debug for SerdeCodec<Req, Resp, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SerdeCodec")
+            .field("max_request_size", &self.max_request_size)
+            .field("max_response_size", &self.max_response_size)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl<Req, Resp, F> Codec for SerdeCodec<Req, Resp, F>
+where
+    Req: Serialize + DeserializeOwned + Send,
+    Resp: Serialize + DeserializeOwned + Send,
+    F: Format,
+{
+    type Protocol = SerdeProtocol;
+    type Request = Req;
+    type Response = Resp;
+
+    async fn read_request<T>(
+        &mut self,
+        _protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_frame(io, self.max_request_size).await?;
+        F::decode(&data)
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_frame(io, self.max_response_size).await?;
+        F::decode(&data)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _protocol: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frame(io, &F::encode(&req)?, self.max_request_size).await?;
+        io.close().await?;
+        Ok(())
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _protocol: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frame(io, &F::encode(&res)?, self.max_response_size).await?;
+        io.close().await?;
+        Ok(())
+    }
+}
+
+/// Declares a typed request-response protocol backed by [`SerdeCodec`].
+///
+/// ```ignore
+/// serde_protocol! {
+///     /// Asks a peer for the time.
+///     pub TimeCodec, TIME_PROTOCOL = "/time/0.1.0", TimeRequest => TimeResponse, Json
+/// }
+/// ```
+///
+/// expands to a `TimeCodec` type alias and a `TIME_PROTOCOL` [`SerdeProtocol`] constant.
+#[macro_export]
+macro_rules! serde_protocol {
+    (
+        $(#[$meta:meta])*
+        $vis:vis $codec:ident, $protocol:ident = $name:literal,
+        $request:ty => $response:ty, $format:ty
+    ) => {
+        $(#[$meta])*
+        $vis type $codec = $crate::serde_codec::SerdeCodec<$request, $response, $format>;
+
+        $vis const $protocol: $crate::serde_codec::SerdeProtocol =
+            $crate::serde_codec::SerdeProtocol($name);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::MessageTooLarge;
+    use libp2p::futures::executor::block_on;
+    use libp2p::futures::io::Cursor;
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Echo {
+        text: String,
+        count: u32,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    enum Answer {
+        Echoed(Echo),
+        Refused { reason: String },
+    }
+
+    crate::serde_protocol! {
+        JsonEcho, JSON_ECHO = "/echo/json/0.1.0", Echo => Answer, Json
+    }
+
+    crate::serde_protocol! {
+        CborEcho, CBOR_ECHO = "/echo/cbor/0.1.0", Echo => Answer, Cbor
+    }
+
+    fn echo() -> Echo {
+        Echo {
+            text: "hello".to_string(),
+            count: 3,
+        }
+    }
+
+    fn round_trip<F: Format>(codec: &mut SerdeCodec<Echo, Answer, F>, protocol: SerdeProtocol) {
+        block_on(async {
+            let mut io = Cursor::new(Vec::new());
+            codec
+                .write_request(&protocol, &mut io, echo())
+                .await
+                .unwrap();
+            io.set_position(0);
+            assert_eq!(
+                codec.read_request(&protocol, &mut io).await.unwrap(),
+                echo()
+            );
+
+            for answer in [
+                Answer::Echoed(echo()),
+                Answer::Refused {
+                    reason: "busy".to_string(),
+                },
+            ] {
+                let mut io = Cursor::new(Vec::new());
+                codec
+                    .write_response(&protocol, &mut io, answer.clone())
+                    .await
+                    .unwrap();
+                io.set_position(0);
+                assert_eq!(
+                    codec.read_response(&protocol, &mut io).await.unwrap(),
+                    answer
+                );
+            }
+        })
+    }
+
+    #[test]
+    fn json_round_trips() {
+        round_trip(&mut JsonEcho::default(), JSON_ECHO);
+    }
+
+    #[test]
+    fn cbor_round_trips() {
+        round_trip(&mut CborEcho::default(), CBOR_ECHO);
+    }
+
+    #[test]
+    fn formats_differ_on_the_wire() {
+        assert_eq!(
+            Json::encode(&echo()).unwrap(),
+            br#"{"text":"hello","count":3}"#
+        );
+        let cbor = Cbor::encode(&echo()).unwrap();
+        assert_eq!(Cbor::decode::<Echo>(&cbor).unwrap(), echo());
+        assert!(Json::decode::<Echo>(&cbor).is_err());
+    }
+
+    #[test]
+    fn messages_above_the_limits_fail() {
+        let mut codec = JsonEcho::new(8, 8);
+        block_on(async {
+            let mut io = Cursor::new(Vec::new());
+            let error = codec
+                .write_request(&JSON_ECHO, &mut io, echo())
+                .await
+                .unwrap_err();
+            assert!(MessageTooLarge::from_io_error(&error).is_some());
+            let error = codec
+                .write_response(&JSON_ECHO, &mut io, Answer::Echoed(echo()))
+                .await
+                .unwrap_err();
+            assert!(MessageTooLarge::from_io_error(&error).is_some());
+
+            // Read back by a peer with lower limits than the sender's.
+            let mut io = Cursor::new(Vec::new());
+            JsonEcho::default()
+                .write_request(&JSON_ECHO, &mut io, echo())
+                .await
+                .unwrap();
+            io.set_position(0);
+            let error = codec.read_request(&JSON_ECHO, &mut io).await.unwrap_err();
+            assert!(MessageTooLarge::from_io_error(&error).is_some());
+
+            let mut io = Cursor::new(Vec::new());
+            JsonEcho::default()
+                .write_response(&JSON_ECHO, &mut io, Answer::Echoed(echo()))
+                .await
+                .unwrap();
+            io.set_position(0);
+            let error = codec.read_response(&JSON_ECHO, &mut io).await.unwrap_err();
+            assert!(MessageTooLarge::from_io_error(&error).is_some());
+        })
+    }
+}
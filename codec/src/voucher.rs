@@ -0,0 +1,27 @@
+//! `/relay-voucher/0.1.0`: presents an operator signed voucher to a relay server before
+//! reserving a slot on it.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VoucherRequest {
+    /// The voucher as a protobuf encoded libp2p signed envelope.
+    pub envelope: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VoucherResponse {
+    /// The relay admits the requester until `expires`, in seconds since the unix epoch.
+    Accepted {
+        expires: u64,
+    },
+    Rejected {
+        reason: String,
+    },
+}
+
+crate::serde_protocol! {
+    /// Hands a voucher to a relay server.
+    pub VoucherCodec, VOUCHER_PROTOCOL = "/relay-voucher/0.1.0",
+    VoucherRequest => VoucherResponse, crate::serde_codec::Json
+}
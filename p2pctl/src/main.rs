@@ -0,0 +1,275 @@
+use anyhow::{bail, Context};
+use behaviour::audit;
+use behaviour::key::keystore::{self, PassphraseOpt};
+use behaviour::key::{self, KeyFormat, KeyType};
+use behaviour::logging::LogOpt;
+use behaviour::relay_acl::Voucher;
+use clap::{Args, Parser, Subcommand};
+use libp2p::identity::{Keypair, PeerId};
+use libp2p::{core::multiaddr::Protocol, core::Multiaddr};
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+fn main() -> anyhow::Result<()> {
+    let opt = Opt::parse();
+    opt.log.init()?;
+    match opt.command {
+        Command::Keygen {
+            out,
+            key_type,
+            format,
+            encrypt,
+            passphrase,
+        } => {
+            let keypair = key::generate(key_type);
+            if encrypt {
+                key::save_encrypted(&out, &keypair, &passphrase.read(true)?)?;
+            } else {
+                key::save(&out, &keypair, format)?;
+            }
+            println!("{}", keypair.public().to_peer_id());
+        }
+        Command::Inspect { input } => {
+            let keypair = input.load()?;
+            let public = keypair.public();
+            println!("Peer id:    {}", public.to_peer_id());
+            if let Some(key_type) = key::key_type(&keypair) {
+                println!("Key type:   {key_type:?}");
+            }
+            println!("Public key: {}", hex::encode(public.encode_protobuf()));
+        }
+        Command::Convert {
+            input,
+            out,
+            to,
+            encrypt,
+        } => {
+            let keypair = input.load()?;
+            match to {
+                Some(format) => key::save(&out, &keypair, format)?,
+                None if encrypt => {
+                    key::save_encrypted(&out, &keypair, &input.passphrase.read(true)?)?
+                }
+                None => bail!("either --to or --encrypt is required"),
+            }
+            println!("{}", keypair.public().to_peer_id());
+        }
+        Command::Multiaddr {
+            ip,
+            port,
+            peer_id,
+            key_file,
+            circuit,
+            passphrase,
+        } => {
+            let peer_id = match (peer_id, key_file) {
+                (Some(peer_id), _) => peer_id,
+                (None, Some(path)) => key::load(&path, &passphrase)?.public().to_peer_id(),
+                (None, None) => bail!("either --peer-id or --key-file is required"),
+            };
+            let mut address = Multiaddr::empty()
+                .with(Protocol::from(ip))
+                .with(Protocol::Tcp(port))
+                .with(Protocol::P2p(peer_id.into()));
+            if let Some(destination) = circuit {
+                address = address.with(Protocol::P2pCircuit);
+                if let Some(target) = destination {
+                    address = address.with(Protocol::P2p(target.into()));
+                }
+            }
+            println!("{address}");
+        }
+        Command::Voucher {
+            input,
+            peer_id,
+            valid_for,
+        } => {
+            let issuer = input.load()?;
+            let voucher = Voucher::issue(&issuer, peer_id, Duration::from_secs(valid_for))?;
+            println!("{}", hex::encode(voucher));
+        }
+        Command::Audit {
+            log,
+            peer,
+            since,
+            until,
+        } => {
+            let since = since.as_deref().map(parse_time).transpose()?;
+            let until = until.as_deref().map(parse_time).transpose()?;
+            let skipped = audit::scan(&log, |record| {
+                if peer.is_some_and(|peer| !record.involves(&peer)) {
+                    return Ok(());
+                }
+                let time = record.time()?;
+                if since.is_some_and(|since| time < since)
+                    || until.is_some_and(|until| time >= until)
+                {
+                    return Ok(());
+                }
+                println!("{}", serde_json::to_string(&record)?);
+                Ok(())
+            })?;
+            for line in skipped {
+                eprintln!(
+                    "skipped {}:{}: {}",
+                    line.file.display(),
+                    line.line,
+                    line.error
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Parser)]
+#[clap(
+    name = "p2pctl",
+    about = "Key, identity and relay audit tools for the p2p nodes"
+)]
+struct Opt {
+    #[clap(subcommand)]
+    command: Command,
+
+    #[clap(flatten)]
+    log: LogOpt,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Generate a new key file and print its peer id
+    Keygen {
+        /// Where to write the key, an existing file is never overwritten
+        #[clap(long)]
+        out: PathBuf,
+
+        #[clap(long, value_enum, default_value_t = KeyType::Ed25519)]
+        key_type: KeyType,
+
+        /// Encoding of the key, `convert --to raw` writes a bare secret
+        #[clap(long, default_value = "protobuf", value_parser = KeyFormat::detectable_parser())]
+        format: KeyFormat,
+
+        /// Encrypt the key with a passphrase
+        #[clap(long, conflicts_with = "format")]
+        encrypt: bool,
+
+        #[clap(flatten)]
+        passphrase: PassphraseOpt,
+    },
+    /// Print the peer id, key type and public key of a key file
+    Inspect {
+        #[clap(flatten)]
+        input: KeyInput,
+    },
+    /// Re-encode a key file in another format
+    Convert {
+        #[clap(flatten)]
+        input: KeyInput,
+
+        /// Where to write the converted key, an existing file is never overwritten
+        #[clap(long)]
+        out: PathBuf,
+
+        /// Format of the converted key
+        #[clap(long, value_enum, required_unless_present = "encrypt")]
+        to: Option<KeyFormat>,
+
+        /// Encrypt the converted key with a passphrase instead
+        #[clap(long, conflicts_with = "to")]
+        encrypt: bool,
+    },
+    /// Compose the address of a node, optionally as a relay circuit
+    Multiaddr {
+        #[clap(long)]
+        ip: IpAddr,
+
+        #[clap(long)]
+        port: u16,
+
+        /// Peer id of the node listening on ip and port
+        #[clap(long, conflicts_with = "key_file")]
+        peer_id: Option<PeerId>,
+
+        /// Key file of the node listening on ip and port
+        #[clap(long)]
+        key_file: Option<PathBuf>,
+
+        /// Append `/p2p-circuit`, followed by `/p2p/<PEER_ID>` when a peer id is given
+        #[clap(long)]
+        circuit: Option<Option<PeerId>>,
+
+        #[clap(flatten)]
+        passphrase: PassphraseOpt,
+    },
+    /// Sign a voucher admitting a peer to relays that list this key's peer id under
+    /// `voucher_issuers`, and print it as hex for `relay_client --voucher`
+    Voucher {
+        /// Key file of the relay operator
+        #[clap(flatten)]
+        input: KeyInput,
+
+        /// The peer allowed to reserve with the voucher
+        #[clap(long)]
+        peer_id: PeerId,
+
+        /// How long the voucher stays valid, in seconds
+        #[clap(long, default_value_t = 30 * 24 * 60 * 60)]
+        valid_for: u64,
+    },
+    /// Print the records of a relay audit log, rotated files included, oldest first
+    Audit {
+        /// The `--audit-log` file of the relay server
+        log: PathBuf,
+
+        /// Only records with this peer as source or destination
+        #[clap(long)]
+        peer: Option<PeerId>,
+
+        /// Only records at or after this time, e.g. `2023-05-01T12:00:00Z`
+        #[clap(long)]
+        since: Option<String>,
+
+        /// Only records before this time
+        #[clap(long)]
+        until: Option<String>,
+    },
+}
+
+fn parse_time(time: &str) -> anyhow::Result<SystemTime> {
+    humantime::parse_rfc3339_weak(time).with_context(|| format!("invalid time {time}"))
+}
+
+/// A key file to read, in a detected format unless `--from` says otherwise.
+#[derive(Debug, Args)]
+struct KeyInput {
+    /// The key file to read
+    input: PathBuf,
+
+    /// Format of the key file, detected when omitted. Raw keys are never detected
+    #[clap(long, value_enum)]
+    from: Option<KeyFormat>,
+
+    /// Type of a raw key, which the file itself does not record
+    #[clap(long, value_enum, default_value_t = KeyType::Ed25519)]
+    key_type: KeyType,
+
+    #[clap(flatten)]
+    passphrase: PassphraseOpt,
+}
+
+impl KeyInput {
+    fn load(&self) -> anyhow::Result<Keypair> {
+        let data = fs::read(&self.input)
+            .with_context(|| format!("reading key file {}", self.input.display()))?;
+        if self.from.is_none() && keystore::is_keystore(&data) {
+            return keystore::decrypt(&data, &self.passphrase.read(false)?);
+        }
+        match self.from.unwrap_or_else(|| KeyFormat::detect(&data)) {
+            KeyFormat::Raw => key::decode_raw(&data, self.key_type),
+            format => key::decode(&data, format),
+        }
+    }
+}
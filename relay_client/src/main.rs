@@ -1,254 +1,372 @@
-use async_std::io;
+use anyhow::Context;
+use behaviour::address;
+use behaviour::behaviour::BehaviourConfig;
+use behaviour::key::KeyOpt;
+use behaviour::logging::LogOpt;
+use behaviour::metrics::MetricsOpt;
+use behaviour::node::{EchoHandler, Node, NodeEvent, NodeHandle};
+use behaviour::output::{self, OutputFormat};
 use clap::Parser;
-use futures::stream::StreamExt;
-use libp2p::{
-    core::multiaddr::Protocol,
-    core::upgrade,
-    core::{Multiaddr, Transport},
-    identify, identity,
-    identity::PeerId,
-    kad::store::MemoryStore,
-    noise, ping, relay, request_response,
-    swarm::{NetworkBehaviour, SwarmBuilder, SwarmEvent},
-    tcp,
-};
-use std::iter;
-use std::{io::BufRead, time::Duration};
-
-use futures::{future::Either, prelude::*, select};
-use libp2p::core::transport::OrTransport;
-use libp2p::request_response::{Config, ProtocolSupport};
-use std::net::{Ipv4Addr, Ipv6Addr};
-use std::str::FromStr;
-// use tokio::io::AsyncBufReadExt;
-use codec::chat::ChatResponse;
-use codec::chat::{ChatCodec, ChatProtocol, ChatRequest, CHAT_PROTOCOL};
-
-#[async_std::main]
-async fn main() -> anyhow::Result<()> {
-    env_logger::init();
+use codec::chat::{ChatEnvelope, ChatRequest, DEFAULT_MAX_MESSAGE_SIZE};
+use futures::stream::{FusedStream, StreamExt};
+use libp2p::{core::multiaddr::Protocol, core::Multiaddr, identity::PeerId};
+use repl::{KnownPeers, Printer, ReplCommand};
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use tokio::sync::mpsc;
 
-    let opt = Opt::parse();
-    println!("opt: {opt:?}");
+mod repl;
+mod tui;
 
-    let relay_server = generate_ed25519(opt.server_secret_key_seed);
-    let relay_peer_id = PeerId::from(relay_server.public());
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let opt = Opt::parse();
+    // Log lines would tear through the full-screen UI, so it only logs to a file.
+    if !opt.tui || opt.log.log_file.is_some() {
+        opt.log.init()?;
+    }
+    tracing::debug!(?opt, "starting");
 
-    // Create a static known PeerId based on given secret
-    let client: identity::Keypair = generate_ed25519(opt.client_secret_key_seed);
+    let voucher = match &opt.voucher {
+        Some(voucher) => Some(hex::decode(voucher.trim()).context("decoding --voucher")?),
+        None => None,
+    };
+    let client = opt.key.keypair()?;
     let local_peer_id = PeerId::from(client.public());
-    println!("Local peer id: {local_peer_id:?}");
-
-    // Create a static known PeerId based on given secret
-    let receive: identity::Keypair = generate_ed25519(opt.receive_secret_key_seed);
-    let receive_peer_id = PeerId::from(receive.public());
-
-    let (transport, behaviour) = relay::client::new(local_peer_id);
-    let tcp_transport = OrTransport::new(transport, tcp::async_io::Transport::default());
-
-    let transport = tcp_transport
-        .upgrade(upgrade::Version::V1)
-        .authenticate(
-            noise::NoiseAuthenticated::xx(&client)
-                .expect("Signing libp2p-noise static DH keypair failed."),
-        )
-        .multiplex(libp2p::yamux::YamuxConfig::default())
-        .timeout(std::time::Duration::from_secs(20))
-        .boxed();
-
-    let protocols = iter::once((ChatProtocol, ProtocolSupport::Full));
-    let behaviour = Behaviour {
-        relay: behaviour,
-        ping: ping::Behaviour::new(ping::Config::default()),
-        identify: identify::Behaviour::new(identify::Config::new(
-            "/TODO/0.0.1".to_string(),
-            client.public(),
-        )),
-        chat: request_response::Behaviour::new(ChatCodec, protocols.clone(), Config::default()),
-        keep_alive: libp2p::swarm::keep_alive::Behaviour::default(),
-        kad: libp2p::kad::Kademlia::new(local_peer_id, MemoryStore::new(local_peer_id)),
+    tracing::info!(peer_id = %local_peer_id, "local peer id");
+
+    let mut config = BehaviourConfig::relay_client();
+    config.chat_max_request_size = opt.max_request_size;
+    config.chat_max_response_size = opt.max_response_size;
+    let metrics = opt.metrics.start(&config.bandwidth)?;
+    let (runner, node) = Node::new(&client, config).await?;
+    let mut runner = runner
+        .with_chat_handler(EchoHandler)
+        .with_chat_retry(opt.retry_chat);
+    if let Some(metrics) = metrics {
+        runner = runner.with_metrics(metrics);
+    }
+    if let Some(voucher) = voucher {
+        runner = runner.with_relay_voucher(voucher);
+    }
+    runner.start();
+    let events = node.events();
+
+    let known_peers = KnownPeers::default();
+    // The prompt hands us the entered lines, the TUI the printed ones to show in its log.
+    let (printer, lines) = if opt.tui {
+        tui::printer()
+    } else if opt.output == OutputFormat::Json {
+        repl::spawn_plain()
+    } else {
+        repl::spawn(known_peers.clone(), opt.history_file.clone())?
     };
+    let mut client = Client {
+        node,
+        printer,
+        known_peers,
+        output: opt.output,
+        relays: HashSet::new(),
+        chat_addresses: HashSet::new(),
+    };
+    // Direct connections, including the ones hole punching sets up from this port
+    let listen_addr = Multiaddr::empty()
+        .with(Protocol::from(Ipv4Addr::UNSPECIFIED))
+        .with(Protocol::Tcp(opt.port));
+    client.node.listen_on(listen_addr).await?;
+    // Reachable through every relay
+    for relay in opt.relay {
+        client.add_relay(relay).await?;
+    }
+    for peer in opt.pin {
+        client.node.pin(peer).await?;
+    }
+    if opt.tui {
+        tui::run(client, lines, events).await
+    } else {
+        run_repl(client, lines, events).await
+    }
+}
 
-    let mut swarm =
-        SwarmBuilder::with_async_std_executor(transport, behaviour, local_peer_id).build();
-
-    // Listen on all interfaces
-    let client_addr = Multiaddr::empty()
-        .with(match opt.use_ipv6 {
-            Some(true) => Protocol::from(Ipv6Addr::UNSPECIFIED),
-            _ => Protocol::from(Ipv4Addr::from_str("127.0.0.1")?),
-        })
-        .with(Protocol::Tcp(opt.port))
-        .with(Protocol::P2p(relay_peer_id.into()))
-        .with(Protocol::P2pCircuit);
-    println!("{}", client_addr.to_string());
-    swarm.listen_on(client_addr.clone())?;
-
-    let receive_addr = client_addr
-        .clone()
-        .with(Protocol::P2p(receive_peer_id.into()));
-    println!("{}", receive_addr.to_string());
-    // Read full lines from stdin
-    let mut stdin = async_std::io::BufReader::new(async_std::io::stdin())
-        .lines()
-        .fuse();
-    swarm
-        .behaviour_mut()
-        .chat
-        .add_address(&receive_peer_id, receive_addr.clone());
-
-    //SwarmEvent::OutgoingConnectionError { peer_id, error } => {println!("{peer_id:?} {error:?}");}
+async fn run_repl(
+    mut client: Client,
+    mut lines: mpsc::UnboundedReceiver<String>,
+    mut events: impl FusedStream<Item = NodeEvent> + Unpin,
+) -> anyhow::Result<()> {
+    client
+        .printer
+        .println("Type /help for the list of commands");
     loop {
-        select! {
-            line = stdin.select_next_some() => {
-                let req_id = swarm.behaviour_mut().chat.send_request(&receive_peer_id, ChatRequest(line.expect("Stdin not to close").as_bytes().to_vec()));
-                // let req_id = swarm.behaviour_mut().chat.send_request(&PeerId::from_str("12D3KooWPjceQrSwdWXPyLLeABRXmuqt69Rg3sBYbU1Nft9HyQ6X").unwrap(), ChatRequest(line.expect("Stdin not to close").as_bytes().to_vec()));
-                println!("req id: {req_id:?}");
-            },
-            event = swarm.select_next_some() => match event {
-                SwarmEvent::OutgoingConnectionError { peer_id, error } => {println!("OutgoingConnectionError: {peer_id:?} {error:?}");}
-                SwarmEvent::ListenerError { listener_id, error } => {println!("ListenerError: {listener_id:?} {error:?}");}
-                SwarmEvent::ListenerClosed { listener_id, addresses, reason }  => {println!("ListenerClosed: {listener_id:?} {addresses:?} {reason:?}");}
-                SwarmEvent::ExpiredListenAddr { listener_id, address }  => {println!("ExpiredListenAddr: {listener_id:?} {address:?}");}
-                SwarmEvent::BannedPeer { peer_id, endpoint } => {println!("{peer_id:?} {endpoint:?}");}
-                SwarmEvent::IncomingConnectionError { local_addr, send_back_addr, error  } => {println!("IncomingConnectionError: {local_addr:?} {send_back_addr:?} {error:?}");}
-                SwarmEvent::IncomingConnection { local_addr , send_back_addr } => {println!("IncomingConnection: {local_addr:?} {send_back_addr:?}");}
-                SwarmEvent::Dialing(peer_id) => {
-                    println!("Dialing: {peer_id}");
-                }
-                SwarmEvent::ConnectionEstablished { peer_id, .. } if peer_id == relay_peer_id => { println!("ConnectionEstablished: {peer_id}"); }
-                SwarmEvent::ConnectionClosed { peer_id, ..} => {
-                    println!("ConnectionClosed: {peer_id}");
-                }
-                SwarmEvent::OutgoingConnectionError { peer_id, error } if peer_id == Some(relay_peer_id) => {
-                    println!("OutgoingConnectionError: {peer_id:?} err: {error:?}");
-                }
-                SwarmEvent::NewListenAddr { address, .. } => {
-                    // swarm.listen_on(address.clone())?;
-                    println!("Listening on {address:?}");
-                }
-                SwarmEvent::Behaviour(event) => {
-                    match event {
-                        BehaviourEvent::Relay(relay::client::Event::ReservationReqAccepted {
-                            relay_peer_id, renewal, ..
-                        }) => {
-                            println!("ReservationReqAccepted{renewal}");
-                            if renewal {
-                                // break;
-                            }
-                        },
-                        BehaviourEvent::Ping(e) => {
-                            // println!("Ping: {e:?}");
-                        }
-                        BehaviourEvent::Chat(e) => {
-                            match e {
-                                request_response::Event::Message { peer, message } => {
-                                    println!("Event::Message: {peer}");
-                                    match message {
-                                        request_response::Message::Response { request_id, response } => {
-                                            println!("Message::Response: {request_id:?} {response:?}")
-                                        }
-                                        request_response::Message::Request { request_id, request, channel } => {
-                                            println!("Message::Request: {request_id:?} {request:?}");
-                                            swarm.behaviour_mut().chat.send_response(channel, ChatResponse(request.data().clone())).unwrap();
-                                        }
-                                    }
-                                }
-                                request_response::Event::ResponseSent { peer, request_id } => {
-                                    println!("ResponseSent: {peer:?} {request_id:?}");
-                                }
-                                request_response::Event::OutboundFailure { peer, request_id, error } => {
-                                    println!("OutboundFailure: {peer:?} {request_id:?} error: {error:?}");
-                                }
-                                request_response::Event::InboundFailure { peer, request_id, error } => {
-                                    println!("InboundFailure: {peer:?} {request_id:?} error: {error:?}");
-                                }
-                            }
+        tokio::select! {
+            line = lines.recv() => {
+                let Some(line) = line else {
+                    return Ok(());
+                };
+                match line.parse() {
+                    Ok(ReplCommand::Quit) => return Ok(()),
+                    Ok(command) => {
+                        if let Err(e) = client.execute(command).await {
+                            client.printer.println(format!("Error: {e:#}"));
                         }
+                    }
+                    Err(e) => client.printer.println(format!("{e}")),
+                }
+            },
+            event = events.select_next_some() => client.on_event(event),
+        }
+    }
+}
 
+struct Client {
+    node: NodeHandle,
+    printer: Printer,
+    known_peers: KnownPeers,
+    output: OutputFormat,
+    /// Relays the node keeps a reservation on.
+    relays: HashSet<Multiaddr>,
+    /// Circuit addresses already handed to the chat behaviour.
+    chat_addresses: HashSet<(PeerId, Multiaddr)>,
+}
 
-
-                        BehaviourEvent::Kad(event) => match event {
-                    libp2p::kad::KademliaEvent::InboundRequest { request } => {
-                        println!("InboundRequest: {request:?}");
+impl Client {
+    async fn execute(&mut self, command: ReplCommand) -> anyhow::Result<()> {
+        match command {
+            ReplCommand::Dial(address) => {
+                if let Some(peer) = PeerId::try_from_multiaddr(&address) {
+                    self.known_peers.insert(peer);
+                }
+                self.node.dial(address).await?;
+            }
+            ReplCommand::Connect(peer) => {
+                self.known_peers.insert(peer);
+                let (node, printer) = (self.node.clone(), self.printer.clone());
+                tokio::spawn(async move {
+                    match node.connect(peer).await {
+                        Ok(path) => printer.println(format!("Connected to {peer} ({path:?})")),
+                        Err(e) => printer.println(format!("Connecting to {peer} failed: {e}")),
                     }
-                    libp2p::kad::KademliaEvent::OutboundQueryProgressed {
-                        id,
-                        result,
-                        stats,
-                        step,
-                    } => {
-                        println!("OutboundQueryProgressed: {id:?} {result:?} {stats:?} {step:?}");
+                });
+            }
+            ReplCommand::Peers => {
+                let peers = self.node.peers().await?;
+                self.printer
+                    .println(format!("{} connected peers", peers.len()));
+                for peer in peers {
+                    self.known_peers.insert(peer);
+                    self.printer.println(format!("  {peer}"));
+                }
+            }
+            ReplCommand::Msg { peer, text } => {
+                self.add_chat_addresses(peer).await?;
+                let (node, printer) = (self.node.clone(), self.printer.clone());
+                tokio::spawn(async move {
+                    // Sent through the relay right away, DCUtR may move it to a direct
+                    // connection later.
+                    if let Err(e) = node.reach(peer).await {
+                        return printer.println(format!("Message to {peer} failed: {e}"));
                     }
-                    libp2p::kad::KademliaEvent::RoutingUpdated {
-                        peer,
-                        is_new_peer,
-                        addresses,
-                        bucket_range,
-                        old_peer,
-                    } => {
-                        println!("RoutingUpdated: {peer:?} {is_new_peer:?} {addresses:?} {bucket_range:?} {old_peer:?}");
+                    let request = ChatRequest(ChatEnvelope::text(text));
+                    match node.send_chat(peer, request).await {
+                        Ok(response) => printer.println(format!(
+                            "{peer}: {}",
+                            String::from_utf8_lossy(response.data())
+                        )),
+                        Err(e) => printer.println(format!("Message to {peer} failed: {e}")),
                     }
-                    libp2p::kad::KademliaEvent::UnroutablePeer { peer } => {
-                        println!("UnroutablePeer: {peer:?}");
+                });
+            }
+            ReplCommand::RelayAdd(relay) => self.add_relay(relay).await?,
+            ReplCommand::RelayRemove(relay) => {
+                self.relays.remove(&relay);
+                if self.node.unreserve(relay.clone()).await? {
+                    self.printer
+                        .println(format!("Stopped listening through {relay}"));
+                } else {
+                    self.printer
+                        .println(format!("Not listening through {relay}"));
+                }
+            }
+            ReplCommand::KadGet(key) => {
+                let (node, printer) = (self.node.clone(), self.printer.clone());
+                tokio::spawn(async move {
+                    match node.kad_get(key.clone().into_bytes()).await {
+                        Ok(value) => {
+                            printer.println(format!("{key} = {}", String::from_utf8_lossy(&value)))
+                        }
+                        Err(e) => printer.println(format!("Getting {key} failed: {e}")),
                     }
-                    libp2p::kad::KademliaEvent::RoutablePeer { peer, address } => {
-                        println!("RoutablePeer: {peer:?} {address:?}");
+                });
+            }
+            ReplCommand::KadPut { key, value } => {
+                let (node, printer) = (self.node.clone(), self.printer.clone());
+                tokio::spawn(async move {
+                    match node
+                        .kad_put(key.clone().into_bytes(), value.into_bytes())
+                        .await
+                    {
+                        Ok(()) => printer.println(format!("Stored {key}")),
+                        Err(e) => printer.println(format!("Storing {key} failed: {e}")),
                     }
-                    libp2p::kad::KademliaEvent::PendingRoutablePeer { peer, address } => {
-                        println!("PendingRoutablePeer: {peer:?} {address:?}");
+                });
+            }
+            ReplCommand::KadProviders(key) => {
+                let (node, printer) = (self.node.clone(), self.printer.clone());
+                tokio::spawn(async move {
+                    match node.kad_providers(key.clone().into_bytes()).await {
+                        Ok(providers) => {
+                            printer.println(format!("{} providers of {key}", providers.len()));
+                            for provider in providers {
+                                printer.println(format!("  {provider}"));
+                            }
+                        }
+                        Err(e) => {
+                            printer.println(format!("Finding providers of {key} failed: {e}"))
+                        }
                     }
-                },
-
-                        _ => {}
+                });
+            }
+            ReplCommand::Ping(peer) => {
+                let (node, printer) = (self.node.clone(), self.printer.clone());
+                tokio::spawn(async move {
+                    match node.ping(peer).await {
+                        Ok(rtt) => printer.println(format!("Pong from {peer} in {rtt:?}")),
+                        Err(e) => printer.println(format!("Ping to {peer} failed: {e}")),
                     }
+                });
+            }
+            ReplCommand::Bandwidth => {
+                for traffic in self.node.bandwidth() {
+                    self.printer.println(format!(
+                        "{} {}: {} bytes in, {} bytes out",
+                        traffic.peer, traffic.protocol, traffic.inbound, traffic.outbound
+                    ));
                 }
-                _ => {}
-                // SwarmEvent::Behaviour(ClientEvent::Ping(_)) => {}
-               // _ => { println!("run") }
-                // e => panic!("{e:?}"),
-
             }
+            ReplCommand::Help => self.printer.println(repl::HELP),
+            ReplCommand::Quit => {}
         }
+        Ok(())
     }
-    Ok(())
-}
 
-#[derive(NetworkBehaviour)]
-struct Behaviour {
-    relay: relay::client::Behaviour,
-    ping: ping::Behaviour,
-    identify: identify::Behaviour,
-    chat: request_response::Behaviour<ChatCodec>,
-    keep_alive: libp2p::swarm::keep_alive::Behaviour,
-    kad: libp2p::kad::Kademlia<MemoryStore>,
-}
+    /// Makes `peer` reachable for chat through every relay we listen on.
+    async fn add_chat_addresses(&mut self, peer: PeerId) -> anyhow::Result<()> {
+        self.known_peers.insert(peer);
+        for relay in &self.relays {
+            let circuit = address::circuit(relay, Some(peer));
+            if self.chat_addresses.insert((peer, circuit.clone())) {
+                self.node.add_chat_address(peer, circuit).await?;
+            }
+        }
+        Ok(())
+    }
 
-fn generate_ed25519(secret_key_seed: u8) -> identity::Keypair {
-    let mut bytes = [0u8; 32];
-    bytes[0] = secret_key_seed;
+    async fn add_relay(&mut self, relay: Multiaddr) -> anyhow::Result<()> {
+        if self.relays.contains(&relay) {
+            return Ok(());
+        }
+        self.node.reserve(relay.clone()).await?;
+        if let Some(peer) = PeerId::try_from_multiaddr(&relay) {
+            self.known_peers.insert(peer);
+        }
+        self.relays.insert(relay);
+        Ok(())
+    }
 
-    identity::Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
+    fn on_event(&self, event: NodeEvent) {
+        match &event {
+            NodeEvent::ConnectionEstablished { peer_id, .. }
+            | NodeEvent::Identified { peer_id, .. } => self.known_peers.insert(*peer_id),
+            NodeEvent::KadRoutingUpdated { peer, .. } | NodeEvent::ChatRequest { peer, .. } => {
+                self.known_peers.insert(*peer)
+            }
+            _ => {}
+        }
+        if self.output == OutputFormat::Json {
+            // The only thing on stdout, the printer writes to stderr in this mode.
+            println!("{}", output::json_line(&event));
+            return;
+        }
+        match event {
+            // Outcomes of our own requests are reported by the task that sent them.
+            NodeEvent::Ping { .. }
+            | NodeEvent::ChatResponse { .. }
+            | NodeEvent::ChatOutboundFailure { .. } => {}
+            NodeEvent::NewListenAddr { address } => {
+                self.printer.println(format!("Listening on {address:?}"));
+            }
+            NodeEvent::CircuitAddressesChanged { addresses } => {
+                self.printer.println("Reachable through relays at:");
+                for address in addresses {
+                    self.printer.println(format!("  {address}"));
+                }
+            }
+            NodeEvent::ChatRequest { peer, request, .. } => {
+                self.printer.println(format!(
+                    "{peer}: {}",
+                    String::from_utf8_lossy(request.data())
+                ));
+            }
+            event => self.printer.println(format!("{event:?}")),
+        }
+    }
 }
 
 #[derive(Debug, Parser)]
 #[clap(name = "libp2p relay")]
 struct Opt {
-    /// Determine if the relay listen on ipv6 or ipv4 loopback address. the default is ipv4
-    #[clap(long)]
-    use_ipv6: Option<bool>,
+    #[clap(flatten)]
+    key: KeyOpt,
+
+    #[clap(flatten)]
+    log: LogOpt,
 
-    /// Fixed value to generate deterministic peer id
+    #[clap(flatten)]
+    metrics: MetricsOpt,
+
+    /// Relay server to listen through, as `/ip4/<ip>/tcp/<port>/p2p/<peer id>`. Repeatable,
+    /// more can be added with `/relay add`
+    #[clap(long, value_parser = address::parse_relay)]
+    relay: Vec<Multiaddr>,
+
+    /// Port for direct connections and hole punching, any free one by default
+    #[clap(long, default_value_t = 0)]
+    port: u16,
+
+    /// Peer to stay connected to, as `/ip4/<ip>/tcp/<port>/p2p/<peer id>` or a circuit
+    /// address. Repeatable
+    #[clap(long, value_parser = address::parse_peer)]
+    pin: Vec<Multiaddr>,
+
+    /// Hex encoded voucher from the relay operator, see `p2pctl voucher`, for relays that only
+    /// admit peers presenting one
     #[clap(long)]
-    server_secret_key_seed: u8,
+    voucher: Option<String>,
 
+    /// File to keep the command history in across runs
     #[clap(long)]
-    client_secret_key_seed: u8,
+    history_file: Option<PathBuf>,
 
+    /// Full-screen terminal UI instead of the line based prompt
     #[clap(long)]
-    receive_secret_key_seed: u8,
+    tui: bool,
 
-    /// The port used to listen on all interfaces
+    /// How events are printed, `json` emits one object per line on stdout for log processors
+    /// and moves the prompt and all other output to stderr
+    #[clap(long, value_enum, default_value_t = OutputFormat::Text, conflicts_with = "tui")]
+    output: OutputFormat,
+
+    /// Largest chat request in bytes accepted from or sent to a peer
+    #[clap(long, default_value_t = DEFAULT_MAX_MESSAGE_SIZE)]
+    max_request_size: usize,
+
+    /// Largest chat response in bytes accepted from or sent to a peer
+    #[clap(long, default_value_t = DEFAULT_MAX_MESSAGE_SIZE)]
+    max_response_size: usize,
+
+    /// Send a message once more should its connection close before the answer, such as when a
+    /// hole punch replaces the relayed connection. Only for peers running this release or later,
+    /// older ones may handle the message twice
     #[clap(long)]
-    port: u16,
+    retry_chat: bool,
 }
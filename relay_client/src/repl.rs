@@ -0,0 +1,353 @@
+use anyhow::{anyhow, bail};
+use behaviour::address::parse_relay;
+use libp2p::{core::Multiaddr, identity::PeerId};
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Context, Editor, ExternalPrinter, Helper, Highlighter, Hinter, Validator};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+const COMMANDS: [&str; 11] = [
+    "/dial",
+    "/connect",
+    "/peers",
+    "/msg",
+    "/relay",
+    "/kad",
+    "/ping",
+    "/bandwidth",
+    "/help",
+    "/quit",
+    "/exit",
+];
+
+pub const HELP: &str = "\
+/dial <multiaddr>             connect to an address
+/connect <peer id>            connect directly, through a relay or by hole punching
+/peers                        list connected peers
+/msg <peer id> <text>         send a chat message
+/relay add|remove <multiaddr> listen through a relay, or stop
+/kad get <key>                look up a DHT record
+/kad put <key> <value>        store a DHT record
+/kad providers <key>          list the providers of a key
+/ping <peer id>               measure the round trip time
+/bandwidth                    bytes exchanged per peer and protocol
+/quit                         exit";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplCommand {
+    Dial(Multiaddr),
+    Connect(PeerId),
+    Peers,
+    Msg { peer: PeerId, text: String },
+    RelayAdd(Multiaddr),
+    RelayRemove(Multiaddr),
+    KadGet(String),
+    KadPut { key: String, value: String },
+    KadProviders(String),
+    Ping(PeerId),
+    Bandwidth,
+    Help,
+    Quit,
+}
+
+impl FromStr for ReplCommand {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => bail!("empty command"),
+        };
+        let mut arg = |name: &str| {
+            words
+                .next()
+                .ok_or_else(|| anyhow!("{command} is missing <{name}>, see /help"))
+        };
+        Ok(match command {
+            "/dial" => ReplCommand::Dial(arg("multiaddr")?.parse()?),
+            "/connect" => ReplCommand::Connect(arg("peer id")?.parse()?),
+            "/peers" => ReplCommand::Peers,
+            "/msg" => {
+                let peer = arg("peer id")?.parse()?;
+                // Keep the text as typed rather than rejoining words.
+                let text = line.trim_start()["/msg".len()..]
+                    .trim_start()
+                    .split_once(char::is_whitespace)
+                    .map(|(_, text)| text.trim_start().to_string())
+                    .filter(|text| !text.is_empty())
+                    .ok_or_else(|| anyhow!("/msg is missing <text>, see /help"))?;
+                ReplCommand::Msg { peer, text }
+            }
+            "/relay" => match arg("add|remove")? {
+                "add" => ReplCommand::RelayAdd(parse_relay(arg("multiaddr")?)?),
+                "remove" => ReplCommand::RelayRemove(arg("multiaddr")?.parse()?),
+                other => bail!("unknown /relay subcommand {other}, see /help"),
+            },
+            "/kad" => match arg("get|put|providers")? {
+                "get" => ReplCommand::KadGet(arg("key")?.to_string()),
+                "put" => ReplCommand::KadPut {
+                    key: arg("key")?.to_string(),
+                    value: arg("value")?.to_string(),
+                },
+                "providers" => ReplCommand::KadProviders(arg("key")?.to_string()),
+                other => bail!("unknown /kad subcommand {other}, see /help"),
+            },
+            "/ping" => ReplCommand::Ping(arg("peer id")?.parse()?),
+            "/bandwidth" => ReplCommand::Bandwidth,
+            "/help" => ReplCommand::Help,
+            "/quit" | "/exit" => ReplCommand::Quit,
+            other => bail!("unknown command {other}, see /help"),
+        })
+    }
+}
+
+/// Peer ids offered by tab completion, shared between the event loop and the editor thread.
+#[derive(Debug, Clone, Default)]
+pub struct KnownPeers(Arc<Mutex<BTreeSet<PeerId>>>);
+
+impl KnownPeers {
+    pub fn insert(&self, peer: PeerId) {
+        self.0.lock().expect("known peers lock").insert(peer);
+    }
+}
+
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct ReplHelper {
+    known_peers: KnownPeers,
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    /// Completes the command name in the first word and peer ids everywhere else.
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &line[start..pos];
+        let candidates: Vec<String> = if start == 0 {
+            COMMANDS
+                .iter()
+                .filter(|command| command.starts_with(word))
+                .map(|command| command.to_string())
+                .collect()
+        } else {
+            let known_peers = self.known_peers.0.lock().expect("known peers lock");
+            known_peers
+                .iter()
+                .map(|peer| peer.to_string())
+                .filter(|peer| peer.starts_with(word))
+                .collect()
+        };
+        let pairs = candidates
+            .into_iter()
+            .map(|candidate| Pair {
+                display: candidate.clone(),
+                replacement: candidate,
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+/// Prints above the prompt without garbling the line being edited.
+#[derive(Clone)]
+pub struct Printer(Arc<Mutex<dyn ExternalPrinter + Send>>);
+
+impl Printer {
+    pub fn new(printer: impl ExternalPrinter + Send + 'static) -> Self {
+        Printer(Arc::new(Mutex::new(printer)))
+    }
+
+    pub fn println(&self, line: impl Into<String>) {
+        let line = line.into();
+        let mut printer = self.0.lock().expect("printer lock");
+        if let Err(e) = printer.print(format!("{line}\n")) {
+            eprintln!("{line} ({e})");
+        }
+    }
+}
+
+/// Prints to stderr, keeping stdout for `--output json`.
+struct StderrPrinter;
+
+impl ExternalPrinter for StderrPrinter {
+    fn print(&mut self, msg: String) -> rustyline::Result<()> {
+        let mut stderr = io::stderr().lock();
+        stderr.write_all(msg.as_bytes())?;
+        stderr.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads lines from stdin without the line editor, which would write its prompt to stdout.
+/// The prompt and everything printed go to stderr, so stdout carries nothing but the JSON
+/// events. There is no history or completion in this mode.
+pub fn spawn_plain() -> (Printer, mpsc::UnboundedReceiver<String>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        loop {
+            eprint!("> ");
+            let mut line = String::new();
+            let line = match stdin.read_line(&mut line) {
+                Ok(0) => "/quit".to_string(),
+                Ok(_) => without_line_ending(&line).to_string(),
+                Err(e) => {
+                    eprintln!("reading input failed: {e}");
+                    "/quit".to_string()
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let quit = matches!(line.parse(), Ok(ReplCommand::Quit));
+            if sender.send(line).is_err() || quit {
+                return;
+            }
+        }
+    });
+    (Printer::new(StderrPrinter), receiver)
+}
+
+/// `line` as typed, without the line ending `read_line` keeps.
+fn without_line_ending(line: &str) -> &str {
+    line.trim_end_matches(['\r', '\n'])
+}
+
+/// Runs the line editor on its own thread and returns the entered lines. End of input is
+/// reported as `/quit`.
+pub fn spawn(
+    known_peers: KnownPeers,
+    history_file: Option<PathBuf>,
+) -> anyhow::Result<(Printer, mpsc::UnboundedReceiver<String>)> {
+    let mut editor = Editor::<ReplHelper, DefaultHistory>::new()?;
+    editor.set_helper(Some(ReplHelper { known_peers }));
+    if let Some(path) = &history_file {
+        // A missing history file is expected on first use.
+        let _ = editor.load_history(path);
+    }
+    let printer = Printer::new(editor.create_external_printer()?);
+    let (sender, receiver) = mpsc::unbounded_channel();
+    std::thread::spawn(move || loop {
+        let line = match editor.readline("> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => "/quit".to_string(),
+            Err(e) => {
+                eprintln!("reading input failed: {e}");
+                "/quit".to_string()
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+        if let Some(path) = &history_file {
+            if let Err(e) = editor.save_history(path) {
+                eprintln!("saving history to {} failed: {e}", path.display());
+            }
+        }
+        let quit = matches!(line.parse(), Ok(ReplCommand::Quit));
+        if sender.send(line).is_err() || quit {
+            return;
+        }
+    });
+    Ok((printer, receiver))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> anyhow::Result<ReplCommand> {
+        line.parse()
+    }
+
+    #[test]
+    fn parses_commands_and_arguments() {
+        let peer = PeerId::random();
+        let relay: Multiaddr = format!("/ip4/10.0.0.1/tcp/4001/p2p/{peer}")
+            .parse()
+            .unwrap();
+        assert_eq!(parse("/peers").unwrap(), ReplCommand::Peers);
+        assert_eq!(parse("  /exit  ").unwrap(), ReplCommand::Quit);
+        assert_eq!(
+            parse(&format!("/connect {peer}")).unwrap(),
+            ReplCommand::Connect(peer)
+        );
+        assert_eq!(
+            parse(&format!("/relay add {relay}")).unwrap(),
+            ReplCommand::RelayAdd(relay)
+        );
+        assert_eq!(
+            parse("/kad put key value").unwrap(),
+            ReplCommand::KadPut {
+                key: "key".to_string(),
+                value: "value".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn plain_input_loses_only_the_line_ending() {
+        let peer = PeerId::random();
+        for ending in ["\n", "\r\n"] {
+            let line = format!("/msg {peer} hello \t{ending}");
+            assert_eq!(
+                parse(without_line_ending(&line)).unwrap(),
+                ReplCommand::Msg {
+                    peer,
+                    text: "hello \t".to_string()
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn msg_keeps_the_text_as_typed() {
+        let peer = PeerId::random();
+        assert_eq!(
+            parse(&format!(" /msg  {peer}   hello,  world ")).unwrap(),
+            ReplCommand::Msg {
+                peer,
+                text: "hello,  world ".to_string()
+            }
+        );
+        assert!(parse(&format!("/msg {peer}   ")).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for line in [
+            "",
+            "hello",
+            "/unknown",
+            "/connect",
+            "/connect not-a-peer-id",
+            "/dial not-an-address",
+            "/relay add /ip4/10.0.0.1/tcp/4001",
+            "/relay list",
+            "/kad put key",
+            "/kad delete key",
+        ] {
+            assert!(parse(line).is_err(), "{line:?} parsed");
+        }
+    }
+
+    #[test]
+    fn missing_arguments_name_the_argument() {
+        let error = parse("/ping").unwrap_err();
+        assert_eq!(error.to_string(), "/ping is missing <peer id>, see /help");
+    }
+}
@@ -0,0 +1,547 @@
+use crate::repl::{Printer, ReplCommand};
+use crate::Client;
+use behaviour::node::NodeEvent;
+use codec::chat::{ChatEnvelope, ChatRequest};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use futures::stream::{FusedStream, StreamExt};
+use libp2p::core::{ConnectedPoint, Multiaddr};
+use libp2p::identity::PeerId;
+use ratatui::backend::{Backend, CrosstermBackend};
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Span, Spans};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{Frame, Terminal};
+use rustyline::ExternalPrinter;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const LOG_LINES: usize = 200;
+
+/// Sends printed lines to the log pane instead of the terminal.
+struct LogPrinter(mpsc::UnboundedSender<String>);
+
+impl ExternalPrinter for LogPrinter {
+    fn print(&mut self, msg: String) -> rustyline::Result<()> {
+        let _ = self.0.send(msg.trim_end().to_string());
+        Ok(())
+    }
+}
+
+/// A [`Printer`] for [`Client`] whose output ends up in the log pane.
+pub fn printer() -> (Printer, mpsc::UnboundedReceiver<String>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    (Printer::new(LogPrinter(sender)), receiver)
+}
+
+/// Runs the full-screen UI until the user quits.
+pub async fn run(
+    mut client: Client,
+    mut log: mpsc::UnboundedReceiver<String>,
+    mut events: impl FusedStream<Item = NodeEvent> + Unpin,
+) -> anyhow::Result<()> {
+    let mut terminal = TerminalGuard::new()?;
+    let mut app = App::default();
+    app.sync_relays(&client.relays);
+    let (replies, mut reply_receiver) = mpsc::unbounded_channel();
+    let mut input = EventStream::new();
+    loop {
+        terminal.0.draw(|f| app.draw(f))?;
+        tokio::select! {
+            Some(event) = input.next() => {
+                if let Event::Key(key) = event? {
+                    if !app.on_key(key, &mut client, &replies).await {
+                        return Ok(());
+                    }
+                }
+            },
+            event = events.select_next_some() => app.on_event(event),
+            Some(line) = log.recv() => app.log(line),
+            Some(reply) = reply_receiver.recv() => app.on_reply(reply),
+        }
+    }
+}
+
+/// Restores the terminal however the UI exits.
+struct TerminalGuard(Terminal<CrosstermBackend<io::Stdout>>);
+
+impl TerminalGuard {
+    fn new() -> anyhow::Result<Self> {
+        enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        execute!(stdout, EnterAlternateScreen)?;
+        Ok(Self(Terminal::new(CrosstermBackend::new(stdout))?))
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(self.0.backend_mut(), LeaveAlternateScreen);
+        let _ = self.0.show_cursor();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionKind {
+    Direct,
+    Relayed,
+    /// A direct connection to a peer first reached through a relay.
+    HolePunched,
+}
+
+impl ConnectionKind {
+    fn label(&self) -> &'static str {
+        match self {
+            ConnectionKind::Direct => "direct",
+            ConnectionKind::Relayed => "relayed",
+            ConnectionKind::HolePunched => "hole-punched",
+        }
+    }
+
+    fn color(&self) -> Color {
+        match self {
+            ConnectionKind::Direct => Color::Green,
+            ConnectionKind::Relayed => Color::Yellow,
+            ConnectionKind::HolePunched => Color::Cyan,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PeerState {
+    connections: Vec<ConnectedPoint>,
+    was_relayed: bool,
+    rtt: Option<Duration>,
+}
+
+impl PeerState {
+    /// The best open connection, `None` while disconnected.
+    fn kind(&self) -> Option<ConnectionKind> {
+        if self.connections.iter().any(|c| !c.is_relayed()) {
+            Some(if self.was_relayed {
+                ConnectionKind::HolePunched
+            } else {
+                ConnectionKind::Direct
+            })
+        } else if self.connections.is_empty() {
+            None
+        } else {
+            Some(ConnectionKind::Relayed)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Reservation {
+    Requested,
+    Accepted,
+    Failed,
+}
+
+#[derive(Debug)]
+struct ChatLine {
+    incoming: bool,
+    text: String,
+}
+
+#[derive(Debug)]
+struct Reply {
+    peer: PeerId,
+    result: Result<String, String>,
+}
+
+#[derive(Debug, Default)]
+struct App {
+    peers: BTreeMap<PeerId, PeerState>,
+    selected: usize,
+    conversations: HashMap<PeerId, Vec<ChatLine>>,
+    reservations: BTreeMap<PeerId, Reservation>,
+    input: String,
+    log: VecDeque<String>,
+}
+
+impl App {
+    fn selected_peer(&self) -> Option<PeerId> {
+        self.peers.keys().nth(self.selected).copied()
+    }
+
+    fn log(&mut self, line: String) {
+        if self.log.len() == LOG_LINES {
+            self.log.pop_front();
+        }
+        self.log.push_back(line);
+    }
+
+    /// Shows the relays of `relays` in the status bar, newly added ones as requested, and drops
+    /// those removed.
+    fn sync_relays(&mut self, relays: &HashSet<Multiaddr>) {
+        let peers: HashSet<PeerId> = relays
+            .iter()
+            .filter_map(PeerId::try_from_multiaddr)
+            .collect();
+        self.reservations.retain(|peer, _| peers.contains(peer));
+        for peer in peers {
+            self.reservations
+                .entry(peer)
+                .or_insert(Reservation::Requested);
+        }
+    }
+
+    /// Sets the state of the reservation on `relay`, unless it was removed since.
+    fn set_reservation(&mut self, relay: &PeerId, state: Reservation) {
+        if let Some(reservation) = self.reservations.get_mut(relay) {
+            *reservation = state;
+        }
+    }
+
+    fn chat(&mut self, peer: PeerId, incoming: bool, text: String) {
+        self.peers.entry(peer).or_default();
+        let line = ChatLine { incoming, text };
+        self.conversations.entry(peer).or_default().push(line);
+    }
+
+    /// Handles a key press, returning `false` once the user asked to quit.
+    async fn on_key(
+        &mut self,
+        key: KeyEvent,
+        client: &mut Client,
+        replies: &mpsc::UnboundedSender<Reply>,
+    ) -> bool {
+        if key.kind == KeyEventKind::Release {
+            return true;
+        }
+        match key.code {
+            KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Up => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Tab => {
+                self.selected = (self.selected + 1).min(self.peers.len().saturating_sub(1))
+            }
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Enter => {
+                let line = std::mem::take(&mut self.input);
+                let command = if line.starts_with('/') {
+                    match line.parse() {
+                        Ok(command) => command,
+                        Err(e) => {
+                            self.log(format!("{e}"));
+                            return true;
+                        }
+                    }
+                } else if line.trim().is_empty() {
+                    return true;
+                } else if let Some(peer) = self.selected_peer() {
+                    ReplCommand::Msg { peer, text: line }
+                } else {
+                    self.log("Select a peer first, or use /msg <peer id> <text>".to_string());
+                    return true;
+                };
+                let result = match command {
+                    ReplCommand::Quit => return false,
+                    ReplCommand::Msg { peer, text } => self.send(client, peer, text, replies).await,
+                    command => client.execute(command).await,
+                };
+                if let Err(e) = result {
+                    self.log(format!("Error: {e:#}"));
+                }
+                self.sync_relays(&client.relays);
+            }
+            _ => {}
+        }
+        true
+    }
+
+    async fn send(
+        &mut self,
+        client: &mut Client,
+        peer: PeerId,
+        text: String,
+        replies: &mpsc::UnboundedSender<Reply>,
+    ) -> anyhow::Result<()> {
+        self.chat(peer, false, text.clone());
+        client.add_chat_addresses(peer).await?;
+        let (node, replies) = (client.node.clone(), replies.clone());
+        tokio::spawn(async move {
+            let result = match node.reach(peer).await {
+                Ok(_) => node
+                    .send_chat(peer, ChatRequest(ChatEnvelope::text(text)))
+                    .await
+                    .map(|response| String::from_utf8_lossy(response.data()).into_owned())
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            let _ = replies.send(Reply { peer, result });
+        });
+        Ok(())
+    }
+
+    fn on_reply(&mut self, reply: Reply) {
+        match reply.result {
+            Ok(text) => self.chat(reply.peer, true, text),
+            Err(e) => self.chat(reply.peer, true, format!("! not delivered: {e}")),
+        }
+    }
+
+    fn on_event(&mut self, event: NodeEvent) {
+        match event {
+            NodeEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } => {
+                let peer = self.peers.entry(peer_id).or_default();
+                peer.was_relayed |= endpoint.is_relayed();
+                peer.connections.push(endpoint);
+                let kind = peer.kind().map_or("", |kind| kind.label());
+                self.log(format!("Connected to {peer_id} ({kind})"));
+            }
+            NodeEvent::ConnectionClosed {
+                peer_id, endpoint, ..
+            } => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    if let Some(i) = peer.connections.iter().position(|c| *c == endpoint) {
+                        peer.connections.remove(i);
+                    }
+                }
+            }
+            NodeEvent::Ping { peer, rtt: Ok(rtt) } => {
+                self.peers.entry(peer).or_default().rtt = Some(rtt);
+            }
+            NodeEvent::ChatRequest { peer, request, .. } => {
+                let text = String::from_utf8_lossy(request.data()).into_owned();
+                self.chat(peer, true, text);
+            }
+            NodeEvent::ReservationAccepted { relay_peer_id, .. } => {
+                self.set_reservation(&relay_peer_id, Reservation::Accepted);
+            }
+            NodeEvent::ReservationFailed {
+                relay_peer_id,
+                error,
+                ..
+            } => {
+                self.log(format!("Reservation on {relay_peer_id} failed: {error}"));
+                self.set_reservation(&relay_peer_id, Reservation::Failed);
+            }
+            NodeEvent::ReservationRetry {
+                relay_peer_id,
+                attempt,
+                delay,
+                error,
+            } => {
+                self.log(format!(
+                    "Reserving on {relay_peer_id} again in {}s (attempt {attempt}): {error}",
+                    delay.as_secs()
+                ));
+                self.set_reservation(&relay_peer_id, Reservation::Failed);
+            }
+            NodeEvent::HolePunchSucceeded { peer_id, .. } => {
+                self.log(format!("Upgraded {peer_id} to a direct connection"))
+            }
+            NodeEvent::HolePunchFailed { peer_id, error } => self.log(format!(
+                "Hole punching to {peer_id} failed, staying relayed: {error}"
+            )),
+            NodeEvent::PeerReached { peer_id, path, .. } => {
+                self.log(format!("Reached {peer_id} ({path:?})"))
+            }
+            NodeEvent::PeerUnreachable { peer_id, failures } => self.log(format!(
+                "Could not reach {peer_id}: {}",
+                failures.join(", ")
+            )),
+            NodeEvent::ConnectionHealth { peer_id, health } => {
+                self.log(format!("Connection to {peer_id} is {health:?}"))
+            }
+            NodeEvent::RedialScheduled {
+                peer_id,
+                attempt,
+                delay,
+                ..
+            } => self.log(format!(
+                "Dialing {peer_id} again in {}s (attempt {attempt})",
+                delay.as_secs()
+            )),
+            NodeEvent::NewListenAddr { address } => self.log(format!("Listening on {address}")),
+            NodeEvent::OutgoingConnectionError { peer_id, error } => {
+                self.log(format!("Dialing {peer_id:?} failed: {error}"))
+            }
+            _ => {}
+        }
+    }
+
+    fn draw<B: Backend>(&self, f: &mut Frame<B>) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Min(6),
+                Constraint::Length(8),
+                Constraint::Length(1),
+            ])
+            .split(f.size());
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(35), Constraint::Percentage(65)])
+            .split(rows[0]);
+        let chat = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(3), Constraint::Length(3)])
+            .split(columns[1]);
+
+        let peers: Vec<ListItem> = self
+            .peers
+            .iter()
+            .map(|(peer, state)| {
+                let (label, color) = match state.kind() {
+                    Some(kind) => (kind.label(), kind.color()),
+                    None => ("offline", Color::DarkGray),
+                };
+                let rtt = state
+                    .rtt
+                    .map(|rtt| format!(" {}ms", rtt.as_millis()))
+                    .unwrap_or_default();
+                ListItem::new(Spans::from(vec![
+                    Span::raw(format!("{} ", short(peer))),
+                    Span::styled(label, Style::default().fg(color)),
+                    Span::raw(rtt),
+                ]))
+            })
+            .collect();
+        let mut state = ListState::default();
+        state.select((!self.peers.is_empty()).then_some(self.selected));
+        let peers = List::new(peers)
+            .block(Block::default().borders(Borders::ALL).title("Peers"))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        f.render_stateful_widget(peers, columns[0], &mut state);
+
+        let selected = self.selected_peer();
+        let lines: Vec<Spans> = selected
+            .and_then(|peer| self.conversations.get(&peer))
+            .map(|lines| {
+                lines
+                    .iter()
+                    .map(|line| {
+                        let (who, color) = match (line.incoming, selected) {
+                            (true, Some(peer)) => (short(&peer), Color::Magenta),
+                            _ => ("me".to_string(), Color::Blue),
+                        };
+                        Spans::from(vec![
+                            Span::styled(format!("{who}: "), Style::default().fg(color)),
+                            Span::raw(line.text.clone()),
+                        ])
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let height = chat[0].height.saturating_sub(2) as usize;
+        let scroll = lines.len().saturating_sub(height) as u16;
+        let title = match selected {
+            Some(peer) => format!("Chat with {peer}"),
+            None => "Chat".to_string(),
+        };
+        let conversation = Paragraph::new(lines)
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .wrap(Wrap { trim: false })
+            .scroll((scroll, 0));
+        f.render_widget(conversation, chat[0]);
+
+        let input = Paragraph::new(self.input.as_str()).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("Message, or /help"),
+        );
+        f.render_widget(input, chat[1]);
+        // Columns, not bytes, and not past the border.
+        let column = Span::raw(self.input.as_str()).width() as u16;
+        let column = column.min(chat[1].width.saturating_sub(2));
+        f.set_cursor(chat[1].x + 1 + column, chat[1].y + 1);
+
+        let height = rows[1].height.saturating_sub(2) as usize;
+        let log: Vec<Spans> = self
+            .log
+            .iter()
+            .skip(self.log.len().saturating_sub(height))
+            .map(|line| Spans::from(line.as_str()))
+            .collect();
+        let log = Paragraph::new(log).block(Block::default().borders(Borders::ALL).title("Log"));
+        f.render_widget(log, rows[1]);
+
+        let mut status = vec![Span::raw("Relays: ")];
+        if self.reservations.is_empty() {
+            status.push(Span::raw("none"));
+        }
+        for (relay, reservation) in &self.reservations {
+            let (label, color) = match reservation {
+                Reservation::Requested => ("requested", Color::Yellow),
+                Reservation::Accepted => ("reserved", Color::Green),
+                Reservation::Failed => ("failed", Color::Red),
+            };
+            let rtt = self
+                .peers
+                .get(relay)
+                .and_then(|peer| peer.rtt)
+                .map(|rtt| format!(" {}ms", rtt.as_millis()))
+                .unwrap_or_default();
+            status.push(Span::raw(format!("{} ", short(relay))));
+            status.push(Span::styled(label, Style::default().fg(color)));
+            status.push(Span::raw(format!("{rtt}  ")));
+        }
+        f.render_widget(Paragraph::new(Spans::from(status)), rows[2]);
+    }
+}
+
+/// The tail of a peer id, enough to tell peers apart on screen.
+fn short(peer: &PeerId) -> String {
+    let peer = peer.to_string();
+    peer[peer.len().saturating_sub(8)..].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relay() -> (PeerId, Multiaddr) {
+        let peer = PeerId::random();
+        let address = format!("/ip4/192.0.2.1/tcp/4001/p2p/{peer}")
+            .parse()
+            .unwrap();
+        (peer, address)
+    }
+
+    #[test]
+    fn relay_status_follows_the_relays_and_their_reservations() {
+        let mut app = App::default();
+        let (kept, kept_address) = relay();
+        let (removed, removed_address) = relay();
+        app.sync_relays(&HashSet::from([kept_address.clone(), removed_address]));
+        assert_eq!(app.reservations[&kept], Reservation::Requested);
+
+        app.on_event(NodeEvent::ReservationAccepted {
+            relay_peer_id: kept,
+            renewal: false,
+        });
+        assert_eq!(app.reservations[&kept], Reservation::Accepted);
+        app.sync_relays(&HashSet::from([kept_address]));
+        assert_eq!(app.reservations[&kept], Reservation::Accepted);
+        assert!(!app.reservations.contains_key(&removed));
+
+        app.on_event(NodeEvent::ReservationRetry {
+            relay_peer_id: kept,
+            attempt: 1,
+            delay: Duration::from_secs(1),
+            error: "connection to the relay closed".to_string(),
+        });
+        assert_eq!(app.reservations[&kept], Reservation::Failed);
+        // Late news of a removed relay does not bring it back.
+        app.on_event(NodeEvent::ReservationAccepted {
+            relay_peer_id: removed,
+            renewal: false,
+        });
+        assert!(!app.reservations.contains_key(&removed));
+    }
+}
@@ -1 +1 @@
-cargo run --bin relay_client  -- --server-secret-key-seed 1 --client-secret-key-seed 3 --receive-secret-key-seed 2 --port 8080
\ No newline at end of file
+cargo run --bin relay_client  -- --insecure-dev-seed 3 --relay /ip4/127.0.0.1/tcp/8080/p2p/12D3KooWPjceQrSwdWXPyLLeABRXmuqt69Rg3sBYbU1Nft9HyQ6X
\ No newline at end of file
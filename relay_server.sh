@@ -1 +1 @@
-cargo run --bin relay_server -- --secret-key-seed 1 --port 8080
\ No newline at end of file
+cargo run --bin relay_server -- --insecure-dev-seed 1 --port 8080
\ No newline at end of file
@@ -1,64 +1,45 @@
+use behaviour::admin::AdminOpt;
+use behaviour::audit::AuditOpt;
+use behaviour::behaviour::BehaviourConfig;
+use behaviour::key::KeyOpt;
+use behaviour::logging::LogOpt;
+use behaviour::metrics::MetricsOpt;
+use behaviour::node::{AckHandler, Node, NodeEvent};
+use behaviour::output::{self, OutputFormat};
+use behaviour::relay_config::RelayOpt;
 use clap::Parser;
-use codec::chat::{ChatCodec, ChatProtocol, ChatRequest, ChatResponse};
+use codec::chat::DEFAULT_MAX_MESSAGE_SIZE;
 use futures::stream::StreamExt;
-use libp2p::kad::store::MemoryStore;
-use libp2p::request_response::{Config, Event, Message, ProtocolSupport};
-use libp2p::swarm::AddressScore;
-use libp2p::{
-    core::multiaddr::Protocol,
-    core::upgrade,
-    core::{Multiaddr, Transport},
-    identify, identity,
-    identity::PeerId,
-    noise, ping, relay, request_response,
-    swarm::{NetworkBehaviour, SwarmBuilder, SwarmEvent},
-    tcp,
-};
-use std::iter;
+use libp2p::{core::multiaddr::Protocol, core::Multiaddr, identity::PeerId};
 use std::net::{Ipv4Addr, Ipv6Addr};
-use std::str::FromStr;
-use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    env_logger::init();
-
     let opt = Opt::parse();
-    println!("opt: {opt:?}");
+    opt.log.init()?;
+    tracing::debug!(?opt, "starting");
 
-    // Create a static known PeerId based on given secret
-    let local_key: identity::Keypair = generate_ed25519(opt.secret_key_seed);
+    let local_key = opt.key.keypair()?;
     let relay_peer_id = PeerId::from(local_key.public());
-    println!("Local peer id: {relay_peer_id:?}");
-
-    let tcp_transport = tcp::tokio::Transport::default();
-
-    let transport = tcp_transport
-        .upgrade(upgrade::Version::V1)
-        .authenticate(
-            noise::NoiseAuthenticated::xx(&local_key)
-                .expect("Signing libp2p-noise static DH keypair failed."),
-        )
-        .multiplex(libp2p::yamux::YamuxConfig::default())
-        .timeout(std::time::Duration::from_secs(20))
-        .boxed();
-
-    let protocols = iter::once((ChatProtocol, ProtocolSupport::Full));
-    let behaviour = Behaviour {
-        relay: relay::Behaviour::new(relay_peer_id, relay::Config::default()),
-        ping: ping::Behaviour::new(ping::Config::new()),
-        identify: identify::Behaviour::new(identify::Config::new(
-            "/TODO/0.0.1".to_string(),
-            local_key.public(),
-        )),
-        chat: request_response::Behaviour::new(ChatCodec, protocols.clone(), Config::default()),
-        keep_alive: libp2p::swarm::keep_alive::Behaviour::default(),
-        kad: libp2p::kad::Kademlia::new(relay_peer_id, MemoryStore::new(relay_peer_id)),
-    };
-
-    let client_addr = Multiaddr::empty().with(Protocol::P2pCircuit);
-
-    let mut swarm = SwarmBuilder::with_tokio_executor(transport, behaviour, relay_peer_id).build();
+    tracing::info!(peer_id = %relay_peer_id, "local peer id");
+
+    let mut config = BehaviourConfig::relay_server();
+    config.chat_max_request_size = opt.max_request_size;
+    config.chat_max_response_size = opt.max_response_size;
+    config.relay = opt.relay.load()?;
+    tracing::info!(relay = ?config.relay, "relay limits");
+    let metrics = opt.metrics.start(&config.bandwidth)?;
+    let (runner, node) = Node::new(&local_key, config).await?;
+    let mut runner = runner.with_chat_handler(AckHandler);
+    if let Some(metrics) = metrics {
+        runner = runner.with_metrics(metrics);
+    }
+    if let Some(log) = opt.audit.open()? {
+        runner = runner.with_audit(log);
+    }
+    runner.start();
+    opt.admin.start(&node)?;
+    let mut events = node.events();
 
     // Listen on all interfaces
     let listen_addr = Multiaddr::empty()
@@ -67,117 +48,20 @@ async fn main() -> anyhow::Result<()> {
             _ => Protocol::from(Ipv4Addr::UNSPECIFIED),
         })
         .with(Protocol::Tcp(opt.port));
-    swarm.listen_on(listen_addr.clone())?;
-    swarm.add_external_address(listen_addr.clone(), AddressScore::Infinite);
-
-    // swarm.behaviour_mut().chat.add_address(
-    //     &PeerId::from_str("12D3KooWQYhTNQdmr3ArTeUHRYzFg94BKyTkoWBDWez9kSCVe2Xo").unwrap(),
-    //     receive_addr.clone(),
-    // );
-
-    loop {
-        match swarm.next().await.expect("Infinite Stream.") {
-            SwarmEvent::Behaviour(event) => match event {
-                BehaviourEvent::Ping(e) => {
-                    // println!("Ping: {e:?}")
-                }
-                BehaviourEvent::Kad(event) => match event {
-                    libp2p::kad::KademliaEvent::InboundRequest { request } => {
-                        println!("InboundRequest: {request:?}");
-                    }
-                    libp2p::kad::KademliaEvent::OutboundQueryProgressed {
-                        id,
-                        result,
-                        stats,
-                        step,
-                    } => {
-                        println!("OutboundQueryProgressed: {id:?} {result:?} {stats:?} {step:?}");
-                    }
-                    libp2p::kad::KademliaEvent::RoutingUpdated {
-                        peer,
-                        is_new_peer,
-                        addresses,
-                        bucket_range,
-                        old_peer,
-                    } => {
-                        println!("RoutingUpdated: {peer:?} {is_new_peer:?} {addresses:?} {bucket_range:?} {old_peer:?}");
-                    }
-                    libp2p::kad::KademliaEvent::UnroutablePeer { peer } => {
-                        println!("UnroutablePeer: {peer:?}");
-                    }
-                    libp2p::kad::KademliaEvent::RoutablePeer { peer, address } => {
-                        println!("RoutablePeer: {peer:?} {address:?}");
-                    }
-                    libp2p::kad::KademliaEvent::PendingRoutablePeer { peer, address } => {
-                        println!("PendingRoutablePeer: {peer:?} {address:?}");
-                    }
-                },
-                BehaviourEvent::Chat(e) => match e {
-                    request_response::Event::ResponseSent { peer, request_id } => {
-                        println!("ResponseSent: {peer:?} {request_id:?}");
-                    }
-                    request_response::Event::Message { peer, message } => match message {
-                        Message::Response {
-                            request_id,
-                            response,
-                        } => {
-                            println!("{request_id:?} {response:?}")
-                        }
-                        Message::Request {
-                            request_id,
-                            request,
-                            channel,
-                        } => {
-                            swarm
-                                .behaviour_mut()
-                                .chat
-                                .send_response(channel, ChatResponse("ok".as_bytes().to_vec()))
-                                .unwrap();
-                        }
-                    },
-                    request_response::Event::OutboundFailure {
-                        peer,
-                        request_id,
-                        error,
-                    } => {
-                        println!("OutboundFailure: {peer:?} {request_id:?} error: {error:?}");
-                    }
-                    request_response::Event::InboundFailure {
-                        peer,
-                        request_id,
-                        error,
-                    } => {
-                        println!("InboundFailure: {peer:?} {request_id:?} error: {error:?}");
-                    }
-                },
-                BehaviourEvent::Relay(event) => {
-                    println!("Relay: {event:?}");
-                }
-                _ => {}
+    node.listen_on(listen_addr.clone()).await?;
+    node.add_external_address(listen_addr).await?;
+
+    while let Some(event) = events.next().await {
+        match opt.output {
+            OutputFormat::Json => println!("{}", output::json_line(&event)),
+            OutputFormat::Text => match event {
+                NodeEvent::Ping { .. } => {}
+                NodeEvent::NewListenAddr { address } => tracing::info!(%address, "listening"),
+                event => tracing::info!("{event:?}"),
             },
-            SwarmEvent::NewListenAddr { address, .. } => {
-                println!("Listening on {address:?}");
-            }
-            _ => {}
         }
     }
-}
-
-#[derive(NetworkBehaviour)]
-struct Behaviour {
-    relay: relay::Behaviour,
-    ping: ping::Behaviour,
-    identify: identify::Behaviour,
-    chat: request_response::Behaviour<ChatCodec>,
-    keep_alive: libp2p::swarm::keep_alive::Behaviour,
-    kad: libp2p::kad::Kademlia<MemoryStore>,
-}
-
-fn generate_ed25519(secret_key_seed: u8) -> identity::Keypair {
-    let mut bytes = [0u8; 32];
-    bytes[0] = secret_key_seed;
-
-    identity::Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
+    Ok(())
 }
 
 #[derive(Debug, Parser)]
@@ -187,11 +71,38 @@ struct Opt {
     #[clap(long)]
     use_ipv6: Option<bool>,
 
-    /// Fixed value to generate deterministic peer id
-    #[clap(long)]
-    secret_key_seed: u8,
+    #[clap(flatten)]
+    key: KeyOpt,
+
+    #[clap(flatten)]
+    log: LogOpt,
+
+    #[clap(flatten)]
+    metrics: MetricsOpt,
+
+    #[clap(flatten)]
+    admin: AdminOpt,
+
+    #[clap(flatten)]
+    relay: RelayOpt,
+
+    #[clap(flatten)]
+    audit: AuditOpt,
 
     /// The port used to listen on all interfaces
     #[clap(long)]
     port: u16,
+
+    /// How events are reported, `text` logs them and `json` prints one object per line to
+    /// stdout for log processors
+    #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Largest chat request in bytes accepted from or sent to a peer
+    #[clap(long, default_value_t = DEFAULT_MAX_MESSAGE_SIZE)]
+    max_request_size: usize,
+
+    /// Largest chat response in bytes accepted from or sent to a peer
+    #[clap(long, default_value_t = DEFAULT_MAX_MESSAGE_SIZE)]
+    max_response_size: usize,
 }